@@ -1,3 +1,9 @@
+use crate::health::*;
+use crate::level::*;
+use crate::nav::NavPath;
+use crate::ore::{Inventory, OreSight, Upgrades};
+use crate::population::DeathCause;
+use crate::tribe::Tribe;
 use crate::util::*;
 // use crate::*;
 
@@ -6,25 +12,28 @@ use crate::util::*;
 use bevy::prelude::*;
 
 use rand::prelude::*;
-use std::collections::HashMap;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
 use strum::IntoEnumIterator;
 use strum_macros::EnumIter;
 
 #[derive(Component, Clone, Debug)]
 pub struct Atom;
 
-#[derive(Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Body {
     pub atom_pos: Vec2,
     pub rotation: Quat,
     pub atom_size: f32,
     pub acceleration: Vec2,
+    /// Respawned when a session is loaded
+    #[serde(skip)]
     pub entity: Option<Entity>,
     pub is_used: bool,
     // pub item_anchors: Vec2,
 }
 
-#[derive(Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Agent {
     pub id: u32,
     pub last_agent_hit: u32,
@@ -51,10 +60,17 @@ pub struct Agent {
 
     pub race: Race,
     pub social: Social,
+    /// None for the main character and the guardians
+    pub tribe: Option<Tribe>,
 
     pub energy: f32,
     pub mass: f32,
 
+    pub health: f32,
+    pub max_health: f32,
+    /// Multiplies the damage dealt when ramming another agent
+    pub attack: f32,
+
     pub power_usage: f32,
     pub memory_time: f32,
 
@@ -64,16 +80,26 @@ pub struct Agent {
 
     pub sensors: Sensors,
 
+    /// Name of the template that the body is built from, see `CreatureTemplates`
+    pub creature: String,
+    /// Respawned when a session is loaded
+    #[serde(skip)]
     pub entity: Option<Entity>,
 
     pub is_guardian: bool,
     pub guardian_pos: Vec2,
+
+    pub alive: bool,
+    pub death_cause: Option<DeathCause>,
+
+    pub nav: NavPath,
+
+    pub inventory: Inventory,
+    pub upgrades: Upgrades,
 }
 
 impl Agent {
-    pub fn gen_random(stage: &GameStage, id: u32) -> Self {
-        let mut rng = thread_rng();
-
+    pub fn gen_random(stage: &GameStage, id: u32, level: &Level, rng: &mut StdRng) -> Self {
         let position: Vec2;
         let mass: f32;
         let race: Race;
@@ -89,50 +115,26 @@ impl Agent {
         // can see 5 times it's radius
         // let eyes = 10.0;
 
-        match stage {
-            bottom @ GameStage::Bottom => {
-                position = Vec2::new(
-                    rng.gen_range(BOTTOM_LIMIT_X_MIN..BOTTOM_LIMIT_X_MAX) * LEVEL_WIDTH,
-                    rng.gen_range(0.0..BOTTOM_STAGE_LIMIT) * LEVEL_HEIGHT,
-                );
-                mass = rng.gen_range(0.02..0.05);
+        position = level.random_position(stage, rng);
+        mass = level.random_mass(stage, rng);
+        race = Race::random_race(stage, rng);
 
-                race = Race::random_race(&bottom);
-            }
-            mid @ GameStage::Mid => {
-                position = Vec2::new(
-                    rng.gen_range(0.0..1.0) * LEVEL_WIDTH,
-                    rng.gen_range(BOTTOM_STAGE_LIMIT..MID_STAGE_LIMIT) * LEVEL_HEIGHT,
-                );
-                mass = rng.gen_range(0.05..0.11);
-
-                race = Race::random_race(&mid);
-                // social_attributes = race.gen_socials();
-            }
-            top @ GameStage::Top => {
-                position = Vec2::new(
-                    rng.gen_range(0.02..TOP_STAGE_LIMIT) * LEVEL_WIDTH,
-                    rng.gen_range(MID_STAGE_LIMIT..TOP_STAGE_LIMIT) * LEVEL_HEIGHT,
-                    // rng.gen_range(BOTTOM_STAGE_LIMIT..MID_STAGE_LIMIT) * LEVEL_HEIGHT,
-                );
-                mass = rng.gen_range(0.11..0.2);
-
-                // hearing_range = MASS_MULT * mass * eyes;
-
-                race = Race::random_race(&top);
-                is_guardian = true;
-                // social_attributes = race.gen_socials();
-            }
-        };
+        // the top stage is guarded
+        if *stage == GameStage::Top {
+            is_guardian = true;
+        }
 
         let radius = mass * MASS_MULT * ATOM_MULT;
 
         let sight_range = radius * 20.0;
         let hearing_range = sight_range;
 
-        let race_attributes = race.gen_attributes();
+        let race_attributes = race.gen_attributes(rng);
         let social_attributes = race_attributes.social_attributes;
         memory_time = race_attributes.memory_time;
+        let power_usage = race_attributes.power_usage;
+        let attack = race_attributes.attack;
+        let max_health = mass * HEALTH_PER_MASS;
 
         let look_at_angle: f32 = rng.gen_range(0.0..6.3);
         let target_position = position + Vec2::new(look_at_angle.cos(), look_at_angle.sin()) * 50.0;
@@ -159,9 +161,15 @@ impl Agent {
             target_position,
             mass,
             radius,
+            race,
 
             energy: 1.0,
+            health: max_health,
+            max_health,
+            attack,
+            power_usage,
             social,
+            tribe: Some(level.tribe_at(position, rng)),
             sensors,
             memory_time,
             look_at_angle,
@@ -170,9 +178,7 @@ impl Agent {
         }
     }
 
-    pub fn gen_guardian(pos: Vec2, id: u32) -> Self {
-        let mut rng = thread_rng();
-
+    pub fn gen_guardian(pos: Vec2, id: u32, rng: &mut StdRng) -> Self {
         let position: Vec2;
         let mass: f32;
         let race: Race;
@@ -195,7 +201,7 @@ impl Agent {
 
         // hearing_range = MASS_MULT * mass * eyes;
 
-        race = Race::random_race(&GameStage::Top);
+        race = Race::random_race(&GameStage::Top, rng);
         is_guardian = true;
         // social_attributes = race.gen_socials();
 
@@ -204,7 +210,7 @@ impl Agent {
         let sight_range = radius * 20.0;
         let hearing_range = sight_range;
 
-        let race_attributes = race.gen_attributes();
+        let race_attributes = race.gen_attributes(rng);
         let social_attributes = race_attributes.social_attributes;
         memory_time = race_attributes.memory_time;
 
@@ -233,8 +239,14 @@ impl Agent {
             target_position,
             mass,
             radius,
+            race,
 
             energy: 1.0,
+            health: mass * HEALTH_PER_MASS * GUARDIAN_TOUGHNESS,
+            max_health: mass * HEALTH_PER_MASS * GUARDIAN_TOUGHNESS,
+            attack: GUARDIAN_ATTACK,
+            // guardians don't eat, they are kept alive by the surface
+            power_usage: 0.0,
             social,
             sensors,
             memory_time,
@@ -303,6 +315,7 @@ impl Agent {
             mass: other_agent.mass,
             speed: other_agent.speed,
             look_at_angle: other_agent.look_at_angle,
+            tribe: other_agent.tribe,
             // status: Status::Alive,
             // }),
         };
@@ -312,6 +325,26 @@ impl Agent {
             .insert(other_agent.id, new_agent_sight);
     }
 
+    pub fn update_food_sight(&mut self, time: f32, distance: f32, food: &Food) {
+        let new_food_sight = FoodSight {
+            time_of_last_sight: time,
+            distance,
+            position: food.position,
+            energy: food.energy,
+            mass: food.mass,
+            id: food.id,
+        };
+
+        self.sensors.food_sight.insert(food.id, new_food_sight);
+    }
+
+    pub fn forget_food(&mut self, time: f32) {
+        let memory_time = self.memory_time;
+        self.sensors
+            .food_sight
+            .retain(|_id, sight_data| time - sight_data.time_of_last_sight <= memory_time);
+    }
+
     pub fn forget_agents(&mut self, time: f32) {
         let mut to_remove: Vec<u32> = Vec::new();
         for (id, sight_data) in self.sensors.agent_sight.iter_mut() {
@@ -325,12 +358,10 @@ impl Agent {
         }
     }
 
-    pub fn find_new_goal(&mut self, time: f32) {
+    pub fn find_new_goal(&mut self, time: f32, rng: &mut impl Rng) {
         // restart goal timer
         self.goal_time = time;
 
-        let mut rng = rand::thread_rng();
-
         let altruism = self.social.social_attributes.altruism;
         let aggro = self.social.social_attributes.aggressivity;
         let collect = self.social.social_attributes.collectioneur;
@@ -359,10 +390,34 @@ impl Agent {
         // if an agent is in sight and the rng okays iit, then change the goal to go to that agent
         // } else
 
-        if !self.sensors.agent_sight.is_empty() && rng.gen::<f32>() < prob_altruism {
+        // collectors go mine the closest deposit that they know of
+        if !self.sensors.ore_sight.is_empty() && rng.gen::<f32>() < prob_collect {
             //
-            let a = agents_in_sight.iter().next().unwrap().1;
+            let closest = self
+                .sensors
+                .ore_sight
+                .values()
+                .min_by(|a, b| {
+                    a.distance
+                        .partial_cmp(&b.distance)
+                        .unwrap_or(std::cmp::Ordering::Equal)
+                })
+                .cloned();
+            if let Some(ore_sight) = closest {
+                // mining is a commitment, the goal is not rerolled below
+                self.goal = Goal::Ore(ore_sight);
+                return;
+            }
+
+        // if an agent is in sight and the rng okays iit, then change the goal to go to that agent
+        } else if !self.sensors.agent_sight.is_empty() && rng.gen::<f32>() < prob_altruism {
+            // tribe-mates first
+            let a = agents_in_sight
+                .values()
+                .find(|a| self.tribe.is_some() && a.tribe == self.tribe)
+                .unwrap_or_else(|| agents_in_sight.iter().next().unwrap().1);
             self.goal = Goal::GoToAgent(a.id);
+            return;
 
         // if there is food in sight, go to closest
         } else if !self.sensors.food_sight.is_empty() && rng.gen::<f32>() < 0.95 {
@@ -386,9 +441,8 @@ impl Agent {
         }
     }
 
-    pub fn act(&mut self, agent_positions: &HashMap<u32, Vec2>) {
+    pub fn act(&mut self, agent_positions: &HashMap<u32, Vec2>, rng: &mut impl Rng) {
         //
-        let mut rng = thread_rng();
 
         match self.goal.clone() {
             //
@@ -396,7 +450,11 @@ impl Agent {
                 self.target_position = pos;
             }
             Goal::GoToAgent(agent_id) => {
-                self.target_position = *agent_positions.get(&agent_id).unwrap();
+                // the other agent may have died in the meantime
+                match agent_positions.get(&agent_id) {
+                    Some(position) => self.target_position = *position,
+                    None => self.goal = Goal::None,
+                }
             }
             Goal::Food(food_sight) => {
                 self.target_position = food_sight.position;
@@ -404,9 +462,15 @@ impl Agent {
             Goal::Item(item_sight) => {
                 self.target_position = item_sight.position;
             }
+            Goal::Ore(ore_sight) => {
+                self.target_position = ore_sight.position;
+            }
             Goal::Bully(agent_id) => {
                 // println!("bully {}", agent_id);
-                self.target_position = *agent_positions.get(&agent_id).unwrap();
+                match agent_positions.get(&agent_id) {
+                    Some(position) => self.target_position = *position,
+                    None => self.goal = Goal::None,
+                }
             }
             Goal::Flee(from) => {
                 let fleeing_direction = (self.position - from).normalize();
@@ -450,12 +514,13 @@ impl Agent {
         attacker_mass: f32,
         attacker_position: Vec2,
         time: f32,
+        rng: &mut impl Rng,
     ) {
         //
         let offset = 0.0;
-        let ratio = attacker_mass / self.mass;
+        // wounded creatures are more easily scared
+        let ratio = attacker_mass / self.mass / self.health_ratio().max(0.1);
         let p_of_fleeing = sigmoid(ratio, -1.0, 0.98, 0.02, 10.0, offset);
-        let mut rng = thread_rng();
         if rng.gen::<f32>() < p_of_fleeing {
             self.goal = Goal::Flee(attacker_position);
         } else {
@@ -478,6 +543,11 @@ impl Agent {
 
     pub fn update_mass_properties(&mut self) {
         self.radius = self.mass * MASS_MULT * ATOM_MULT;
+        self.max_health = self.mass * HEALTH_PER_MASS;
+        if self.is_guardian {
+            self.max_health *= GUARDIAN_TOUGHNESS;
+        }
+        self.health = self.health.min(self.max_health);
         self.sensors.sight_range = self.radius * 10.0;
         self.sensors.hearing_range = self.sensors.sight_range;
     }
@@ -554,18 +624,23 @@ impl Default for Agent {
             boost: false,
 
             turning: Turning::None,
-            acc: Acceleration::Forward,
+            acc: Acceleration::Forward(1.0),
 
-            race: Race::random_race(&GameStage::Bottom),
+            race: Race::random_race(&GameStage::Bottom, &mut rng),
             social: Social::default(),
+            tribe: None,
 
             mass,
 
             energy: 100000000.0,
 
+            health: mass * HEALTH_PER_MASS,
+            max_health: mass * HEALTH_PER_MASS,
+            attack: 1.0,
+
             memory_time: 4.0,
 
-            power_usage: 0.0,
+            power_usage: 1.0,
 
             goal_status: AgentGoalStatus::None,
             goal_time: 0.0,
@@ -575,7 +650,16 @@ impl Default for Agent {
             is_guardian: false,
             guardian_pos: Vec2::ZERO,
 
+            creature: String::new(),
             entity: None,
+
+            alive: true,
+            death_cause: None,
+
+            nav: NavPath::default(),
+
+            inventory: Inventory::default(),
+            upgrades: Upgrades::default(),
         };
     }
 }
@@ -586,15 +670,18 @@ impl Default for Agent {
 //     }
 // }
 
-#[derive(Clone, Debug)]
+// In the order of the ids, like the agents, since the decisions go through them with the
+// world rng
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Sensors {
     pub hearing_range: f32,
     pub sight_range: f32,
 
-    pub agent_sight: HashMap<u32, AgentSight>,
-    pub food_sight: HashMap<u32, FoodSight>,
-    pub item_sight: HashMap<u32, ItemSight>,
-    pub hearing: HashMap<u32, HearingData>,
+    pub agent_sight: BTreeMap<u32, AgentSight>,
+    pub food_sight: BTreeMap<u32, FoodSight>,
+    pub item_sight: BTreeMap<u32, ItemSight>,
+    pub ore_sight: BTreeMap<u32, OreSight>,
+    pub hearing: BTreeMap<u32, HearingData>,
 }
 
 impl Default for Sensors {
@@ -602,10 +689,11 @@ impl Default for Sensors {
         Self {
             hearing_range: 1.0,
             sight_range: 100.0,
-            agent_sight: HashMap::new(),
-            item_sight: HashMap::new(),
-            food_sight: HashMap::new(),
-            hearing: HashMap::new(),
+            agent_sight: BTreeMap::new(),
+            item_sight: BTreeMap::new(),
+            ore_sight: BTreeMap::new(),
+            food_sight: BTreeMap::new(),
+            hearing: BTreeMap::new(),
         }
     }
 }
@@ -616,21 +704,22 @@ impl Default for Sensors {
 //     Unknown,
 // }
 
-#[derive(Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub enum Turning {
     Left(f32),
     Right(f32),
     None,
 }
 
-#[derive(Clone, Debug)]
+/// How hard the creature swims, from 0 to 1
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub enum Acceleration {
-    Forward,
-    Backward,
+    Forward(f32),
+    Backward(f32),
     None,
 }
 
-#[derive(Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct AgentSight {
     pub time_of_last_sight: f32,
     pub distance: f32,
@@ -642,10 +731,11 @@ pub struct AgentSight {
     pub mass: f32,
     pub speed: f32,
     pub look_at_angle: f32,
+    pub tribe: Option<Tribe>,
     // pub status: Status,
 }
 
-#[derive(Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct ItemSight {
     pub time_of_last_sight: f32,
     pub distance: f32,
@@ -659,7 +749,7 @@ pub struct ItemSight {
     pub hp: f32,
 }
 
-#[derive(Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct FoodSight {
     pub time_of_last_sight: f32,
     pub distance: f32,
@@ -687,20 +777,20 @@ pub enum Sight {
     None,
 }
 
-#[derive(Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct HearingData {
     pub time_of_hearing: f32,
     pub distance: f32,
     pub things: Vec<Hearing>,
 }
 
-#[derive(Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub enum Hearing {
     Agent(Direction),
     Weapon(Direction),
 }
 
-#[derive(Debug, PartialEq, Eq, Clone)]
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
 pub enum AgentGoalStatus {
     None,
     LookingForGoal,
@@ -709,7 +799,7 @@ pub enum AgentGoalStatus {
     Error,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum Goal {
     None,
     SearchForAFight,
@@ -722,10 +812,11 @@ pub enum Goal {
     Food(FoodSight),
     Flee(Vec2), // direction
     Item(ItemSight),
+    Ore(OreSight),
     Bully(u32),
 }
 
-#[derive(Debug, EnumIter, Copy, Clone)]
+#[derive(Serialize, Deserialize, Debug, EnumIter, Copy, Clone)]
 pub enum Feeling {
     Neutral,
     Happy,
@@ -740,25 +831,25 @@ impl Feeling {
     }
 }
 
-#[derive(Debug, EnumIter, Clone)]
+#[derive(Serialize, Deserialize, Debug, EnumIter, Clone)]
 pub enum RaceBottom {
     Ameoba,
     StratolopusArealus,
 }
 
-#[derive(Debug, EnumIter, Clone)]
+#[derive(Serialize, Deserialize, Debug, EnumIter, Clone)]
 pub enum RaceMid {
     Piko,
     Seahorse,
 }
 
-#[derive(Debug, EnumIter, Clone)]
+#[derive(Serialize, Deserialize, Debug, EnumIter, Clone)]
 pub enum RaceTop {
     Squid,
     Whale,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum Race {
     Bottom(RaceBottom),
     Mid(RaceMid),
@@ -768,16 +859,18 @@ pub enum Race {
 pub struct RaceAttributes {
     pub social_attributes: SocialAttributes,
     pub memory_time: f32,
+    /// Multiplies the energy cost of moving around, see `metabolism`
+    pub power_usage: f32,
+    /// Multiplies the damage dealt in fights, see `health`
+    pub attack: f32,
 }
 
 impl Race {
-    pub fn random_race(stage: &GameStage) -> Race {
-        let mut rng = rand::thread_rng();
-
+    pub fn random_race(stage: &GameStage, rng: &mut impl Rng) -> Race {
         let race = match stage {
-            GameStage::Bottom => Race::Bottom(RaceBottom::iter().choose(&mut rng).unwrap()),
-            GameStage::Mid => Race::Mid(RaceMid::iter().choose(&mut rng).unwrap()),
-            GameStage::Top => Race::Top(RaceTop::iter().choose(&mut rng).unwrap()),
+            GameStage::Bottom => Race::Bottom(RaceBottom::iter().choose(rng).unwrap()),
+            GameStage::Mid => Race::Mid(RaceMid::iter().choose(rng).unwrap()),
+            GameStage::Top => Race::Top(RaceTop::iter().choose(rng).unwrap()),
         };
 
         return race;
@@ -785,9 +878,7 @@ impl Race {
 
     // pub fn gen_memory_time(&self)
 
-    pub fn gen_attributes(&self) -> RaceAttributes {
-        let mut rng = thread_rng();
-
+    pub fn gen_attributes(&self, rng: &mut impl Rng) -> RaceAttributes {
         let socials = match self {
             // Bottom
             Race::Bottom(RaceBottom::Ameoba) => RaceAttributes {
@@ -797,6 +888,8 @@ impl Race {
                     collectioneur: rng.gen_range(0.0..0.5),
                 },
                 memory_time: rng.gen_range(0.0..0.5),
+                power_usage: rng.gen_range(0.6..0.9),
+                attack: rng.gen_range(0.5..0.8),
             },
 
             Race::Bottom(RaceBottom::StratolopusArealus) => RaceAttributes {
@@ -806,6 +899,8 @@ impl Race {
                     collectioneur: rng.gen_range(0.0..0.5),
                 },
                 memory_time: rng.gen_range(0.5..1.5),
+                power_usage: rng.gen_range(0.8..1.1),
+                attack: rng.gen_range(0.8..1.2),
             },
 
             // Mid
@@ -816,6 +911,8 @@ impl Race {
                     collectioneur: rng.gen_range(0.0..0.5),
                 },
                 memory_time: rng.gen_range(2.0..4.0),
+                power_usage: rng.gen_range(1.0..1.4),
+                attack: rng.gen_range(1.3..1.8),
             },
 
             Race::Mid(RaceMid::Seahorse) => RaceAttributes {
@@ -825,6 +922,8 @@ impl Race {
                     collectioneur: rng.gen_range(0.0..0.5),
                 },
                 memory_time: rng.gen_range(3.0..3.5),
+                power_usage: rng.gen_range(0.7..1.0),
+                attack: rng.gen_range(0.6..0.9),
             },
 
             // Top
@@ -835,6 +934,8 @@ impl Race {
                     collectioneur: rng.gen_range(0.0..0.5),
                 },
                 memory_time: rng.gen_range(3.0..5.0),
+                power_usage: rng.gen_range(1.2..1.6),
+                attack: rng.gen_range(1.0..1.4),
             },
             Race::Top(RaceTop::Whale) => RaceAttributes {
                 social_attributes: SocialAttributes {
@@ -843,6 +944,8 @@ impl Race {
                     collectioneur: rng.gen_range(0.0..0.5),
                 },
                 memory_time: rng.gen_range(5.0..10.0),
+                power_usage: rng.gen_range(0.9..1.2),
+                attack: rng.gen_range(1.5..2.0),
             },
             // other?
             _ => RaceAttributes {
@@ -852,6 +955,8 @@ impl Race {
                     collectioneur: rng.gen_range(0.0..0.5),
                 },
                 memory_time: rng.gen_range(1.0..1.01),
+                power_usage: rng.gen_range(0.8..1.2),
+                attack: rng.gen_range(0.8..1.2),
             },
         };
 
@@ -859,26 +964,26 @@ impl Race {
     }
 }
 
-#[derive(Clone, Debug, Component, PartialEq)]
+#[derive(Serialize, Deserialize, Clone, Debug, Component, PartialEq)]
 pub struct AgentId {
     pub kdtree_hash: u32,
     // pub maybe_AgentId: Option<AgentId>,
 }
 
-#[derive(Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct SocialAttributes {
     pub aggressivity: f32,
     pub altruism: f32,
     pub collectioneur: f32,
 }
 
-#[derive(Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct PartnerData {
     pub time_since_partnered: f32,
     pub feeling: Feeling,
 }
 
-#[derive(Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Social {
     pub agent_whom_asked: Option<AgentId>,
     pub asked_to_agent: Option<AgentId>,
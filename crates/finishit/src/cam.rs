@@ -1,85 +1,208 @@
-use bevy::{input::mouse::MouseWheel, prelude::*};
+use bevy::{input::mouse::MouseWheel, prelude::*, transform::TransformSystem};
+
+use rand::prelude::*;
+
+use crate::agent::*;
+use crate::inputs::*;
+use crate::level::*;
+use crate::util::*;
+
+// Scale of the camera transform for a main character of STARTING_MASS in the bottom stage
+pub const BASE_ZOOM: f32 = 0.5;
+// The camera never zooms out further than this, whatever the mass
+pub const MAX_MASS_ZOOM: f32 = 4.0;
+
+// Rates of the exponential smoothing, per second
+pub const FOLLOW_RATE: f32 = 4.0;
+pub const ZOOM_RATE: f32 = 1.5;
+
+// The camera leads the main character by its velocity over this many seconds
+pub const LOOK_AHEAD_TIME: f32 = 0.6;
+pub const MAX_LOOK_AHEAD: f32 = 250.0;
+
+// Past this distance the camera jumps to its focus, e.g. after a restart or a quickload
+pub const SNAP_DISTANCE: f32 = 3000.0;
+
+// Trauma added by a hit, between 0 and 1. The shake grows with the square of the trauma.
+pub const IMPACT_TRAUMA: f32 = 0.3;
+pub const GUARDIAN_TRAUMA: f32 = 0.8;
+pub const TRAUMA_DECAY: f32 = 1.5;
+// Offset in pixels of the strongest shake
+pub const MAX_SHAKE: f32 = 24.0;
 
 #[derive(Component)]
 pub struct Cam {
+    /// Speed of the free look, in pixels per frame at the base zoom
     pub speed: f32,
-    pub key_left: KeyCode,
-    pub key_right: KeyCode,
-    pub key_up: KeyCode,
-    pub key_down: KeyCode,
-    pub enabled: bool,
+    /// The camera is moved with the camera actions instead of following the main character
+    pub free_look: bool,
+    /// Smoothed point followed by the camera, without the shake. Jumps to the main
+    /// character when None.
+    pub focus: Option<Vec2>,
+    /// Smoothed scale of the camera transform
+    pub zoom: f32,
+    /// Zoom of the mouse wheel, on top of the followed zoom in free look
+    pub wheel_zoom: f32,
+    pub trauma: f32,
+    /// Scripted focus and zoom, followed instead of the main character, e.g. by the ending
+    pub shot: Option<(Vec2, f32)>,
 }
 impl Default for Cam {
     fn default() -> Self {
         Self {
             speed: 10.0,
-            key_up: KeyCode::W,
-            key_down: KeyCode::S,
-            key_left: KeyCode::A,
-            key_right: KeyCode::D,
-            enabled: true,
+            free_look: false,
+            focus: None,
+            zoom: BASE_ZOOM,
+            wheel_zoom: 1.0,
+            trauma: 0.0,
+            shot: None,
         }
     }
 }
 
+impl Cam {
+    pub fn add_trauma(&mut self, trauma: f32) {
+        self.trauma = (self.trauma + trauma).min(1.0);
+    }
+}
+
 pub struct CamPlugin;
 
 impl Plugin for CamPlugin {
     fn build(&self, app: &mut App) {
-        app.add_system(camera_movevement_system);
-        // .add_system(zoom_camera.system());
+        // after the movements of the frame, before they reach the global transforms
+        app.add_system_to_stage(
+            CoreStage::PostUpdate,
+            camera_movevement_system.before(TransformSystem::TransformPropagate),
+        );
     }
 }
 
-pub fn movement_axis(input: &Res<Input<KeyCode>>, plus: KeyCode, minus: KeyCode) -> f32 {
+pub fn movement_axis(actions: &Actions, plus: Action, minus: Action) -> f32 {
     let mut axis = 0.0;
-    if input.pressed(plus) && !input.pressed(KeyCode::LControl) && !input.pressed(KeyCode::LShift) {
+    if actions.pressed(plus) {
         axis += 1.0;
     }
-    if input.pressed(minus) && !input.pressed(KeyCode::LControl) && !input.pressed(KeyCode::LShift)
-    {
+    if actions.pressed(minus) {
         axis -= 1.0;
     }
     return axis;
 }
 
+// The camera zooms out as the main character grows, and as the stages open up
+fn target_zoom(agent: &Agent, stage: &GameStage) -> f32 {
+    let mass_zoom = (agent.mass / STARTING_MASS)
+        .sqrt()
+        .clamp(1.0, MAX_MASS_ZOOM);
+    let stage_zoom = match stage {
+        GameStage::Bottom => 1.0,
+        GameStage::Mid => 1.25,
+        GameStage::Top => 1.5,
+    };
+    BASE_ZOOM * mass_zoom * stage_zoom
+}
+
+// Fraction of the way to cover this frame, for a smoothing rate per second
+fn smoothing(rate: f32, delta: f32) -> f32 {
+    1.0 - (-rate * delta).exp()
+}
+
 pub fn camera_movevement_system(
-    // mouse_wheel: Res<Events<MouseWheel>>,
     mut ev_scroll: EventReader<MouseWheel>,
-    keyboard_input: Res<Input<KeyCode>>,
-
-    mut transforms: Query<(&Cam, &mut Transform)>,
+    mut collision_events: EventReader<CollisionEvent>,
+    actions: Res<Actions>,
+    game: Res<Game>,
+    level: Res<Level>,
+    time: Res<Time>,
+    mut transforms: Query<(&mut Cam, &mut Transform)>,
 ) {
-    let mut velocity = Vec3::ZERO;
-    let mut do_move_cam = false;
-    for (cam, mut transform) in transforms.iter_mut() {
-        let (axis_side, axis_up) = if cam.enabled {
-            (
-                movement_axis(&keyboard_input, cam.key_right, cam.key_left),
-                movement_axis(&keyboard_input, cam.key_up, cam.key_down),
-            )
+    let delta = time.delta_seconds();
+    let main_char = game.agents.get(&1);
+
+    let impacts = collision_events
+        .iter()
+        .filter(|collision| collision.agent_id == 1)
+        .map(|collision| {
+            if collision.other_is_guardian {
+                GUARDIAN_TRAUMA
+            } else {
+                IMPACT_TRAUMA
+            }
+        })
+        .collect::<Vec<f32>>();
+    let scrolls = ev_scroll.iter().map(|event| event.y).collect::<Vec<f32>>();
+
+    for (mut cam, mut transform) in transforms.iter_mut() {
+        if actions.just_pressed(Action::FreeLook) {
+            cam.free_look = !cam.free_look;
+            cam.wheel_zoom = 1.0;
+        }
+
+        let mut zoom = main_char.map_or(cam.zoom, |agent| {
+            target_zoom(agent, &level.stage_at(agent.position.y))
+        });
+
+        if let Some((target, shot_zoom)) = cam.shot {
+            zoom = shot_zoom;
+            cam.focus = Some(match cam.focus {
+                Some(focus) => focus.lerp(target, smoothing(FOLLOW_RATE, delta)),
+                None => target,
+            });
+        } else if cam.free_look {
+            for scroll in scrolls.iter() {
+                if *scroll > 0.0 {
+                    cam.wheel_zoom *= 1.0 - 0.1;
+                } else {
+                    cam.wheel_zoom *= 1.0 + 0.1;
+                }
+            }
+            cam.wheel_zoom = cam.wheel_zoom.clamp(0.1, 20.0);
+            zoom *= cam.wheel_zoom;
+
+            let axis = Vec2::new(
+                movement_axis(&actions, Action::CameraRight, Action::CameraLeft),
+                movement_axis(&actions, Action::CameraUp, Action::CameraDown),
+            );
+            // as fast on screen at any zoom
+            let velocity = axis * cam.speed * cam.zoom / BASE_ZOOM;
+            cam.focus = Some(cam.focus.unwrap_or(transform.translation.truncate()) + velocity);
+        } else if let Some(agent) = main_char {
+            let velocity = if delta > 0.0 {
+                (agent.position - agent.last_position) / delta
+            } else {
+                Vec2::ZERO
+            };
+            let look_ahead = (velocity * LOOK_AHEAD_TIME).clamp_length_max(MAX_LOOK_AHEAD);
+            let target = agent.position + look_ahead;
+
+            cam.focus = match cam.focus {
+                Some(focus) if focus.distance(target) < SNAP_DISTANCE => {
+                    Some(focus.lerp(target, smoothing(FOLLOW_RATE, delta)))
+                }
+                _ => Some(target),
+            };
+        }
+
+        cam.zoom += (zoom - cam.zoom) * smoothing(ZOOM_RATE, delta);
+
+        for trauma in impacts.iter() {
+            cam.add_trauma(*trauma);
+        }
+        let shake = if cam.trauma > 0.0 {
+            let mut rng = rand::thread_rng();
+            let direction = Vec2::new(rng.gen_range(-1.0..1.0), rng.gen_range(-1.0..1.0));
+            direction * MAX_SHAKE * cam.trauma * cam.trauma * cam.zoom / BASE_ZOOM
         } else {
-            (0.0, 0.0)
+            Vec2::ZERO
         };
+        cam.trauma = (cam.trauma - TRAUMA_DECAY * delta).max(0.0);
 
-        if axis_side.abs() > 0.0000001 || axis_up.abs() > 0.0000001 {
-            do_move_cam = true;
+        if let Some(focus) = cam.focus {
+            transform.translation.x = focus.x + shake.x;
+            transform.translation.y = focus.y + shake.y;
         }
-
-        velocity = Vec3::new(axis_side * cam.speed, axis_up * cam.speed, 0.0);
-
-        // transform.translation += velocity;
-
-        // for event in ev_scroll.iter() {
-        //     if event.y > 0.0 {
-        //         transform.scale.x *= 1.0 - 0.1;
-        //         transform.scale.y *= 1.0 - 0.1;
-        //     } else {
-        //         transform.scale.x *= 1.0 + 0.1;
-        //         transform.scale.y *= 1.0 + 0.1;
-        //     }
-        //     transform.scale.x = transform.scale.x.clamp(0.1, 100.0);
-        //     transform.scale.y = transform.scale.y.clamp(0.1, 100.0);
-        // }
+        transform.scale.x = cam.zoom;
+        transform.scale.y = cam.zoom;
     }
 }
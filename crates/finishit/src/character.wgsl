@@ -498,7 +498,8 @@ fn fragment(in: FragmentInput) -> [[location(0)]] vec4<f32> {
     var background = blue2; // toLinear(uni.color);
     background.a = 0.0;
     // let background = fushia;
-    let main_color = fushia;
+    // tinted on the cpu side, see `show_damage`
+    let main_color = toLinear(uni.color);
     let contour_color = blue3;
 
     // let background = float4(bgc.x, bgc.y, bgc.z, 1.0) ;
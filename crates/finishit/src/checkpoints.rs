@@ -0,0 +1,232 @@
+use std::collections::HashSet;
+
+use bevy::prelude::*;
+
+use serde::{Deserialize, Serialize};
+
+use crate::agent::*;
+use crate::guardians::*;
+use crate::hud::*;
+use crate::level::*;
+use crate::population::*;
+use crate::states::*;
+use crate::util::*;
+
+// The main character is swallowed whole by creatures this many times heavier than itself
+pub const SWALLOW_MASS_RATIO: f32 = 3.0;
+// The main character dies after starving for this long, instead of wasting away
+pub const MAIN_CHAR_STARVATION_TIME: f32 = 10.0;
+// The run is over after this many deaths
+pub const MAX_DEATHS: u32 = 5;
+
+// Time between the death of the main character and its respawn
+pub const MAIN_CHAR_RESPAWN_DELAY: f32 = 2.0;
+// NPCs this close to the checkpoint are cleared away when the main character respawns
+pub const CHECKPOINT_CLEAR_RADIUS: f32 = 800.0;
+
+/// The main character moved into another stage of the level
+pub struct StageChangeEvent {
+    pub stage: GameStage,
+}
+
+/// A place on the way up. Reached when the main character rises above it, and where it
+/// respawns after dying.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Checkpoint {
+    pub position: Vec2,
+}
+
+/// How far the main character went, and what it cost
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct Progress {
+    /// Index of the last checkpoint reached, None when still at the start position
+    pub checkpoint: Option<usize>,
+    /// Mass of the main character when it reached the checkpoint, it respawns with it
+    pub checkpoint_mass: f32,
+    pub deaths: u32,
+    pub death_time: Option<f32>,
+    pub starving_since: Option<f32>,
+    /// Ids of the different creatures that the main character ran into, for the run summary
+    #[serde(default)]
+    pub bumped: HashSet<u32>,
+    #[serde(default)]
+    pub guardian_hits: u32,
+}
+
+impl Progress {
+    pub fn respawn_position(&self, level: &Level) -> Vec2 {
+        self.checkpoint
+            .and_then(|k| level.checkpoints.get(k))
+            .map_or(level.start_position, |checkpoint| checkpoint.position)
+    }
+}
+
+pub fn reach_checkpoints(
+    game: Res<Game>,
+    level: Res<Level>,
+    mut progress: ResMut<Progress>,
+    mut notifications: EventWriter<HudNotification>,
+) {
+    let agent = match game.agents.get(&1) {
+        Some(agent) if agent.alive => agent,
+        _ => return,
+    };
+
+    let next = progress.checkpoint.map_or(0, |k| k + 1);
+    for (k, checkpoint) in level.checkpoints.iter().enumerate().skip(next) {
+        if agent.position.y > checkpoint.position.y {
+            progress.checkpoint = Some(k);
+            progress.checkpoint_mass = agent.mass;
+            notifications.send(HudNotification(format!("Checkpoint {} reached", k + 1)));
+        }
+    }
+}
+
+// Keeps the stage of the game on the stage that the main character is in
+pub fn track_stage(
+    mut game: ResMut<Game>,
+    level: Res<Level>,
+    mut stage_events: EventWriter<StageChangeEvent>,
+) {
+    let stage = match game.agents.get(&1) {
+        Some(agent) if agent.alive => level.stage_at(agent.position.y),
+        _ => return,
+    };
+
+    if stage != game.game_stage {
+        game.game_stage = stage.clone();
+        stage_events.send(StageChangeEvent { stage });
+    }
+}
+
+// The main character dies of its wounds, of hunger, or in the mouth of a bigger creature
+pub fn lose_conditions(
+    mut game: ResMut<Game>,
+    mut progress: ResMut<Progress>,
+    time: Res<WorldTime>,
+    mut collision_events: EventReader<CollisionEvent>,
+    mut app_state: ResMut<State<AppState>>,
+    mut notifications: EventWriter<HudNotification>,
+) {
+    let now = time.seconds_since_startup() as f32;
+
+    let swallowed = collision_events
+        .iter()
+        .filter(|collision| collision.agent_id == 1)
+        .any(|collision| {
+            match (
+                game.agents.get(&1),
+                game.agents.get(&collision.other_agent_id),
+            ) {
+                (Some(agent), Some(other)) => {
+                    other.mass > agent.mass * SWALLOW_MASS_RATIO && agent.impact_damage(other) > 0.0
+                }
+                _ => false,
+            }
+        });
+
+    let agent = match game.agents.get_mut(&1) {
+        Some(agent) => agent,
+        None => return,
+    };
+
+    if agent.alive {
+        if swallowed {
+            agent.die(DeathCause::Swallowed);
+        }
+
+        if agent.is_starving() {
+            let since = *progress.starving_since.get_or_insert(now);
+            if now - since > MAIN_CHAR_STARVATION_TIME {
+                agent.die(DeathCause::Starvation);
+            }
+        } else {
+            progress.starving_since = None;
+        }
+    }
+
+    if !agent.alive && progress.death_time.is_none() {
+        progress.death_time = Some(now);
+        progress.deaths += 1;
+        notifications.send(HudNotification(format!(
+            "You died ({:?}), {} deaths so far",
+            agent.death_cause.unwrap_or(DeathCause::Unknown),
+            progress.deaths
+        )));
+
+        if progress.deaths >= MAX_DEATHS {
+            // wins over a pause queued on the same frame, the death is not noticed again
+            let _ = app_state.overwrite_set(AppState::GameOver);
+        }
+    }
+}
+
+// Brings the main character back at the last checkpoint. The guardians go back to their
+// posts, and the creatures around the checkpoint are cleared away. Everything else
+// stays as it was.
+pub fn respawn_main_character(
+    mut game: ResMut<Game>,
+    mut progress: ResMut<Progress>,
+    level: Res<Level>,
+    time: Res<WorldTime>,
+    mut notifications: EventWriter<HudNotification>,
+) {
+    let now = time.seconds_since_startup() as f32;
+
+    match progress.death_time {
+        Some(death_time) if now - death_time > MAIN_CHAR_RESPAWN_DELAY => {}
+        _ => return,
+    }
+
+    let position = progress.respawn_position(&level);
+    let mass = progress.checkpoint_mass.max(STARTING_MASS);
+
+    if let Some(agent) = game.agents.get_mut(&1) {
+        agent.position = position;
+        agent.last_position = position;
+        agent.target_position = position;
+        agent.main_char_target_pos = None;
+        agent.mass = mass;
+        agent.update_mass_properties();
+        agent.health = agent.max_health;
+        agent.energy = ENERGY_GROUND_STATE;
+        agent.boost = false;
+        agent.alive = true;
+        agent.death_cause = None;
+    }
+
+    let Game {
+        agents,
+        guardian_squads,
+        ..
+    } = game.as_mut();
+
+    for squad in guardian_squads.iter_mut() {
+        if squad.alert != AlertState::Idle {
+            if let Some(center) = squad.center(agents) {
+                squad.waypoint = squad.nearest_waypoint(center);
+            }
+            squad.set_alert(AlertState::Returning, now);
+        }
+    }
+
+    for (id, agent) in agents.iter_mut() {
+        if *id == 1 {
+            continue;
+        }
+
+        // the population manager respawns them away from the main character
+        if !agent.is_guardian && agent.position.distance(position) < CHECKPOINT_CLEAR_RADIUS {
+            agent.die(DeathCause::Unknown);
+        }
+
+        agent.sensors.agent_sight.remove(&1);
+        if let Goal::GoToAgent(1) | Goal::Bully(1) = agent.goal {
+            agent.goal = Goal::None;
+        }
+    }
+
+    progress.death_time = None;
+    progress.starving_since = None;
+    notifications.send(HudNotification("Back at the last checkpoint".to_string()));
+}
@@ -0,0 +1,391 @@
+use bevy::prelude::*;
+
+use rand::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::agent::*;
+use crate::cam::*;
+use crate::level::*;
+use crate::util::*;
+
+// Resolution of the sampled field
+pub const CURRENT_CELL_SIZE: f32 = 100.0;
+
+// Speed, in pixels per second, at which a current of 1.0 carries a particle of drag 1.0.
+// Close to the terminal speed of the main character under the same current.
+pub const CURRENT_DRIFT_SPEED: f32 = 40.0;
+
+// Food drifts freely, but not as fast as the water. It floats, so the downcurrent
+// doesn't take it to the floor.
+pub const FOOD_DRAG: f32 = 0.5;
+// The food kdtree follows the drift at this interval. Eating and regrowth rebuild it anyway,
+// and food never drifts more than a few pixels in between.
+pub const FOOD_KDTREE_INTERVAL: f32 = 0.5;
+
+pub const NUM_CURRENT_PARTICLES: usize = 400;
+// Particles are kept around the camera
+pub const CURRENT_PARTICLE_RANGE: f32 = 1200.0;
+pub const CURRENT_PARTICLE_LIFETIME: f32 = 6.0;
+
+pub const CURRENT_ARROW_SPACING: f32 = 250.0;
+
+/// An upward flow in a rectangle, strongest at its center
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Upwelling {
+    pub center: Vec2,
+    pub size: Vec2,
+    pub strength: f32,
+}
+
+/// A narrow and strong flow, from `from` to `to`
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Jet {
+    pub from: Vec2,
+    pub to: Vec2,
+    pub width: f32,
+    pub strength: f32,
+}
+
+/// The currents of a level, as written in the level file
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct CurrentsDescription {
+    /// Downcurrent at the floor, and how much it increases up to the top of the level
+    pub downcurrent: [f32; 2],
+    pub eddy_strength: f32,
+    /// Typical size of an eddy
+    pub eddy_size: f32,
+    pub seed: u64,
+    pub upwellings: Vec<Upwelling>,
+    pub jets: Vec<Jet>,
+}
+
+impl Default for CurrentsDescription {
+    fn default() -> Self {
+        Self {
+            downcurrent: [0.1, 3.0],
+            eddy_strength: 0.3,
+            eddy_size: 600.0,
+            seed: 0,
+            upwellings: Vec::new(),
+            jets: Vec::new(),
+        }
+    }
+}
+
+impl CurrentsDescription {
+    pub fn downcurrent_at(&self, position: Vec2, level_height: f32) -> Vec2 {
+        Vec2::new(
+            0.0,
+            -(self.downcurrent[0] + self.downcurrent[1] * position.y / level_height),
+        )
+    }
+
+    pub fn current_at(&self, position: Vec2, level_height: f32) -> Vec2 {
+        let mut current = self.downcurrent_at(position, level_height);
+
+        // the curl of a noise field has no sources nor sinks: it only swirls
+        if self.eddy_strength > 0.0 {
+            let p = position / self.eddy_size;
+            let e = 0.01;
+            let dx = (value_noise(p + Vec2::new(e, 0.0), self.seed)
+                - value_noise(p - Vec2::new(e, 0.0), self.seed))
+                / (2.0 * e);
+            let dy = (value_noise(p + Vec2::new(0.0, e), self.seed)
+                - value_noise(p - Vec2::new(0.0, e), self.seed))
+                / (2.0 * e);
+            current += Vec2::new(dy, -dx) * self.eddy_strength;
+        }
+
+        for upwelling in self.upwellings.iter() {
+            let d = (position - upwelling.center).abs() / (upwelling.size / 2.0);
+            if d.x < 1.0 && d.y < 1.0 {
+                let weight = (1.0 - d.x * d.x) * (1.0 - d.y * d.y);
+                current.y += upwelling.strength * weight;
+            }
+        }
+
+        for jet in self.jets.iter() {
+            let axis = jet.to - jet.from;
+            let length = axis.length();
+            if length <= 0.0 {
+                continue;
+            }
+
+            let direction = axis / length;
+            let along = (position - jet.from).dot(direction);
+            if along < 0.0 || along > length {
+                continue;
+            }
+
+            let across = (position - jet.from).perp_dot(direction) / jet.width;
+            current += direction * jet.strength * (-across * across).exp();
+        }
+
+        current
+    }
+}
+
+/// Smooth noise in [-1, 1], with features about one unit wide
+fn value_noise(p: Vec2, seed: u64) -> f32 {
+    let cell = p.floor();
+    let t = p - cell;
+    // smoothstep, for continuous derivatives
+    let t = t * t * (Vec2::splat(3.0) - 2.0 * t);
+
+    let (x, y) = (cell.x as i64, cell.y as i64);
+    let a = hash_noise(x, y, seed);
+    let b = hash_noise(x + 1, y, seed);
+    let c = hash_noise(x, y + 1, seed);
+    let d = hash_noise(x + 1, y + 1, seed);
+
+    let bottom = a + (b - a) * t.x;
+    let top = c + (d - c) * t.x;
+    bottom + (top - bottom) * t.y
+}
+
+fn hash_noise(x: i64, y: i64, seed: u64) -> f32 {
+    let mut h = seed ^ (x as u64).wrapping_mul(0x9E37_79B9_7F4A_7C15);
+    h ^= (y as u64).wrapping_mul(0xC2B2_AE3D_27D4_EB4F);
+    h = (h ^ (h >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    h = (h ^ (h >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    h ^= h >> 31;
+    (h >> 40) as f32 / (1u64 << 23) as f32 - 1.0
+}
+
+/// The currents of the level, sampled on a grid once at startup
+pub struct CurrentField {
+    pub description: CurrentsDescription,
+    pub level_height: f32,
+    pub columns: usize,
+    pub rows: usize,
+    pub samples: Vec<Vec2>,
+    /// Whether each sample is inside a rock
+    pub blocked: Vec<bool>,
+}
+
+impl CurrentField {
+    pub fn from_level(level: &Level) -> Self {
+        let columns = (level.width / CURRENT_CELL_SIZE).ceil() as usize + 1;
+        let rows = (level.height / CURRENT_CELL_SIZE).ceil() as usize + 1;
+
+        let mut samples = Vec::with_capacity(columns * rows);
+        let mut blocked = Vec::with_capacity(columns * rows);
+        for row in 0..rows {
+            for column in 0..columns {
+                let position = Vec2::new(column as f32, row as f32) * CURRENT_CELL_SIZE;
+
+                // the water doesn't flow through the rocks
+                if level.is_blocked(position, 0.0) {
+                    samples.push(Vec2::ZERO);
+                    blocked.push(true);
+                } else {
+                    samples.push(level.currents.current_at(position, level.height));
+                    blocked.push(false);
+                }
+            }
+        }
+
+        Self {
+            description: level.currents.clone(),
+            level_height: level.height,
+            columns,
+            rows,
+            samples,
+            blocked,
+        }
+    }
+
+    /// Cheap, coarse version of `Level::is_blocked`, from the nearest sample
+    pub fn is_blocked(&self, position: Vec2) -> bool {
+        let p = (position / CURRENT_CELL_SIZE).round().max(Vec2::ZERO);
+        let column = (p.x as usize).min(self.columns - 1);
+        let row = (p.y as usize).min(self.rows - 1);
+        self.blocked[row * self.columns + column]
+    }
+
+    /// Bilinear interpolation of the samples, clamped to the level
+    pub fn sample(&self, position: Vec2) -> Vec2 {
+        let p = (position / CURRENT_CELL_SIZE).max(Vec2::ZERO);
+        let column = (p.x as usize).min(self.columns - 2);
+        let row = (p.y as usize).min(self.rows - 2);
+        let t = (p - Vec2::new(column as f32, row as f32)).min(Vec2::ONE);
+
+        let at = |column: usize, row: usize| self.samples[row * self.columns + column];
+        let bottom = at(column, row).lerp(at(column + 1, row), t.x);
+        let top = at(column, row + 1).lerp(at(column + 1, row + 1), t.x);
+        bottom.lerp(top, t.y)
+    }
+}
+
+impl Agent {
+    /// Small creatures get carried away more easily. 1.0 at STARTING_MASS.
+    pub fn current_drag(&self) -> f32 {
+        (STARTING_MASS / self.mass.max(STARTING_MASS * 0.1)).sqrt()
+    }
+}
+
+// Food has no inertia, it simply goes with the flow
+pub fn drift_food(
+    mut game: ResMut<Game>,
+    mut kdtrees: ResMut<KdTrees>,
+    field: Res<CurrentField>,
+    level: Res<Level>,
+    time: Res<WorldTime>,
+    mut query: Query<(&FoodComp, &mut Transform)>,
+) {
+    let timestep = time.delta_seconds();
+
+    for (_id, food) in game.foods.iter_mut() {
+        let current = field.sample(food.position)
+            - field
+                .description
+                .downcurrent_at(food.position, field.level_height);
+        let new_position = food.position + current * FOOD_DRAG * CURRENT_DRIFT_SPEED * timestep;
+
+        // food piles up against the rocks and the walls
+        if new_position != food.position
+            && level.contains(new_position)
+            && !field.is_blocked(new_position)
+        {
+            food.position = new_position;
+            kdtrees.food_drifted = true;
+        }
+    }
+
+    for (food_comp, mut transform) in query.iter_mut() {
+        if let Some(food) = game.foods.get(&food_comp.id) {
+            transform.translation.x = food.position.x;
+            transform.translation.y = food.position.y;
+        }
+    }
+
+    let now = time.seconds_since_startup() as f32;
+    if kdtrees.food_drifted && now - kdtrees.food_built_at > FOOD_KDTREE_INTERVAL {
+        kdtrees.gen_food_kdtree(&game.foods);
+        kdtrees.food_drifted = false;
+        kdtrees.food_built_at = now;
+    }
+}
+
+#[derive(Component)]
+pub struct CurrentParticle {
+    pub age: f32,
+    pub lifetime: f32,
+}
+
+#[derive(Component)]
+pub struct CurrentArrow;
+
+/// Debug overlay of the current field, toggled with F3
+#[derive(Default)]
+pub struct CurrentsOverlay {
+    pub visible: bool,
+}
+
+pub fn spawn_current_visuals(mut commands: Commands, field: Res<CurrentField>, level: Res<Level>) {
+    let mut rng = rand::thread_rng();
+
+    for _ in 0..NUM_CURRENT_PARTICLES {
+        commands
+            .spawn_bundle(SpriteBundle {
+                sprite: Sprite {
+                    color: Color::rgba(0.8, 0.9, 1.0, 0.0),
+                    custom_size: Some(Vec2::splat(6.0)),
+                    ..Default::default()
+                },
+                transform: Transform::from_translation(Vec3::new(0.0, 0.0, 0.02)),
+                ..Default::default()
+            })
+            .insert(CurrentParticle {
+                // respawned around the camera on the first frame
+                age: CURRENT_PARTICLE_LIFETIME,
+                lifetime: rng.gen_range(0.5..1.0) * CURRENT_PARTICLE_LIFETIME,
+            });
+    }
+
+    let columns = (level.width / CURRENT_ARROW_SPACING) as usize;
+    let rows = (level.height / CURRENT_ARROW_SPACING) as usize;
+
+    for row in 0..rows {
+        for column in 0..columns {
+            let position = (Vec2::new(column as f32, row as f32) + 0.5) * CURRENT_ARROW_SPACING;
+            let current = field.sample(position);
+
+            let mut transform = Transform::from_translation(position.extend(5.0));
+            transform.rotation = Quat::from_rotation_z(current.y.atan2(current.x));
+
+            commands
+                .spawn_bundle(SpriteBundle {
+                    sprite: Sprite {
+                        color: Color::rgba(1.0, 0.8, 0.2, 0.8),
+                        custom_size: Some(Vec2::new(
+                            (current.length() * 50.0).clamp(10.0, CURRENT_ARROW_SPACING),
+                            8.0,
+                        )),
+                        ..Default::default()
+                    },
+                    visibility: Visibility { is_visible: false },
+                    transform,
+                    ..Default::default()
+                })
+                .insert(CurrentArrow);
+        }
+    }
+}
+
+pub fn toggle_currents_overlay(
+    actions: Res<Actions>,
+    mut overlay: ResMut<CurrentsOverlay>,
+    mut query: Query<&mut Visibility, With<CurrentArrow>>,
+) {
+    if actions.just_pressed(Action::ToggleCurrents) {
+        overlay.visible = !overlay.visible;
+
+        for mut visibility in query.iter_mut() {
+            visibility.is_visible = overlay.visible;
+        }
+    }
+}
+
+// Specks of matter drifting with the water, around the camera
+pub fn drift_particles(
+    field: Res<CurrentField>,
+    time: Res<Time>,
+    cam_query: Query<&Transform, With<Cam>>,
+    mut query: Query<(&mut Transform, &mut Sprite, &mut CurrentParticle), Without<Cam>>,
+) {
+    let mut rng = rand::thread_rng();
+    let timestep = time.delta_seconds();
+
+    let cam_position = match cam_query.iter().next() {
+        Some(cam_transform) => cam_transform.translation.truncate(),
+        None => return,
+    };
+
+    for (mut transform, mut sprite, mut particle) in query.iter_mut() {
+        let position = transform.translation.truncate();
+        particle.age += timestep;
+
+        if particle.age > particle.lifetime
+            || position.distance(cam_position) > CURRENT_PARTICLE_RANGE
+        {
+            let offset = Vec2::new(rng.gen_range(-1.0..1.0), rng.gen_range(-1.0..1.0))
+                * CURRENT_PARTICLE_RANGE;
+            transform.translation.x = cam_position.x + offset.x;
+            transform.translation.y = cam_position.y + offset.y;
+            particle.age = 0.0;
+            continue;
+        }
+
+        let velocity = field.sample(position) * CURRENT_DRIFT_SPEED;
+        transform.translation.x += velocity.x * timestep;
+        transform.translation.y += velocity.y * timestep;
+
+        // fade in and out
+        let life = particle.age / particle.lifetime;
+        sprite
+            .color
+            .set_a(0.4 * (std::f32::consts::PI * life).sin());
+    }
+}
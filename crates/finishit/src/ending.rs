@@ -0,0 +1,446 @@
+use bevy::prelude::*;
+
+use serde::{Deserialize, Serialize};
+
+use crate::cam::*;
+use crate::checkpoints::*;
+use crate::level::*;
+use crate::music::*;
+use crate::states::*;
+use crate::util::*;
+
+// In front of the HUD and the minimap, relative to the camera. The cards go in front of it.
+pub const ENDING_FADE_Z: f32 = 85.0;
+pub const ENDING_CARD_Z: f32 = 90.0;
+
+/// A line of text of a card. `{time}`, `{bumped}`, `{guardian_hits}` and `{deaths}` are
+/// replaced by the numbers of the run.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct CardLine {
+    pub text: String,
+    pub size: f32,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum CameraTarget {
+    MainCharacter,
+    /// Above the main character, at the height of the surface
+    Surface,
+    Position(Vec2),
+}
+
+fn default_card_color() -> [f32; 3] {
+    [1.0, 1.0, 1.0]
+}
+
+/// One step of the ending. Steps are played in order, each for its duration.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum EndingStep {
+    /// Text in the middle of the screen, faded in and out over `fade` seconds
+    Card {
+        lines: Vec<CardLine>,
+        duration: f32,
+        #[serde(default)]
+        fade: f32,
+        #[serde(default = "default_card_color")]
+        color: [f32; 3],
+        /// Position on screen, in pixels from the center
+        #[serde(default)]
+        offset: Vec2,
+        /// The card is skipped for runs shorter than this, in seconds...
+        #[serde(default)]
+        min_run_time: Option<f32>,
+        /// ... or longer than this
+        #[serde(default)]
+        max_run_time: Option<f32>,
+    },
+    /// The camera glides to the target. The zoom is relative to BASE_ZOOM.
+    Camera {
+        target: CameraTarget,
+        zoom: f32,
+        duration: f32,
+    },
+    /// The screen darkens, or clears up, to `alpha`
+    Fade {
+        alpha: f32,
+        duration: f32,
+    },
+    Music {
+        cue: MusicCue,
+    },
+    Wait {
+        duration: f32,
+    },
+}
+
+impl EndingStep {
+    pub fn duration(&self) -> f32 {
+        match self {
+            EndingStep::Card { duration, .. }
+            | EndingStep::Camera { duration, .. }
+            | EndingStep::Fade { duration, .. }
+            | EndingStep::Wait { duration } => *duration,
+            EndingStep::Music { .. } => 0.0,
+        }
+    }
+
+    fn is_played(&self, run_time: f32) -> bool {
+        match self {
+            EndingStep::Card {
+                min_run_time,
+                max_run_time,
+                ..
+            } => {
+                min_run_time.map_or(true, |min| run_time >= min)
+                    && max_run_time.map_or(true, |max| run_time < max)
+            }
+            _ => true,
+        }
+    }
+}
+
+/// The sequence played once the main character reaches the surface, from the embedded
+/// ending.json. The game restarts at its end.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Ending {
+    pub steps: Vec<EndingStep>,
+}
+
+impl Default for Ending {
+    fn default() -> Self {
+        serde_json::from_str(&include_str!("ending.json")).unwrap()
+    }
+}
+
+/// Where the ending is at
+#[derive(Default)]
+pub struct EndingPlayer {
+    pub step: usize,
+    pub step_start: f32,
+    pub started: bool,
+    /// Alpha of the screen fade when the current fade step started
+    pub fade_from: f32,
+    pub fade: f32,
+}
+
+/// Cards and screen fade of the ending, taken down when it ends
+#[derive(Component)]
+pub struct EndingEntity;
+
+#[derive(Component)]
+pub struct EndingCard {
+    pub color: [f32; 3],
+}
+
+#[derive(Component)]
+pub struct EndingFade;
+
+/// When the main character reached the surface, in seconds since startup
+pub struct GameEndTime {
+    pub time: f32,
+}
+
+/// Numbers of the run, written into the cards
+pub struct RunSummary {
+    pub time: f32,
+    pub bumped: u32,
+    pub guardian_hits: u32,
+    pub deaths: u32,
+}
+
+impl RunSummary {
+    pub fn fill(&self, text: &str) -> String {
+        let minutes = (self.time / 60.0).floor();
+        let seconds = self.time - minutes * 60.0;
+        text.replace("{time}", &format!("{}:{:02.0}", minutes, seconds.floor()))
+            .replace("{bumped}", &self.bumped.to_string())
+            .replace("{guardian_hits}", &self.guardian_hits.to_string())
+            .replace("{deaths}", &self.deaths.to_string())
+    }
+}
+
+fn spawn_card(
+    commands: &mut Commands,
+    asset_server: &AssetServer,
+    cam_entity: Entity,
+    lines: &[CardLine],
+    color: [f32; 3],
+    offset: Vec2,
+    summary: &RunSummary,
+) {
+    let text_alignment = TextAlignment {
+        vertical: VerticalAlign::Center,
+        horizontal: HorizontalAlign::Center,
+    };
+
+    // centered on the offset
+    let height = lines.iter().map(|line| line.size * 1.2).sum::<f32>();
+    let mut y = offset.y + height / 2.0;
+
+    for line in lines {
+        y -= line.size * 0.6;
+        let text_style = TextStyle {
+            font: asset_server.load("fonts/Roboto-Regular.ttf"),
+            font_size: line.size,
+            color: Color::rgba(color[0], color[1], color[2], 0.0),
+        };
+
+        let card = commands
+            .spawn_bundle(Text2dBundle {
+                text: Text::with_section(summary.fill(&line.text), text_style, text_alignment),
+                transform: Transform::from_translation(Vec3::new(offset.x, y, ENDING_CARD_Z)),
+                ..Default::default()
+            })
+            .insert(EndingEntity)
+            .insert(EndingCard { color })
+            .id();
+        commands.entity(cam_entity).push_children(&[card]);
+
+        y -= line.size * 0.6;
+    }
+}
+
+pub fn start_ending(
+    mut commands: Commands,
+    mut player: ResMut<EndingPlayer>,
+    time: Res<Time>,
+    cam_query: Query<Entity, With<Cam>>,
+) {
+    *player = EndingPlayer {
+        step_start: time.seconds_since_startup() as f32,
+        ..Default::default()
+    };
+
+    // over the whole screen, whatever the zoom
+    let fade = commands
+        .spawn_bundle(SpriteBundle {
+            sprite: Sprite {
+                color: Color::rgba(0.0, 0.0, 0.0, 0.0),
+                custom_size: Some(Vec2::splat(10000.0)),
+                ..Default::default()
+            },
+            transform: Transform::from_translation(Vec3::new(0.0, 0.0, ENDING_FADE_Z)),
+            ..Default::default()
+        })
+        .insert(EndingEntity)
+        .insert(EndingFade)
+        .id();
+    commands.entity(cam_query.single()).push_children(&[fade]);
+}
+
+pub fn play_ending(
+    mut commands: Commands,
+    ending: Res<Ending>,
+    mut player: ResMut<EndingPlayer>,
+    mut director: ResMut<MusicDirector>,
+    mut app_state: ResMut<State<AppState>>,
+    asset_server: Res<AssetServer>,
+    game: Res<Game>,
+    progress: Res<Progress>,
+    level: Res<Level>,
+    game_end_time: Res<GameEndTime>,
+    time: Res<Time>,
+    mut cam_query: Query<(Entity, &mut Cam)>,
+    mut card_query: Query<(Entity, &EndingCard, &mut Text)>,
+    mut fade_query: Query<&mut Sprite, With<EndingFade>>,
+) {
+    let now = time.seconds_since_startup() as f32;
+    let summary = RunSummary {
+        time: game_end_time.time - game.time,
+        bumped: progress.bumped.len() as u32,
+        guardian_hits: progress.guardian_hits,
+        deaths: progress.deaths,
+    };
+
+    // the steps that don't apply to this run are skipped
+    while let Some(step) = ending.steps.get(player.step) {
+        if step.is_played(summary.time) {
+            break;
+        }
+        player.step += 1;
+    }
+
+    let step = match ending.steps.get(player.step) {
+        Some(step) => step,
+        None => {
+            app_state.overwrite_set(AppState::Restarting).unwrap();
+            return;
+        }
+    };
+    let (cam_entity, mut cam) = cam_query.single_mut();
+
+    if !player.started {
+        player.started = true;
+        player.step_start = now;
+        player.fade_from = player.fade;
+
+        match step {
+            EndingStep::Card {
+                lines,
+                color,
+                offset,
+                ..
+            } => spawn_card(
+                &mut commands,
+                &asset_server,
+                cam_entity,
+                lines,
+                *color,
+                *offset,
+                &summary,
+            ),
+            EndingStep::Camera { target, zoom, .. } => {
+                let main_char_position = game
+                    .agents
+                    .get(&1)
+                    .map_or(level.start_position, |agent| agent.position);
+                let position = match target {
+                    CameraTarget::MainCharacter => main_char_position,
+                    CameraTarget::Surface => Vec2::new(main_char_position.x, level.win_height),
+                    CameraTarget::Position(position) => *position,
+                };
+                cam.shot = Some((position, zoom * BASE_ZOOM));
+            }
+            EndingStep::Music { cue } => director.ending_cue = Some(*cue),
+            EndingStep::Fade { .. } | EndingStep::Wait { .. } => {}
+        }
+    }
+
+    let elapsed = now - player.step_start;
+    let duration = step.duration();
+
+    match step {
+        EndingStep::Card { fade, .. } => {
+            let alpha = if *fade > 0.0 {
+                (elapsed / fade)
+                    .min((duration - elapsed) / fade)
+                    .clamp(0.0, 1.0)
+            } else {
+                1.0
+            };
+            for (_entity, card, mut text) in card_query.iter_mut() {
+                for section in text.sections.iter_mut() {
+                    section.style.color =
+                        Color::rgba(card.color[0], card.color[1], card.color[2], alpha);
+                }
+            }
+        }
+        EndingStep::Fade { alpha, .. } => {
+            let progress = if duration > 0.0 {
+                (elapsed / duration).min(1.0)
+            } else {
+                1.0
+            };
+            player.fade = player.fade_from + (alpha - player.fade_from) * progress;
+            for mut sprite in fade_query.iter_mut() {
+                sprite.color.set_a(player.fade);
+            }
+        }
+        _ => {}
+    }
+
+    if elapsed >= duration {
+        if let EndingStep::Card { .. } = step {
+            for (entity, _card, _text) in card_query.iter() {
+                commands.entity(entity).despawn_recursive();
+            }
+        }
+        player.step += 1;
+        player.started = false;
+    }
+}
+
+// Also when the ending is skipped
+pub fn finish_ending(
+    mut commands: Commands,
+    mut director: ResMut<MusicDirector>,
+    mut cam_query: Query<&mut Cam>,
+    query: Query<Entity, With<EndingEntity>>,
+) {
+    for entity in query.iter() {
+        commands.entity(entity).despawn_recursive();
+    }
+    for mut cam in cam_query.iter_mut() {
+        cam.shot = None;
+    }
+    director.ending_cue = None;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn summary(time: f32) -> RunSummary {
+        RunSummary {
+            time,
+            bumped: 12,
+            guardian_hits: 3,
+            deaths: 0,
+        }
+    }
+
+    fn card(min_run_time: Option<f32>, max_run_time: Option<f32>) -> EndingStep {
+        EndingStep::Card {
+            lines: vec![],
+            duration: 4.0,
+            fade: 1.0,
+            color: default_card_color(),
+            offset: Vec2::ZERO,
+            min_run_time,
+            max_run_time,
+        }
+    }
+
+    #[test]
+    fn fill_writes_the_numbers_of_the_run() {
+        let text = "{time} up, {bumped} bumped, {guardian_hits} smashes, {deaths} deaths";
+        assert_eq!(
+            summary(125.7).fill(text),
+            "2:05 up, 12 bumped, 3 smashes, 0 deaths"
+        );
+    }
+
+    #[test]
+    fn fill_pads_the_seconds() {
+        assert_eq!(summary(0.0).fill("{time}"), "0:00");
+        assert_eq!(summary(59.99).fill("{time}"), "0:59");
+        assert_eq!(summary(3600.0).fill("{time}"), "60:00");
+        assert_eq!(summary(1.0).fill("no numbers"), "no numbers");
+    }
+
+    #[test]
+    fn cards_are_played_within_their_run_times() {
+        assert!(card(None, None).is_played(0.0));
+
+        let fast = card(None, Some(300.0));
+        assert!(fast.is_played(299.0));
+        assert!(!fast.is_played(300.0));
+
+        let slow = card(Some(300.0), None);
+        assert!(!slow.is_played(299.0));
+        assert!(slow.is_played(300.0));
+
+        let between = card(Some(100.0), Some(200.0));
+        assert!(!between.is_played(50.0));
+        assert!(between.is_played(150.0));
+        assert!(!between.is_played(250.0));
+    }
+
+    #[test]
+    fn other_steps_are_always_played() {
+        assert!(EndingStep::Wait { duration: 1.0 }.is_played(0.0));
+        assert!(EndingStep::Fade {
+            alpha: 1.0,
+            duration: 2.0
+        }
+        .is_played(1000.0));
+    }
+
+    #[test]
+    fn embedded_ending_parses() {
+        let ending = Ending::default();
+        assert!(!ending.steps.is_empty());
+    }
+}
@@ -0,0 +1,399 @@
+use bevy::prelude::*;
+
+use rand::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use std::collections::BTreeMap;
+
+use crate::agent::*;
+use crate::util::*;
+
+// A squad has reached a waypoint when its center is this close to it
+pub const WAYPOINT_REACHED_DISTANCE: f32 = 150.0;
+
+/// A squad stops being suspicious and charges the main character
+pub struct GuardianChargeEvent {
+    pub position: Vec2,
+}
+
+/// One guardian squad, as written by the level designer in the level file
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct GuardianSquadDescription {
+    pub name: String,
+    pub count: u32,
+    /// Patrolled in a loop, in world coordinates
+    pub waypoints: Vec<Vec2>,
+    /// Distance between two guardians of the formation
+    pub spacing: f32,
+
+    /// The squad notices the main character when any of its guardians is this close to it
+    pub detection_radius: f32,
+    /// Time the main character has to stay in sight before the squad charges
+    pub suspicion_time: f32,
+
+    /// The squad gives up after chasing for this long...
+    pub max_chase_time: f32,
+    /// ... or when the main character is this far from the patrolled waypoints...
+    pub leash_radius: f32,
+    /// ... or when the main character has been out of sight for this long
+    pub lose_sight_time: f32,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct GuardianLayout {
+    pub squads: Vec<GuardianSquadDescription>,
+}
+
+impl GuardianLayout {
+    /// Generates the guardians of every squad and adds them to `agents`
+    pub fn spawn_squads(
+        &self,
+        agents: &mut BTreeMap<u32, Agent>,
+        rng: &mut StdRng,
+    ) -> Vec<GuardianSquad> {
+        self.squads
+            .iter()
+            .filter(|description| !description.waypoints.is_empty())
+            .map(|description| GuardianSquad::spawn(description.clone(), agents, rng))
+            .collect()
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum AlertState {
+    Idle,
+    Suspicious,
+    Chasing,
+    Returning,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct GuardianSquad {
+    pub description: GuardianSquadDescription,
+    pub members: Vec<u32>,
+
+    pub alert: AlertState,
+    /// Time at which the current alert state was entered
+    pub alert_time: f32,
+    pub last_spotted_time: f32,
+    pub last_known_position: Vec2,
+
+    pub waypoint: usize,
+    /// Shown on the minimap, since one of its guardians came within radar range of the
+    /// main character
+    #[serde(default)]
+    pub revealed: bool,
+}
+
+impl GuardianSquad {
+    pub fn spawn(
+        description: GuardianSquadDescription,
+        agents: &mut BTreeMap<u32, Agent>,
+        rng: &mut StdRng,
+    ) -> Self {
+        let mut squad = GuardianSquad {
+            description,
+            members: Vec::new(),
+            alert: AlertState::Idle,
+            alert_time: 0.0,
+            last_spotted_time: 0.0,
+            last_known_position: Vec2::ZERO,
+            waypoint: 0,
+            revealed: false,
+        };
+
+        for k in 0..squad.description.count as usize {
+            let mut id: u32 = rng.gen();
+            // avoid accidentally duplicating the main character's id, or any other id
+            while id == 1 || agents.contains_key(&id) {
+                id = rng.gen();
+            }
+
+            let pos = squad.description.waypoints[0] + squad.formation_offset(k);
+            agents.insert(id, Agent::gen_guardian(pos, id, rng));
+            squad.members.push(id);
+        }
+
+        squad
+    }
+
+    /// Guardians patrol side by side, centered on the waypoint
+    pub fn formation_offset(&self, k: usize) -> Vec2 {
+        let n = self.description.count.max(1) as f32;
+        Vec2::new((k as f32 - (n - 1.0) / 2.0) * self.description.spacing, 0.0)
+    }
+
+    pub fn center(&self, agents: &BTreeMap<u32, Agent>) -> Option<Vec2> {
+        let positions = self
+            .members
+            .iter()
+            .filter_map(|id| agents.get(id))
+            .map(|guardian| guardian.position)
+            .collect::<Vec<_>>();
+
+        if positions.is_empty() {
+            return None;
+        }
+
+        Some(positions.iter().fold(Vec2::ZERO, |acc, pos| acc + *pos) / positions.len() as f32)
+    }
+
+    pub fn nearest_waypoint(&self, position: Vec2) -> usize {
+        let mut nearest = 0;
+        for (k, waypoint) in self.description.waypoints.iter().enumerate() {
+            if waypoint.distance(position) < self.description.waypoints[nearest].distance(position)
+            {
+                nearest = k;
+            }
+        }
+        nearest
+    }
+
+    pub fn distance_to_patrol(&self, position: Vec2) -> f32 {
+        self.description.waypoints[self.nearest_waypoint(position)].distance(position)
+    }
+
+    pub fn set_alert(&mut self, alert: AlertState, time: f32) {
+        if self.alert != alert {
+            self.alert = alert;
+            self.alert_time = time;
+        }
+    }
+
+    pub fn update_alert(&mut self, center: Vec2, spotted: Option<Vec2>, time: f32) {
+        if let Some(position) = spotted {
+            self.last_spotted_time = time;
+            self.last_known_position = position;
+        }
+
+        let d = &self.description;
+        match self.alert {
+            AlertState::Idle => {
+                if spotted.is_some() {
+                    self.set_alert(AlertState::Suspicious, time);
+                } else if center.distance(d.waypoints[self.waypoint]) < WAYPOINT_REACHED_DISTANCE {
+                    self.waypoint = (self.waypoint + 1) % d.waypoints.len();
+                }
+            }
+            AlertState::Suspicious => {
+                if spotted.is_none() && time - self.last_spotted_time > d.suspicion_time {
+                    self.set_alert(AlertState::Idle, time);
+                } else if spotted.is_some() && time - self.alert_time > d.suspicion_time {
+                    self.set_alert(AlertState::Chasing, time);
+                }
+            }
+            AlertState::Chasing => {
+                let gave_up = time - self.alert_time > d.max_chase_time
+                    || time - self.last_spotted_time > d.lose_sight_time
+                    || self.distance_to_patrol(self.last_known_position) > d.leash_radius;
+
+                if gave_up {
+                    self.waypoint = self.nearest_waypoint(center);
+                    self.set_alert(AlertState::Returning, time);
+                }
+            }
+            AlertState::Returning => {
+                if center.distance(d.waypoints[self.waypoint]) < WAYPOINT_REACHED_DISTANCE {
+                    self.set_alert(AlertState::Idle, time);
+                }
+            }
+        }
+    }
+}
+
+pub fn guardian_squads_behaviour(
+    mut game: ResMut<Game>,
+    time: Res<WorldTime>,
+    mut charge_events: EventWriter<GuardianChargeEvent>,
+) {
+    let now = time.seconds_since_startup() as f32;
+
+    let main_char_position = game
+        .agents
+        .get(&1)
+        .filter(|agent| agent.alive)
+        .map(|agent| agent.position);
+
+    let Game {
+        agents,
+        guardian_squads,
+        ..
+    } = game.as_mut();
+
+    for squad in guardian_squads.iter_mut() {
+        // guardians can die in fights
+        squad.members.retain(|id| agents.contains_key(id));
+
+        let center = match squad.center(agents) {
+            Some(center) => center,
+            None => continue,
+        };
+
+        let spotted = main_char_position.filter(|position| {
+            squad
+                .members
+                .iter()
+                .filter_map(|id| agents.get(id))
+                .any(|guardian| {
+                    guardian.position.distance(*position) < squad.description.detection_radius
+                })
+        });
+
+        let alert = squad.alert;
+        squad.update_alert(center, spotted, now);
+        if alert != AlertState::Chasing && squad.alert == AlertState::Chasing {
+            charge_events.send(GuardianChargeEvent { position: center });
+        }
+
+        let waypoint = squad.description.waypoints[squad.waypoint];
+        for (k, id) in squad.members.iter().enumerate() {
+            if let Some(guardian) = agents.get_mut(id) {
+                let offset = squad.formation_offset(k);
+                guardian.goal = match squad.alert {
+                    AlertState::Idle | AlertState::Returning => Goal::GoTo(waypoint + offset),
+                    AlertState::Suspicious => Goal::GoTo(squad.last_known_position + offset),
+                    AlertState::Chasing => Goal::Bully(1),
+                };
+                guardian.goal_time = now;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn squad() -> GuardianSquad {
+        GuardianSquad {
+            description: GuardianSquadDescription {
+                name: "test".to_string(),
+                count: 3,
+                waypoints: vec![
+                    Vec2::new(0.0, 0.0),
+                    Vec2::new(1000.0, 0.0),
+                    Vec2::new(1000.0, 1000.0),
+                ],
+                spacing: 100.0,
+                detection_radius: 500.0,
+                suspicion_time: 1.0,
+                max_chase_time: 10.0,
+                leash_radius: 2000.0,
+                lose_sight_time: 3.0,
+            },
+            members: Vec::new(),
+            alert: AlertState::Idle,
+            alert_time: 0.0,
+            last_spotted_time: 0.0,
+            last_known_position: Vec2::ZERO,
+            waypoint: 0,
+            revealed: false,
+        }
+    }
+
+    fn chasing(time: f32) -> GuardianSquad {
+        let mut squad = squad();
+        squad.alert = AlertState::Chasing;
+        squad.alert_time = time;
+        squad.last_spotted_time = time;
+        squad.last_known_position = Vec2::new(500.0, 100.0);
+        squad
+    }
+
+    #[test]
+    fn idle_squad_patrols_the_waypoints_in_a_loop() {
+        let mut squad = squad();
+
+        squad.update_alert(Vec2::new(500.0, 0.0), None, 1.0);
+        assert_eq!(squad.waypoint, 0);
+
+        squad.update_alert(Vec2::new(10.0, 0.0), None, 2.0);
+        assert_eq!(squad.waypoint, 1);
+
+        squad.waypoint = 2;
+        squad.update_alert(Vec2::new(1000.0, 990.0), None, 3.0);
+        assert_eq!(squad.waypoint, 0);
+        assert_eq!(squad.alert, AlertState::Idle);
+    }
+
+    #[test]
+    fn spotting_the_main_character_makes_the_squad_suspicious() {
+        let mut squad = squad();
+        let seen = Vec2::new(300.0, 300.0);
+
+        squad.update_alert(Vec2::ZERO, Some(seen), 5.0);
+        assert_eq!(squad.alert, AlertState::Suspicious);
+        assert_eq!(squad.alert_time, 5.0);
+        assert_eq!(squad.last_known_position, seen);
+        // the patrol waits
+        assert_eq!(squad.waypoint, 0);
+    }
+
+    #[test]
+    fn suspicious_squad_charges_once_the_suspicion_time_is_over() {
+        let mut squad = squad();
+        let seen = Some(Vec2::new(300.0, 300.0));
+        squad.update_alert(Vec2::ZERO, seen, 5.0);
+
+        squad.update_alert(Vec2::ZERO, seen, 5.5);
+        assert_eq!(squad.alert, AlertState::Suspicious);
+
+        squad.update_alert(Vec2::ZERO, seen, 6.5);
+        assert_eq!(squad.alert, AlertState::Chasing);
+        assert_eq!(squad.alert_time, 6.5);
+    }
+
+    #[test]
+    fn suspicious_squad_calms_down_out_of_sight() {
+        let mut squad = squad();
+        squad.update_alert(Vec2::ZERO, Some(Vec2::new(300.0, 300.0)), 5.0);
+
+        squad.update_alert(Vec2::ZERO, None, 5.5);
+        assert_eq!(squad.alert, AlertState::Suspicious);
+
+        squad.update_alert(Vec2::ZERO, None, 6.5);
+        assert_eq!(squad.alert, AlertState::Idle);
+    }
+
+    #[test]
+    fn chase_ends_after_the_max_chase_time() {
+        let mut squad = chasing(0.0);
+        let seen = Some(Vec2::new(500.0, 100.0));
+
+        squad.update_alert(Vec2::new(400.0, 0.0), seen, 9.0);
+        assert_eq!(squad.alert, AlertState::Chasing);
+
+        // back to the waypoint closest to where the chase ended
+        squad.update_alert(Vec2::new(900.0, 100.0), seen, 11.0);
+        assert_eq!(squad.alert, AlertState::Returning);
+        assert_eq!(squad.waypoint, 1);
+    }
+
+    #[test]
+    fn chase_ends_when_the_main_character_is_lost_or_too_far() {
+        let mut squad = chasing(0.0);
+        squad.update_alert(Vec2::ZERO, None, 2.0);
+        assert_eq!(squad.alert, AlertState::Chasing);
+        squad.update_alert(Vec2::ZERO, None, 4.0);
+        assert_eq!(squad.alert, AlertState::Returning);
+
+        let mut squad = chasing(0.0);
+        squad.update_alert(Vec2::ZERO, Some(Vec2::new(-3000.0, 0.0)), 1.0);
+        assert_eq!(squad.alert, AlertState::Returning);
+        assert_eq!(squad.waypoint, 0);
+    }
+
+    #[test]
+    fn returning_squad_is_idle_back_at_its_waypoint() {
+        let mut squad = chasing(0.0);
+        squad.update_alert(Vec2::new(900.0, 100.0), None, 20.0);
+        assert_eq!(squad.alert, AlertState::Returning);
+
+        squad.update_alert(Vec2::new(700.0, 0.0), None, 21.0);
+        assert_eq!(squad.alert, AlertState::Returning);
+
+        squad.update_alert(Vec2::new(1000.0, 50.0), None, 22.0);
+        assert_eq!(squad.alert, AlertState::Idle);
+        assert_eq!(squad.alert_time, 22.0);
+    }
+}
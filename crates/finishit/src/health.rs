@@ -0,0 +1,136 @@
+use bevy::prelude::*;
+
+use crate::agent::*;
+use crate::hud::*;
+use crate::population::*;
+use crate::tribe::*;
+use crate::util::*;
+use crate::*;
+
+// An agent of STARTING_MASS has 1.0 hit points
+pub const HEALTH_PER_MASS: f32 = 1.0 / STARTING_MASS;
+
+pub const DAMAGE_MULT: f32 = 0.5;
+// Bumps below this momentum are harmless
+pub const MIN_DAMAGE_MOMENTUM: f32 = 0.05;
+
+pub const GUARDIAN_TOUGHNESS: f32 = 4.0;
+pub const GUARDIAN_ATTACK: f32 = 2.0;
+// Extra damage dealt to the main character by the guardians, on top of the impact
+pub const GUARDIAN_DAMAGE: f32 = 0.15;
+
+// Fraction of the max health regained every second, once out of a fight
+pub const HEALTH_REGEN_RATE: f32 = 0.02;
+pub const HEALTH_REGEN_DELAY: f32 = 3.0;
+
+impl Agent {
+    pub fn health_ratio(&self) -> f32 {
+        if self.max_health > 0.0 {
+            (self.health / self.max_health).clamp(0.0, 1.0)
+        } else {
+            0.0
+        }
+    }
+
+    /// Damage taken by self when rammed by `attacker`
+    pub fn impact_damage(&self, attacker: &Agent) -> f32 {
+        let distance = self.position.distance(attacker.position).max(1.0);
+        let momentum = self.compute_agent_charging_momentum(attacker) / distance;
+
+        let mut damage = 0.0;
+        if momentum > MIN_DAMAGE_MOMENTUM {
+            damage = momentum * attacker.attack * DAMAGE_MULT;
+        }
+
+        if attacker.is_guardian && self.id == 1 {
+            damage += GUARDIAN_DAMAGE;
+        }
+
+        damage * self.armor_mult()
+    }
+}
+
+pub fn apply_collision_damage(
+    mut game: ResMut<Game>,
+    relations: Res<TribeRelations>,
+    mut world_rng: ResMut<WorldRng>,
+    time: Res<WorldTime>,
+    mut collision_events: EventReader<CollisionEvent>,
+    mut notifications: EventWriter<HudNotification>,
+) {
+    let now = time.seconds_since_startup() as f32;
+
+    for collision in collision_events.iter() {
+        let (damage, attacker_mass, attacker_position, allied) = match (
+            game.agents.get(&collision.agent_id),
+            game.agents.get(&collision.other_agent_id),
+        ) {
+            (Some(agent), Some(attacker)) => (
+                agent.impact_damage(attacker),
+                attacker.mass,
+                attacker.position,
+                relations.relation(agent, attacker) == Relation::Ally,
+            ),
+            _ => continue,
+        };
+
+        if damage <= 0.0 {
+            continue;
+        }
+
+        if let Some(agent) = game.agents.get_mut(&collision.agent_id) {
+            if !agent.alive {
+                continue;
+            }
+
+            agent.health -= damage;
+
+            if agent.health <= 0.0 {
+                agent.health = 0.0;
+                agent.die(DeathCause::Wounds);
+                // the death of the main character is reported by the lose conditions
+                if collision.other_agent_id == 1 {
+                    notifications.send(HudNotification("Creature killed".to_string()));
+                }
+            } else if agent.id != 1 && !agent.is_guardian && !allied {
+                // fight back or flee, depending on how bad it looks
+                agent.react_to_collision(
+                    &collision.other_agent_id,
+                    attacker_mass,
+                    attacker_position,
+                    now,
+                    &mut world_rng.rng,
+                );
+            }
+        }
+    }
+}
+
+pub fn regenerate_health(mut game: ResMut<Game>, time: Res<WorldTime>) {
+    let now = time.seconds_since_startup() as f32;
+
+    for (_id, agent) in game.agents.iter_mut() {
+        if !agent.alive || agent.is_starving() {
+            continue;
+        }
+
+        if now - agent.last_collision_time > HEALTH_REGEN_DELAY {
+            agent.health = (agent.health
+                + agent.max_health * HEALTH_REGEN_RATE * time.delta_seconds())
+            .min(agent.max_health);
+        }
+    }
+}
+
+// Creatures wear the color of their tribe, and lose it when wounded
+pub fn show_damage(game: Res<Game>, mut query: Query<(&AgentId, &mut CharacterUniform)>) {
+    let outsider: Vec4 = Color::hex("8c114a").unwrap().into();
+    let wounded: Vec4 = Color::rgb(0.35, 0.33, 0.33).into();
+
+    for (agent_id, mut character_uniform) in query.iter_mut() {
+        if let Some(agent) = game.agents.get(&agent_id.kdtree_hash) {
+            let healthy: Vec4 = agent.tribe.map_or(outsider, |tribe| tribe.color().into());
+            character_uniform.color = healthy.lerp(wounded, 1.0 - agent.health_ratio());
+        }
+    }
+}
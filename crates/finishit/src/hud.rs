@@ -0,0 +1,330 @@
+use bevy::prelude::*;
+
+use crate::cam::*;
+use crate::level::*;
+use crate::util::*;
+
+// Distance to the upper left corner of the window, in pixels
+pub const HUD_MARGIN: f32 = 16.0;
+// In front of the world and of the state texts, relative to the camera
+pub const HUD_Z: f32 = 80.0;
+
+pub const BAR_WIDTH: f32 = 200.0;
+pub const BAR_HEIGHT: f32 = 12.0;
+pub const BOOST_BAR_HEIGHT: f32 = 6.0;
+// Left of the bars and texts, to leave room for the depth gauge
+pub const HUD_COLUMN: f32 = 24.0;
+pub const GAUGE_WIDTH: f32 = 8.0;
+pub const GAUGE_HEIGHT: f32 = 300.0;
+
+// The energy bar is full from this much energy. Creatures are born with 1.0.
+pub const FULL_ENERGY: f32 = 2.0;
+// Below this fraction of the bar, the energy is shown as running out
+pub const LOW_ENERGY: f32 = 0.2;
+
+pub const NOTIFICATION_TIME: f32 = 2.5;
+pub const MAX_NOTIFICATIONS: usize = 4;
+pub const NOTIFICATION_SPACING: f32 = 20.0;
+
+/// A short text shown under the bars for NOTIFICATION_TIME
+pub struct HudNotification(pub String);
+
+/// Root of the HUD, a child of the camera so that it stays in the corner of the window
+#[derive(Component)]
+pub struct Hud;
+
+#[derive(Component)]
+pub struct HudEnergyBar;
+
+#[derive(Component)]
+pub struct HudBoostBar;
+
+#[derive(Component)]
+pub struct HudStageText;
+
+#[derive(Component)]
+pub struct HudDepthMarker;
+
+/// One of the texts that the notifications are written in, from the top
+#[derive(Component)]
+pub struct HudNotificationText(pub usize);
+
+/// The notifications on screen, with the time at which they go away
+#[derive(Default)]
+pub struct Notifications {
+    pub active: Vec<(String, f32)>,
+}
+
+pub struct HudPlugin;
+
+impl Plugin for HudPlugin {
+    fn build(&self, app: &mut App) {
+        // the camera is spawned by a startup system
+        app.add_event::<HudNotification>()
+            .insert_resource(Notifications::default())
+            .add_startup_system_to_stage(StartupStage::PostStartup, spawn_hud)
+            .add_system(place_hud)
+            .add_system(update_hud)
+            .add_system(show_notifications);
+    }
+}
+
+fn rect_sprite(size: Vec2, color: Color, position: Vec2, z: f32) -> SpriteBundle {
+    SpriteBundle {
+        sprite: Sprite {
+            color,
+            custom_size: Some(size),
+            ..Default::default()
+        },
+        transform: Transform::from_translation(position.extend(z)),
+        ..Default::default()
+    }
+}
+
+/// Fills a bar of the HUD from its left end, by `fraction` of BAR_WIDTH
+pub fn set_bar(sprite: &mut Sprite, transform: &mut Transform, fraction: f32) {
+    let width = BAR_WIDTH * fraction.clamp(0.0, 1.0);
+    let height = sprite.custom_size.map_or(BAR_HEIGHT, |size| size.y);
+    sprite.custom_size = Some(Vec2::new(width, height));
+    transform.translation.x = HUD_COLUMN + width / 2.0;
+}
+
+// Height of the depth gauge for a height of the level, from the top of the gauge
+fn gauge_y(level: &Level, height: f32) -> f32 {
+    -GAUGE_HEIGHT * (1.0 - (height / level.height).clamp(0.0, 1.0))
+}
+
+pub fn spawn_hud(
+    mut commands: Commands,
+    asset_server: Res<AssetServer>,
+    level: Res<Level>,
+    cam_query: Query<Entity, With<Cam>>,
+) {
+    let cam_entity = cam_query.single();
+    let mut children = Vec::new();
+
+    let background = Color::rgba(0.0, 0.0, 0.0, 0.5);
+    let bar_center = HUD_COLUMN + BAR_WIDTH / 2.0;
+
+    let energy_y = -BAR_HEIGHT / 2.0;
+    children.push(
+        commands
+            .spawn_bundle(rect_sprite(
+                Vec2::new(BAR_WIDTH, BAR_HEIGHT),
+                background,
+                Vec2::new(bar_center, energy_y),
+                0.1,
+            ))
+            .id(),
+    );
+    children.push(
+        commands
+            .spawn_bundle(rect_sprite(
+                Vec2::new(BAR_WIDTH, BAR_HEIGHT),
+                Color::YELLOW,
+                Vec2::new(bar_center, energy_y),
+                0.2,
+            ))
+            .insert(HudEnergyBar)
+            .id(),
+    );
+
+    let boost_y = -BAR_HEIGHT - 4.0 - BOOST_BAR_HEIGHT / 2.0;
+    children.push(
+        commands
+            .spawn_bundle(rect_sprite(
+                Vec2::new(BAR_WIDTH, BOOST_BAR_HEIGHT),
+                background,
+                Vec2::new(bar_center, boost_y),
+                0.1,
+            ))
+            .id(),
+    );
+    children.push(
+        commands
+            .spawn_bundle(rect_sprite(
+                Vec2::new(BAR_WIDTH, BOOST_BAR_HEIGHT),
+                Color::CYAN,
+                Vec2::new(bar_center, boost_y),
+                0.2,
+            ))
+            .insert(HudBoostBar)
+            .id(),
+    );
+
+    // the depth gauge, with the limits of the stages and the surface
+    children.push(
+        commands
+            .spawn_bundle(rect_sprite(
+                Vec2::new(GAUGE_WIDTH, GAUGE_HEIGHT),
+                background,
+                Vec2::new(GAUGE_WIDTH / 2.0, -GAUGE_HEIGHT / 2.0),
+                0.1,
+            ))
+            .id(),
+    );
+    let limits = &level.stage_limits;
+    for height in [limits.bottom, limits.mid, limits.top] {
+        children.push(
+            commands
+                .spawn_bundle(rect_sprite(
+                    Vec2::new(GAUGE_WIDTH * 1.5, 1.0),
+                    Color::rgba(1.0, 1.0, 1.0, 0.6),
+                    Vec2::new(GAUGE_WIDTH / 2.0, gauge_y(&level, height * level.height)),
+                    0.2,
+                ))
+                .id(),
+        );
+    }
+    children.push(
+        commands
+            .spawn_bundle(rect_sprite(
+                Vec2::new(GAUGE_WIDTH * 1.5, 2.0),
+                Color::GOLD,
+                Vec2::new(GAUGE_WIDTH / 2.0, gauge_y(&level, level.win_height)),
+                0.3,
+            ))
+            .id(),
+    );
+    children.push(
+        commands
+            .spawn_bundle(rect_sprite(
+                Vec2::new(GAUGE_WIDTH * 2.0, 4.0),
+                Color::WHITE,
+                Vec2::new(GAUGE_WIDTH / 2.0, 0.0),
+                0.4,
+            ))
+            .insert(HudDepthMarker)
+            .id(),
+    );
+
+    let text_style = TextStyle {
+        font: asset_server.load("fonts/Roboto-Regular.ttf"),
+        font_size: 18.0,
+        color: Color::WHITE,
+    };
+    let text_alignment = TextAlignment {
+        vertical: VerticalAlign::Top,
+        horizontal: HorizontalAlign::Left,
+    };
+
+    let text_y = boost_y - BOOST_BAR_HEIGHT - 6.0;
+    children.push(
+        commands
+            .spawn_bundle(Text2dBundle {
+                text: Text::with_section("", text_style.clone(), text_alignment),
+                transform: Transform::from_translation(Vec3::new(HUD_COLUMN, text_y, 0.2)),
+                ..Default::default()
+            })
+            .insert(HudStageText)
+            .id(),
+    );
+
+    let notification_y = text_y - NOTIFICATION_SPACING * 1.5;
+    for k in 0..MAX_NOTIFICATIONS {
+        let y = notification_y - NOTIFICATION_SPACING * k as f32;
+        children.push(
+            commands
+                .spawn_bundle(Text2dBundle {
+                    text: Text::with_section("", text_style.clone(), text_alignment),
+                    transform: Transform::from_translation(Vec3::new(HUD_COLUMN, y, 0.2)),
+                    ..Default::default()
+                })
+                .insert(HudNotificationText(k))
+                .id(),
+        );
+    }
+
+    let root = commands
+        .spawn_bundle((
+            Transform::from_translation(Vec3::new(0.0, 0.0, HUD_Z)),
+            GlobalTransform::default(),
+        ))
+        .insert(Hud)
+        .push_children(&children)
+        .id();
+    commands.entity(cam_entity).push_children(&[root]);
+}
+
+// Keeps the HUD in the upper left corner of the window, laid out in pixels of the window
+pub fn place_hud(windows: Res<Windows>, mut query: Query<&mut Transform, With<Hud>>) {
+    let window = match windows.get_primary() {
+        Some(window) => window,
+        None => return,
+    };
+    let corner = Vec2::new(-window.width(), window.height()) / 2.0;
+
+    for mut transform in query.iter_mut() {
+        transform.translation.x = corner.x + HUD_MARGIN;
+        transform.translation.y = corner.y - HUD_MARGIN;
+    }
+}
+
+pub fn update_hud(
+    game: Res<Game>,
+    level: Res<Level>,
+    mut energy_query: Query<(&mut Sprite, &mut Transform), With<HudEnergyBar>>,
+    mut marker_query: Query<&mut Transform, (With<HudDepthMarker>, Without<HudEnergyBar>)>,
+    mut text_query: Query<&mut Text, With<HudStageText>>,
+) {
+    let agent = match game.agents.get(&1) {
+        Some(agent) => agent,
+        None => return,
+    };
+
+    let energy = agent.energy / FULL_ENERGY;
+    for (mut sprite, mut transform) in energy_query.iter_mut() {
+        set_bar(&mut sprite, &mut transform, energy);
+        sprite.color = if energy < LOW_ENERGY {
+            Color::RED
+        } else {
+            Color::YELLOW
+        };
+    }
+
+    for mut transform in marker_query.iter_mut() {
+        transform.translation.y = gauge_y(&level, agent.position.y);
+    }
+
+    let stage = match level.stage_at(agent.position.y) {
+        GameStage::Bottom => "Bottom",
+        GameStage::Mid => "Mid",
+        GameStage::Top => "Top",
+    };
+    for mut text in text_query.iter_mut() {
+        text.sections[0].value = format!("{} stage", stage);
+    }
+}
+
+// The same notification sent again stays on screen longer instead of showing up twice
+pub fn show_notifications(
+    mut events: EventReader<HudNotification>,
+    mut notifications: ResMut<Notifications>,
+    time: Res<Time>,
+    mut text_query: Query<(&HudNotificationText, &mut Text)>,
+) {
+    let now = time.seconds_since_startup() as f32;
+
+    for HudNotification(message) in events.iter() {
+        let expiry = now + NOTIFICATION_TIME;
+        match notifications
+            .active
+            .iter_mut()
+            .find(|(text, _)| *text == *message)
+        {
+            Some((_, active_expiry)) => *active_expiry = expiry,
+            None => notifications.active.push((message.clone(), expiry)),
+        }
+    }
+
+    notifications.active.retain(|(_, expiry)| *expiry > now);
+    let skipped = notifications.active.len().saturating_sub(MAX_NOTIFICATIONS);
+    notifications.active.drain(..skipped);
+
+    for (slot, mut text) in text_query.iter_mut() {
+        text.sections[0].value = notifications
+            .active
+            .get(slot.0)
+            .map_or(String::new(), |(message, _)| message.clone());
+    }
+}
@@ -1,4 +1,11 @@
-use bevy::{prelude::*, window::CursorMoved};
+use bevy::{input::InputSystem, prelude::*, window::CursorMoved};
+
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use strum::IntoEnumIterator;
+use strum_macros::EnumIter;
+
+use crate::hud::*;
 
 pub struct Cursor {
     pub position: Vec2,
@@ -56,3 +63,448 @@ pub fn record_mouse_events_system(
         cursor_res.pos_relative_to_click = Vec2::ZERO;
     }
 }
+
+// Bindings edited in the game are written there, and read back at the next start
+pub const BINDINGS_PATH: &str = "bindings.json";
+// Stick positions closer to the center than this are read as centered
+pub const STICK_DEAD_ZONE: f32 = 0.15;
+// Triggers pulled less than this are read as released
+pub const TRIGGER_DEAD_ZONE: f32 = 0.05;
+
+/// `value` past the dead zone, rescaled so that the response starts at 0 instead of
+/// jumping to the dead zone
+pub fn past_dead_zone(value: f32, dead_zone: f32) -> f32 {
+    let magnitude = (value.abs() - dead_zone).max(0.0) / (1.0 - dead_zone);
+    magnitude.min(1.0).copysign(value)
+}
+
+/// What the player can ask for, whatever the key or button it is bound to
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash, EnumIter)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    Thrust,
+    Reverse,
+    TurnLeft,
+    TurnRight,
+    Boost,
+    SteerToCursor,
+    UpgradeThrust,
+    UpgradeArmor,
+    Pause,
+    Confirm,
+    /// While paused, picks the next action to rebind
+    Rebind,
+    Quicksave,
+    Quickload,
+    SaveReplay,
+    PlayReplay,
+    ToggleCurrents,
+    /// Detaches the camera from the main character, to move it with the camera actions
+    FreeLook,
+    CameraLeft,
+    CameraRight,
+    CameraUp,
+    CameraDown,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Binding {
+    Key(KeyCode),
+    Mouse(MouseButton),
+    /// On any connected gamepad. The triggers are pressed past 0.75 of their course, the
+    /// default threshold of Bevy. Thrust reads them as axes instead.
+    Pad(GamepadButtonType),
+}
+
+impl Binding {
+    /// As shown to the player
+    pub fn name(&self) -> String {
+        match self {
+            Binding::Key(key) => format!("{:?}", key),
+            Binding::Mouse(button) => format!("{:?} click", button),
+            Binding::Pad(button) => format!("{:?}", button),
+        }
+    }
+}
+
+/// The keys and buttons bound to each action
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct InputMap {
+    pub bindings: HashMap<Action, Vec<Binding>>,
+}
+
+impl Default for InputMap {
+    fn default() -> Self {
+        serde_json::from_str(&include_str!("bindings.json")).unwrap()
+    }
+}
+
+impl InputMap {
+    /// The bindings of BINDINGS_PATH if there are some, on top of the default ones
+    pub fn load() -> Self {
+        let mut input_map = Self::default();
+
+        let contents = match std::fs::read_to_string(BINDINGS_PATH) {
+            Ok(contents) => contents,
+            Err(_) => return input_map,
+        };
+
+        match serde_json::from_str::<InputMap>(&contents) {
+            Ok(loaded) => input_map.bindings.extend(loaded.bindings),
+            Err(error) => eprintln!("could not parse {}: {}", BINDINGS_PATH, error),
+        }
+        input_map
+    }
+
+    pub fn save(&self) {
+        let result = serde_json::to_string_pretty(self)
+            .map_err(|error| error.to_string())
+            .and_then(|contents| {
+                std::fs::write(BINDINGS_PATH, contents).map_err(|error| error.to_string())
+            });
+
+        if let Err(error) = result {
+            eprintln!("could not save {}: {}", BINDINGS_PATH, error);
+        }
+    }
+
+    pub fn bindings(&self, action: Action) -> &[Binding] {
+        self.bindings
+            .get(&action)
+            .map_or(&[][..], |bindings| bindings.as_slice())
+    }
+
+    /// The bindings of `action` as shown to the player, e.g. "Escape or Start"
+    pub fn describe(&self, action: Action) -> String {
+        match self.bindings(action) {
+            [] => "nothing".to_string(),
+            bindings => bindings
+                .iter()
+                .map(Binding::name)
+                .collect::<Vec<_>>()
+                .join(" or "),
+        }
+    }
+
+    /// Binds `binding` to `action` only, so that two actions never fire together. The
+    /// actions that had it and would be left without bindings take the old bindings of
+    /// `action` instead, and are returned. Refused with the action that would be left
+    /// unbound when `action` had no bindings to give, nothing changes then.
+    pub fn rebind(&mut self, action: Action, binding: Binding) -> Result<Vec<Action>, Action> {
+        let previous = self
+            .bindings(action)
+            .iter()
+            .filter(|other| **other != binding)
+            .cloned()
+            .collect::<Vec<_>>();
+
+        // sorted, the map gives them in any order
+        let mut swapped = self
+            .bindings
+            .iter()
+            .filter(|(other, bindings)| **other != action && bindings.as_slice() == [binding])
+            .map(|(other, _)| *other)
+            .collect::<Vec<_>>();
+        swapped.sort_by_key(|other| *other as usize);
+
+        if let (Some(unbound), true) = (swapped.first(), previous.is_empty()) {
+            return Err(*unbound);
+        }
+
+        for bindings in self.bindings.values_mut() {
+            bindings.retain(|other| *other != binding);
+        }
+        for other in swapped.iter() {
+            self.bindings.insert(*other, previous.clone());
+        }
+        self.bindings.insert(action, vec![binding]);
+        Ok(swapped)
+    }
+}
+
+/// The actions of the frame, read by the systems instead of the keyboard and mouse
+#[derive(Default)]
+pub struct Actions {
+    pressed: HashSet<Action>,
+    just_pressed: HashSet<Action>,
+    /// Horizontal position of the left stick, from -1 (left) to 1 (right)
+    pub steer: f32,
+    /// Right trigger minus left trigger, from -1 (reverse) to 1 (thrust)
+    pub thrust: f32,
+}
+
+impl Actions {
+    pub fn pressed(&self, action: Action) -> bool {
+        self.pressed.contains(&action)
+    }
+
+    pub fn just_pressed(&self, action: Action) -> bool {
+        self.just_pressed.contains(&action)
+    }
+
+    /// Consumes the action for the rest of the frame, e.g. when it changes the app state
+    pub fn reset(&mut self, action: Action) {
+        self.pressed.remove(&action);
+        self.just_pressed.remove(&action);
+    }
+}
+
+pub fn update_actions(
+    input_map: Res<InputMap>,
+    keyboard: Res<Input<KeyCode>>,
+    mouse: Res<Input<MouseButton>>,
+    gamepads: Res<Gamepads>,
+    pad_buttons: Res<Input<GamepadButton>>,
+    pad_axes: Res<Axis<GamepadAxis>>,
+    mut actions: ResMut<Actions>,
+) {
+    actions.pressed.clear();
+    actions.just_pressed.clear();
+
+    let axis = |gamepad: &Gamepad, axis_type| {
+        pad_axes
+            .get(GamepadAxis(*gamepad, axis_type))
+            .unwrap_or(0.0)
+    };
+    let furthest = |a: f32, b: f32| if b.abs() > a.abs() { b } else { a };
+
+    // the stick pushed the furthest wins
+    actions.steer = gamepads
+        .iter()
+        .map(|gamepad| past_dead_zone(axis(gamepad, GamepadAxisType::LeftStickX), STICK_DEAD_ZONE))
+        .fold(0.0, furthest);
+
+    // and the triggers pulled the furthest
+    actions.thrust = gamepads
+        .iter()
+        .map(|gamepad| {
+            let thrust = axis(gamepad, GamepadAxisType::RightZ).max(0.0);
+            let reverse = axis(gamepad, GamepadAxisType::LeftZ).max(0.0);
+            past_dead_zone(thrust, TRIGGER_DEAD_ZONE) - past_dead_zone(reverse, TRIGGER_DEAD_ZONE)
+        })
+        .fold(0.0, furthest);
+
+    for (action, bindings) in input_map.bindings.iter() {
+        for binding in bindings {
+            let (pressed, just_pressed) = match binding {
+                Binding::Key(key) => (keyboard.pressed(*key), keyboard.just_pressed(*key)),
+                Binding::Mouse(button) => (mouse.pressed(*button), mouse.just_pressed(*button)),
+                Binding::Pad(button_type) => {
+                    gamepads.iter().fold((false, false), |acc, gamepad| {
+                        let button = GamepadButton(*gamepad, *button_type);
+                        (
+                            acc.0 || pad_buttons.pressed(button),
+                            acc.1 || pad_buttons.just_pressed(button),
+                        )
+                    })
+                }
+            };
+
+            if pressed {
+                actions.pressed.insert(*action);
+            }
+            if just_pressed {
+                actions.just_pressed.insert(*action);
+            }
+        }
+    }
+}
+
+/// The action waiting for a new binding, while paused
+#[derive(Default)]
+pub struct Rebinding {
+    pub action: Option<Action>,
+}
+
+// The rebind action goes through the actions one after the other, and the next key or
+// button pressed is bound to the one selected
+pub fn rebind_actions(
+    mut input_map: ResMut<InputMap>,
+    mut rebinding: ResMut<Rebinding>,
+    actions: Res<Actions>,
+    keyboard: Res<Input<KeyCode>>,
+    mouse: Res<Input<MouseButton>>,
+    pad_buttons: Res<Input<GamepadButton>>,
+    mut notifications: EventWriter<HudNotification>,
+) {
+    if actions.just_pressed(Action::Rebind) {
+        let next = match rebinding.action {
+            Some(action) => Action::iter().skip_while(|a| *a != action).nth(1),
+            None => Action::iter().next(),
+        };
+        rebinding.action = next;
+
+        let message = match next {
+            Some(action) => format!(
+                "Press a key for {:?}, now {}",
+                action,
+                input_map.describe(action)
+            ),
+            None => "Done rebinding".to_string(),
+        };
+        notifications.send(HudNotification(message));
+        return;
+    }
+
+    let action = match rebinding.action {
+        Some(action) => action,
+        None => return,
+    };
+
+    let binding = keyboard
+        .get_just_pressed()
+        .next()
+        .map(|key| Binding::Key(*key))
+        .or_else(|| {
+            mouse
+                .get_just_pressed()
+                .next()
+                .map(|button| Binding::Mouse(*button))
+        })
+        .or_else(|| {
+            pad_buttons
+                .get_just_pressed()
+                .next()
+                .map(|button| Binding::Pad(button.1))
+        });
+
+    // these keep their bindings, or there would be no way to leave the pause
+    let binding = binding.filter(|binding| {
+        !input_map.bindings(Action::Pause).contains(binding)
+            && !input_map.bindings(Action::Rebind).contains(binding)
+    });
+
+    if let Some(binding) = binding {
+        let message = match input_map.rebind(action, binding) {
+            Ok(swapped) => {
+                input_map.save();
+                rebinding.action = None;
+                let mut message = format!("{:?} bound to {}", action, binding.name());
+                for other in swapped {
+                    message += &format!(", {:?} to {}", other, input_map.describe(other));
+                }
+                message
+            }
+            Err(unbound) => format!(
+                "{} is all that {:?} has, pick another one",
+                binding.name(),
+                unbound
+            ),
+        };
+        notifications.send(HudNotification(message));
+    }
+}
+
+pub fn stop_rebinding(mut rebinding: ResMut<Rebinding>) {
+    rebinding.action = None;
+}
+
+/// Reads the bindings, and turns the keyboard, the mouse and the gamepads into actions
+/// before the other systems run
+pub struct ActionsPlugin;
+
+impl Plugin for ActionsPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(InputMap::load())
+            .init_resource::<Actions>()
+            .init_resource::<Rebinding>()
+            .add_system_to_stage(CoreStage::PreUpdate, update_actions.after(InputSystem));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn input_map(bindings: &[(Action, &[Binding])]) -> InputMap {
+        InputMap {
+            bindings: bindings
+                .iter()
+                .map(|(action, bindings)| (*action, bindings.to_vec()))
+                .collect(),
+        }
+    }
+
+    const W: Binding = Binding::Key(KeyCode::W);
+    const UP: Binding = Binding::Key(KeyCode::Up);
+    const S: Binding = Binding::Key(KeyCode::S);
+    const SOUTH: Binding = Binding::Pad(GamepadButtonType::South);
+
+    #[test]
+    fn taking_the_only_binding_of_an_action_swaps_them() {
+        let mut map = input_map(&[(Action::Thrust, &[W]), (Action::Reverse, &[S])]);
+
+        assert_eq!(map.rebind(Action::Thrust, S), Ok(vec![Action::Reverse]));
+        assert_eq!(map.bindings(Action::Thrust), &[S]);
+        assert_eq!(map.bindings(Action::Reverse), &[W]);
+    }
+
+    #[test]
+    fn a_shared_binding_is_taken_from_the_other_action() {
+        let mut map = input_map(&[(Action::Thrust, &[W]), (Action::Reverse, &[S, UP])]);
+
+        assert_eq!(map.rebind(Action::Thrust, UP), Ok(vec![]));
+        assert_eq!(map.bindings(Action::Thrust), &[UP]);
+        assert_eq!(map.bindings(Action::Reverse), &[S]);
+    }
+
+    #[test]
+    fn no_action_is_left_unbound() {
+        let mut map = input_map(&[
+            (Action::Thrust, &[W, UP]),
+            (Action::Boost, &[SOUTH]),
+            (Action::Confirm, &[SOUTH]),
+        ]);
+
+        assert_eq!(
+            map.rebind(Action::Thrust, SOUTH),
+            Ok(vec![Action::Boost, Action::Confirm])
+        );
+        for action in [Action::Thrust, Action::Boost, Action::Confirm] {
+            assert!(!map.bindings(action).is_empty(), "{:?} is unbound", action);
+        }
+        assert_eq!(map.bindings(Action::Boost), &[W, UP]);
+    }
+
+    #[test]
+    fn rebind_is_refused_when_there_is_nothing_to_swap() {
+        let mut map = input_map(&[(Action::Reverse, &[S])]);
+
+        assert_eq!(map.rebind(Action::Thrust, S), Err(Action::Reverse));
+        assert_eq!(map.bindings(Action::Thrust), &[] as &[Binding]);
+        assert_eq!(map.bindings(Action::Reverse), &[S]);
+    }
+
+    #[test]
+    fn rebind_to_its_own_binding() {
+        let mut map = input_map(&[(Action::Thrust, &[W, UP]), (Action::Reverse, &[S])]);
+
+        assert_eq!(map.rebind(Action::Thrust, UP), Ok(vec![]));
+        assert_eq!(map.bindings(Action::Thrust), &[UP]);
+        assert_eq!(map.bindings(Action::Reverse), &[S]);
+
+        // its only binding is never given away to itself
+        assert_eq!(map.rebind(Action::Thrust, UP), Ok(vec![]));
+        assert_eq!(map.bindings(Action::Thrust), &[UP]);
+    }
+
+    #[test]
+    fn stick_is_rescaled_past_the_dead_zone() {
+        assert_eq!(past_dead_zone(0.1, 0.15), 0.0);
+        assert_eq!(past_dead_zone(-0.15, 0.15), 0.0);
+        assert!(past_dead_zone(0.16, 0.15) < 0.02);
+        assert!((past_dead_zone(0.575, 0.15) - 0.5).abs() < 1e-6);
+        assert!((past_dead_zone(-0.575, 0.15) + 0.5).abs() < 1e-6);
+        assert_eq!(past_dead_zone(1.0, 0.15), 1.0);
+        assert_eq!(past_dead_zone(-1.2, 0.15), -1.0);
+    }
+
+    #[test]
+    fn describe_joins_the_bindings() {
+        let map = input_map(&[(Action::Thrust, &[W, SOUTH])]);
+
+        assert_eq!(map.describe(Action::Thrust), "W or South");
+        assert_eq!(map.describe(Action::Reverse), "nothing");
+    }
+}
@@ -0,0 +1,595 @@
+use bevy::prelude::*;
+
+use rand::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use crate::checkpoints::*;
+use crate::currents::*;
+use crate::guardians::*;
+use crate::levelgen::*;
+use crate::ore::*;
+use crate::tribe::*;
+use crate::util::*;
+
+// Room that the main character needs to squeeze through a passage
+pub const PASSAGE_CLEARANCE: f32 = 50.0;
+
+// Resolution of the grid used to check that the surface can be reached
+pub const REACHABILITY_CELL_SIZE: f32 = 50.0;
+
+// Fraction of the foods of a stage that grow in its food zones, when it has any
+pub const FOOD_ZONE_SHARE: f32 = 0.6;
+
+/// Upper limit of each stage, as a fraction of the level height
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct StageLimits {
+    pub bottom: f32,
+    pub mid: f32,
+    pub top: f32,
+}
+
+/// What lives in one stage of the level
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SpawnTable {
+    /// Number of NPCs that the population manager keeps alive in the stage
+    pub agents: usize,
+    pub foods: usize,
+    /// Mass range of the NPCs born in the stage
+    pub mass: [f32; 2],
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SpawnTables {
+    pub bottom: SpawnTable,
+    pub mid: SpawnTable,
+    pub top: SpawnTable,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "shape", rename_all = "snake_case")]
+pub enum Obstacle {
+    Circle { center: Vec2, radius: f32 },
+    Rect { center: Vec2, size: Vec2 },
+    Polygon { points: Vec<Vec2> },
+}
+
+impl Obstacle {
+    /// Signed distance from `point` to the edge of the obstacle, negative inside
+    pub fn distance(&self, point: Vec2) -> f32 {
+        match self {
+            Obstacle::Circle { center, radius } => point.distance(*center) - radius,
+            Obstacle::Rect { center, size } => {
+                let d = (point - *center).abs() - *size / 2.0;
+                d.max(Vec2::ZERO).length() + d.x.max(d.y).min(0.0)
+            }
+            Obstacle::Polygon { points } => {
+                let mut distance = f32::MAX;
+                let mut inside = false;
+
+                for k in 0..points.len() {
+                    let a = points[k];
+                    let b = points[(k + 1) % points.len()];
+
+                    let ab = b - a;
+                    let t = ((point - a).dot(ab) / ab.length_squared().max(f32::EPSILON))
+                        .clamp(0.0, 1.0);
+                    distance = distance.min(point.distance(a + ab * t));
+
+                    // even-odd rule
+                    if (a.y > point.y) != (b.y > point.y)
+                        && point.x < a.x + (point.y - a.y) / (b.y - a.y) * (b.x - a.x)
+                    {
+                        inside = !inside;
+                    }
+                }
+
+                if inside {
+                    -distance
+                } else {
+                    distance
+                }
+            }
+        }
+    }
+}
+
+/// A place where food is abundant
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct FoodZone {
+    pub center: Vec2,
+    pub radius: f32,
+}
+
+/// Everything that defines a level. Loaded once at startup, from the file given as the
+/// first command line argument, or from the embedded level.json.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Level {
+    pub name: String,
+    pub width: f32,
+    pub height: f32,
+    pub stage_limits: StageLimits,
+    /// Horizontal range of the bottom stage, as a fraction of the level width
+    pub bottom_limits_x: [f32; 2],
+    pub start_position: Vec2,
+    /// The main character wins once it rises above this height, the surface of the ocean
+    pub win_height: f32,
+    pub spawns: SpawnTables,
+    pub guardians: GuardianLayout,
+    #[serde(default)]
+    pub obstacles: Vec<Obstacle>,
+    #[serde(default)]
+    pub food_zones: Vec<FoodZone>,
+    #[serde(default)]
+    pub currents: CurrentsDescription,
+    #[serde(default)]
+    pub ore_deposits: Vec<OreDeposit>,
+    #[serde(default)]
+    pub territories: Vec<Territory>,
+    /// From the deepest to the shallowest
+    #[serde(default)]
+    pub checkpoints: Vec<Checkpoint>,
+}
+
+#[derive(Debug)]
+pub enum LevelError {
+    Io {
+        path: PathBuf,
+        error: std::io::Error,
+    },
+    Parse(serde_json::Error),
+    Invalid(Vec<String>),
+}
+
+impl fmt::Display for LevelError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LevelError::Io { path, error } => {
+                write!(f, "could not read level file {}: {}", path.display(), error)
+            }
+            LevelError::Parse(error) => write!(f, "could not parse level: {}", error),
+            LevelError::Invalid(problems) => {
+                writeln!(f, "invalid level:")?;
+                for problem in problems {
+                    writeln!(f, "  - {}", problem)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl std::error::Error for LevelError {}
+
+impl From<serde_json::Error> for LevelError {
+    fn from(error: serde_json::Error) -> Self {
+        LevelError::Parse(error)
+    }
+}
+
+impl Level {
+    pub fn from_json(contents: &str) -> Result<Self, LevelError> {
+        let level: Level = serde_json::from_str(contents)?;
+        level.validate()?;
+        Ok(level)
+    }
+
+    pub fn load(path: &Path) -> Result<Self, LevelError> {
+        let contents = std::fs::read_to_string(path).map_err(|error| LevelError::Io {
+            path: path.to_path_buf(),
+            error,
+        })?;
+        Self::from_json(&contents)
+    }
+
+    pub fn load_default() -> Result<Self, LevelError> {
+        Self::from_json(&include_str!("level.json"))
+    }
+
+    /// The level file can be given as the first command line argument.
+    /// `--seed <seed>` generates a level instead, and `--random` does so with a random seed.
+    pub fn load_from_args() -> Result<Self, LevelError> {
+        let args = std::env::args().skip(1).collect::<Vec<_>>();
+
+        match args.first().map(|arg| arg.as_str()) {
+            Some("--seed") => {
+                let seed = args
+                    .get(1)
+                    .and_then(|seed| seed.parse::<u64>().ok())
+                    .ok_or_else(|| {
+                        LevelError::Invalid(vec!["--seed expects a positive integer".to_string()])
+                    })?;
+                LevelGenerator::new(seed).generate()
+            }
+            Some("--random") => LevelGenerator::new(rand::thread_rng().gen()).generate(),
+            Some(path) => Self::load(Path::new(path)),
+            None => Self::load_default(),
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), LevelError> {
+        let mut problems = Vec::new();
+
+        // the reachability grid is sized after these, it is only built when they are valid
+        let size_is_valid = self.width.is_finite()
+            && self.height.is_finite()
+            && self.width > 0.0
+            && self.height > 0.0;
+        let limits = &self.stage_limits;
+        let limits_are_valid = 0.0 < limits.bottom
+            && limits.bottom < limits.mid
+            && limits.mid < limits.top
+            && limits.top <= 1.0;
+
+        if !size_is_valid {
+            problems.push(format!(
+                "the level size must be positive, got {} x {}",
+                self.width, self.height
+            ));
+        }
+
+        if !limits_are_valid {
+            problems.push(format!(
+                "the stage limits must be increasing fractions of the height, got {:?}",
+                limits
+            ));
+        }
+
+        let [x_min, x_max] = self.bottom_limits_x;
+        if !(0.0 <= x_min && x_min < x_max && x_max <= 1.0) {
+            problems.push(format!(
+                "bottom_limits_x must be an increasing pair of fractions of the width, got {:?}",
+                self.bottom_limits_x
+            ));
+        }
+
+        if !self.contains(self.start_position) {
+            problems.push(format!(
+                "the start position {} is outside of the level",
+                self.start_position
+            ));
+        }
+
+        if !(self.start_position.y < self.win_height && self.win_height <= self.height) {
+            problems.push(format!(
+                "the win height {} must be above the start position and inside the level",
+                self.win_height
+            ));
+        }
+
+        for (stage, table) in [
+            ("bottom", &self.spawns.bottom),
+            ("mid", &self.spawns.mid),
+            ("top", &self.spawns.top),
+        ] {
+            let [mass_min, mass_max] = table.mass;
+            if !(0.0 < mass_min && mass_min < mass_max) {
+                problems.push(format!(
+                    "the {} spawn table needs an increasing positive mass range, got {:?}",
+                    stage, table.mass
+                ));
+            }
+        }
+
+        for squad in self.guardians.squads.iter() {
+            if squad.waypoints.is_empty() {
+                problems.push(format!("guardian squad {} has no waypoints", squad.name));
+            }
+            for waypoint in squad.waypoints.iter() {
+                if !self.contains(*waypoint) {
+                    problems.push(format!(
+                        "guardian squad {} has a waypoint outside of the level: {}",
+                        squad.name, waypoint
+                    ));
+                }
+                if self.is_blocked(*waypoint, 0.0) {
+                    problems.push(format!(
+                        "guardian squad {} has a waypoint inside a rock: {}",
+                        squad.name, waypoint
+                    ));
+                }
+            }
+            if squad.detection_radius <= 0.0 || squad.leash_radius <= 0.0 {
+                problems.push(format!(
+                    "guardian squad {} needs positive detection and leash radii",
+                    squad.name
+                ));
+            }
+        }
+
+        for (k, obstacle) in self.obstacles.iter().enumerate() {
+            let valid = match obstacle {
+                Obstacle::Circle { radius, .. } => *radius > 0.0,
+                Obstacle::Rect { size, .. } => size.x > 0.0 && size.y > 0.0,
+                Obstacle::Polygon { points } => points.len() >= 3,
+            };
+            if !valid {
+                problems.push(format!("obstacle {} is degenerate: {:?}", k, obstacle));
+            }
+        }
+
+        if self.currents.eddy_size <= 0.0 {
+            problems.push(format!(
+                "the eddies need a positive size, got {}",
+                self.currents.eddy_size
+            ));
+        }
+        for jet in self.currents.jets.iter() {
+            if jet.width <= 0.0 {
+                problems.push(format!("jet {:?} needs a positive width", jet));
+            }
+        }
+
+        for zone in self.food_zones.iter() {
+            if zone.radius <= 0.0 || !self.contains(zone.center) {
+                problems.push(format!("food zone {:?} is outside of the level", zone));
+            }
+        }
+
+        for deposit in self.ore_deposits.iter() {
+            if deposit.amount == 0 {
+                problems.push(format!("ore deposit {:?} is empty", deposit));
+            }
+            if !self.contains(deposit.position) || self.is_blocked(deposit.position, 0.0) {
+                problems.push(format!(
+                    "ore deposit {:?} must be in the open water of the level",
+                    deposit
+                ));
+            }
+        }
+
+        for territory in self.territories.iter() {
+            if territory.radius <= 0.0 || !self.contains(territory.center) {
+                problems.push(format!("territory {:?} is outside of the level", territory));
+            }
+        }
+
+        for pair in self.checkpoints.windows(2) {
+            if pair[0].position.y >= pair[1].position.y {
+                problems.push(format!(
+                    "the checkpoints must go up, {} comes before {}",
+                    pair[0].position, pair[1].position
+                ));
+            }
+        }
+        for checkpoint in self.checkpoints.iter() {
+            if !self.contains(checkpoint.position)
+                || self.is_blocked(checkpoint.position, PASSAGE_CLEARANCE)
+            {
+                problems.push(format!(
+                    "the checkpoint {} must be in the open water of the level",
+                    checkpoint.position
+                ));
+            }
+        }
+
+        if self.is_blocked(self.start_position, PASSAGE_CLEARANCE) {
+            problems.push(format!(
+                "the start position {} is inside an obstacle",
+                self.start_position
+            ));
+        } else if size_is_valid && limits_are_valid && !self.surface_is_reachable(PASSAGE_CLEARANCE)
+        {
+            problems.push("the obstacles block every path to the surface".to_string());
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(LevelError::Invalid(problems))
+        }
+    }
+
+    pub fn contains(&self, position: Vec2) -> bool {
+        (0.0..=self.width).contains(&position.x) && (0.0..=self.height).contains(&position.y)
+    }
+
+    /// Whether a creature with the given clearance would overlap an obstacle at `position`
+    pub fn is_blocked(&self, position: Vec2, clearance: f32) -> bool {
+        self.obstacles
+            .iter()
+            .any(|obstacle| obstacle.distance(position) < clearance)
+    }
+
+    /// Flood fills a coarse grid from the start position, until the surface is found
+    pub fn surface_is_reachable(&self, clearance: f32) -> bool {
+        let columns = (self.width / REACHABILITY_CELL_SIZE).ceil() as usize;
+        let rows = (self.height / REACHABILITY_CELL_SIZE).ceil() as usize;
+        let cell_center = |column: usize, row: usize| {
+            Vec2::new(column as f32 + 0.5, row as f32 + 0.5) * REACHABILITY_CELL_SIZE
+        };
+
+        let start = (
+            ((self.start_position.x / REACHABILITY_CELL_SIZE) as usize).min(columns - 1),
+            ((self.start_position.y / REACHABILITY_CELL_SIZE) as usize).min(rows - 1),
+        );
+
+        let mut visited = vec![false; columns * rows];
+        let mut queue = VecDeque::new();
+        visited[start.1 * columns + start.0] = true;
+        queue.push_back(start);
+
+        while let Some((column, row)) = queue.pop_front() {
+            if cell_center(column, row).y > self.win_height {
+                return true;
+            }
+
+            let neighbours = [
+                (column.wrapping_sub(1), row),
+                (column + 1, row),
+                (column, row.wrapping_sub(1)),
+                (column, row + 1),
+            ];
+
+            for (c, r) in neighbours {
+                if c >= columns || r >= rows || visited[r * columns + c] {
+                    continue;
+                }
+                visited[r * columns + c] = true;
+
+                if !self.is_blocked(cell_center(c, r), clearance) {
+                    queue.push_back((c, r));
+                }
+            }
+        }
+
+        false
+    }
+
+    pub fn spawn_table(&self, stage: &GameStage) -> &SpawnTable {
+        match stage {
+            GameStage::Bottom => &self.spawns.bottom,
+            GameStage::Mid => &self.spawns.mid,
+            GameStage::Top => &self.spawns.top,
+        }
+    }
+
+    /// Stage that a height of the level is in
+    pub fn stage_at(&self, height: f32) -> GameStage {
+        if height < self.stage_limits.bottom * self.height {
+            GameStage::Bottom
+        } else if height < self.stage_limits.mid * self.height {
+            GameStage::Mid
+        } else {
+            GameStage::Top
+        }
+    }
+
+    pub fn total_foods(&self) -> usize {
+        self.spawns.bottom.foods + self.spawns.mid.foods + self.spawns.top.foods
+    }
+
+    /// Lower left and upper right corners of the area where things of a stage are born
+    pub fn stage_area(&self, stage: &GameStage) -> (Vec2, Vec2) {
+        let limits = &self.stage_limits;
+        match stage {
+            GameStage::Bottom => (
+                Vec2::new(self.bottom_limits_x[0] * self.width, 0.0),
+                Vec2::new(
+                    self.bottom_limits_x[1] * self.width,
+                    limits.bottom * self.height,
+                ),
+            ),
+            GameStage::Mid => (
+                Vec2::new(0.0, limits.bottom * self.height),
+                Vec2::new(self.width, limits.mid * self.height),
+            ),
+            GameStage::Top => (
+                Vec2::new(0.0, limits.mid * self.height),
+                Vec2::new(self.width, limits.top * self.height),
+            ),
+        }
+    }
+
+    /// A random position of the stage, outside of the obstacles if possible
+    pub fn random_position(&self, stage: &GameStage, rng: &mut StdRng) -> Vec2 {
+        let (min, max) = self.stage_area(stage);
+
+        let mut position = Vec2::ZERO;
+        for _ in 0..20 {
+            position = Vec2::new(
+                min.x + rng.gen::<f32>() * (max.x - min.x),
+                min.y + rng.gen::<f32>() * (max.y - min.y),
+            );
+            if !self.is_blocked(position, 0.0) {
+                break;
+            }
+        }
+        position
+    }
+
+    /// Most of the food grows in the food zones of the stage, the rest anywhere in it
+    pub fn random_food_position(&self, stage: &GameStage, rng: &mut StdRng) -> Vec2 {
+        let (min, max) = self.stage_area(stage);
+
+        let zones = self
+            .food_zones
+            .iter()
+            .filter(|zone| zone.center.cmpge(min).all() && zone.center.cmplt(max).all())
+            .collect::<Vec<_>>();
+
+        if let Some(zone) = zones.choose(rng) {
+            if rng.gen::<f32>() < FOOD_ZONE_SHARE {
+                for _ in 0..20 {
+                    let angle = rng.gen_range(0.0..std::f32::consts::TAU);
+                    // uniform over the disk
+                    let distance = zone.radius * rng.gen::<f32>().sqrt();
+                    let position = zone.center + Vec2::new(angle.cos(), angle.sin()) * distance;
+
+                    if self.contains(position) && !self.is_blocked(position, 0.0) {
+                        return position;
+                    }
+                }
+            }
+        }
+
+        self.random_position(stage, rng)
+    }
+
+    pub fn random_mass(&self, stage: &GameStage, rng: &mut StdRng) -> f32 {
+        let [mass_min, mass_max] = self.spawn_table(stage).mass;
+        rng.gen_range(mass_min..mass_max)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn problems(level: &Level) -> Vec<String> {
+        match level.validate() {
+            Err(LevelError::Invalid(problems)) => problems,
+            other => panic!("expected an invalid level, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn embedded_level_is_valid() {
+        let level = Level::load_default().unwrap();
+        assert!(level.validate().is_ok());
+        assert!(level.surface_is_reachable(PASSAGE_CLEARANCE));
+    }
+
+    #[test]
+    fn zero_size_level_is_refused() {
+        let mut level = Level::load_default().unwrap();
+        level.width = 0.0;
+        assert!(problems(&level)
+            .iter()
+            .any(|problem| problem.contains("size must be positive")));
+
+        level.width = 1000.0;
+        level.height = -5.0;
+        assert!(problems(&level)
+            .iter()
+            .any(|problem| problem.contains("size must be positive")));
+    }
+
+    #[test]
+    fn blocked_start_is_refused() {
+        let mut level = Level::load_default().unwrap();
+        level.obstacles.push(Obstacle::Circle {
+            center: level.start_position,
+            radius: 50.0,
+        });
+        assert!(problems(&level)
+            .iter()
+            .any(|problem| problem.contains("is inside an obstacle")));
+    }
+
+    #[test]
+    fn unreachable_surface_is_refused() {
+        let mut level = Level::load_default().unwrap();
+        // a wall across the whole level, above the start
+        level.obstacles.push(Obstacle::Rect {
+            center: Vec2::new(level.width / 2.0, level.start_position.y + 400.0),
+            size: Vec2::new(level.width * 2.0, 100.0),
+        });
+        assert!(problems(&level)
+            .iter()
+            .any(|problem| problem.contains("block every path to the surface")));
+    }
+}
@@ -0,0 +1,518 @@
+use bevy::prelude::*;
+
+use rand::prelude::*;
+use strum::IntoEnumIterator;
+
+use crate::checkpoints::*;
+use crate::currents::*;
+use crate::guardians::*;
+use crate::level::*;
+use crate::ore::*;
+use crate::tribe::*;
+
+// Vertical distance between two points of the main passage
+pub const PASSAGE_STEP: f32 = 350.0;
+// Half width of the passage at the choke points, where the guardians stand
+pub const CHOKE_HALF_WIDTH: f32 = 160.0;
+
+// Half width of the open water kept around the patrols of the guardians
+pub const PATROL_HALF_WIDTH: f32 = 100.0;
+
+// Rocks never come closer than this to the open space
+pub const ROCK_MARGIN: f32 = 20.0;
+
+// Ore deposits are placed against the rocks, no farther than this from them
+pub const ORE_WALL_DISTANCE: f32 = 200.0;
+
+// A generated level is checked, and generated again with the next seed if the surface
+// cannot be reached. Never happens in practice as the passage is kept clear.
+pub const MAX_GENERATION_ATTEMPTS: u64 = 10;
+
+/// A piece of open water, kept clear of rocks
+#[derive(Clone, Debug)]
+enum OpenSpace {
+    Passage { a: Vec2, b: Vec2, half_width: f32 },
+    Chamber { center: Vec2, radius: f32 },
+}
+
+impl OpenSpace {
+    /// Signed distance from `point` to the edge of the open space, negative inside
+    fn distance(&self, point: Vec2) -> f32 {
+        match self {
+            OpenSpace::Passage { a, b, half_width } => {
+                let ab = *b - *a;
+                let t =
+                    ((point - *a).dot(ab) / ab.length_squared().max(f32::EPSILON)).clamp(0.0, 1.0);
+                point.distance(*a + ab * t) - half_width
+            }
+            OpenSpace::Chamber { center, radius } => point.distance(*center) - radius,
+        }
+    }
+}
+
+/// Builds a cave level from a seed: a winding passage goes from the ocean floor to the
+/// surface, widening into chambers full of food and narrowing at choke points, where
+/// guardian squads block the way. Rocks fill the rest.
+#[derive(Clone, Debug)]
+pub struct LevelGenerator {
+    pub seed: u64,
+    pub width: f32,
+    pub height: f32,
+    pub stage_limits: StageLimits,
+    pub bottom_limits_x: [f32; 2],
+    pub win_height: f32,
+    /// Half width of the passage in the bottom, mid and top stages
+    pub passage_half_widths: [f32; 3],
+    /// Maximum horizontal drift of the passage between two of its points
+    pub passage_drift: f32,
+    /// Heights of the choke points, as fractions of the level height
+    pub chokes: Vec<f32>,
+    pub chambers_per_stage: [usize; 3],
+    pub chamber_radius: [f32; 2],
+    pub rock_attempts: usize,
+    pub rock_radius: [f32; 2],
+    /// Number of upwellings and jets in the top stage
+    pub upwellings: usize,
+    pub jets: usize,
+    pub ore_deposits_per_stage: [usize; 3],
+    pub ore_amount: [u32; 2],
+    pub spawns: SpawnTables,
+}
+
+impl LevelGenerator {
+    /// Same dimensions and spawn tables as the hand-written level
+    pub fn new(seed: u64) -> Self {
+        Self {
+            seed,
+            width: 5000.0,
+            height: 7000.0,
+            stage_limits: StageLimits {
+                bottom: 0.05,
+                mid: 0.2,
+                top: 0.98,
+            },
+            bottom_limits_x: [0.3, 0.7],
+            win_height: 6000.0,
+            passage_half_widths: [400.0, 300.0, 250.0],
+            passage_drift: 300.0,
+            // the stage boundaries, and twice on the way to the surface
+            chokes: vec![0.05, 0.2, 0.5, 0.75],
+            chambers_per_stage: [0, 2, 4],
+            chamber_radius: [350.0, 700.0],
+            rock_attempts: 600,
+            rock_radius: [120.0, 450.0],
+            upwellings: 3,
+            jets: 2,
+            ore_deposits_per_stage: [3, 6, 6],
+            ore_amount: [10, 30],
+            spawns: SpawnTables {
+                bottom: SpawnTable {
+                    agents: 50,
+                    foods: 250,
+                    mass: [0.02, 0.05],
+                },
+                mid: SpawnTable {
+                    agents: 50,
+                    foods: 750,
+                    mass: [0.05, 0.11],
+                },
+                top: SpawnTable {
+                    agents: 0,
+                    foods: 4000,
+                    mass: [0.11, 0.2],
+                },
+            },
+        }
+    }
+
+    pub fn generate(&self) -> Result<Level, LevelError> {
+        let mut problems = Vec::new();
+
+        for attempt in 0..MAX_GENERATION_ATTEMPTS {
+            let seed = self.seed.wrapping_add(attempt);
+            let level = self.generate_with_seed(seed);
+
+            // validation includes the reachability of the surface
+            match level.validate() {
+                Ok(()) => return Ok(level),
+                Err(LevelError::Invalid(mut level_problems)) => {
+                    problems.append(&mut level_problems)
+                }
+                Err(error) => return Err(error),
+            }
+        }
+
+        Err(LevelError::Invalid(problems))
+    }
+
+    fn stage_index(&self, y: f32) -> usize {
+        if y < self.stage_limits.bottom * self.height {
+            0
+        } else if y < self.stage_limits.mid * self.height {
+            1
+        } else {
+            2
+        }
+    }
+
+    fn generate_with_seed(&self, seed: u64) -> Level {
+        let mut rng = StdRng::seed_from_u64(seed);
+
+        let start_position = Vec2::new(self.width / 2.0, 20.0);
+        let choke_heights = self
+            .chokes
+            .iter()
+            .map(|choke| choke * self.height)
+            .collect::<Vec<_>>();
+
+        ///// main passage, from the floor up to above the surface
+        let margin = self.passage_half_widths.iter().cloned().fold(0.0, f32::max);
+        let mut points = vec![(start_position, self.passage_half_widths[0])];
+        let mut position = start_position;
+
+        while position.y < self.height {
+            let mut y = (position.y + PASSAGE_STEP).min(self.height);
+
+            // the passage always narrows exactly at a choke point
+            if let Some(choke_height) = choke_heights
+                .iter()
+                .find(|choke_height| position.y < **choke_height && y >= **choke_height)
+            {
+                y = *choke_height;
+            }
+
+            let mut x = position.x + rng.gen_range(-1.0..1.0) * self.passage_drift;
+            x = if y < self.stage_limits.bottom * self.height {
+                x.clamp(
+                    self.bottom_limits_x[0] * self.width,
+                    self.bottom_limits_x[1] * self.width,
+                )
+            } else {
+                x.clamp(margin, self.width - margin)
+            };
+
+            position = Vec2::new(x, y);
+
+            let half_width = if choke_heights.contains(&y) {
+                CHOKE_HALF_WIDTH
+            } else {
+                self.passage_half_widths[self.stage_index(y)] * rng.gen_range(0.8..1.2)
+            };
+            points.push((position, half_width));
+        }
+
+        let chokes = points
+            .iter()
+            .filter(|(point, _)| choke_heights.contains(&point.y))
+            .map(|(point, _)| *point)
+            .collect::<Vec<_>>();
+
+        let mut open_spaces = points
+            .windows(2)
+            .map(|pair| OpenSpace::Passage {
+                a: pair[0].0,
+                b: pair[1].0,
+                // the narrower end sets the width of the whole segment
+                half_width: pair[0].1.min(pair[1].1),
+            })
+            .collect::<Vec<_>>();
+
+        ///// chambers, next to the passage
+        let mut food_zones = Vec::new();
+        for stage in 0..3 {
+            let mut candidates = points
+                .iter()
+                .filter(|(point, _)| {
+                    self.stage_index(point.y) == stage
+                        && !choke_heights.contains(&point.y)
+                        && point.y < self.win_height
+                })
+                .map(|(point, _)| *point)
+                .collect::<Vec<_>>();
+            candidates.shuffle(&mut rng);
+
+            let mut chambers = 0;
+            for point in candidates {
+                if chambers >= self.chambers_per_stage[stage] {
+                    break;
+                }
+
+                let mut radius = rng.gen_range(self.chamber_radius[0]..self.chamber_radius[1]);
+                let offset = Vec2::new(rng.gen_range(-1.0..1.0) * radius, 0.0);
+
+                let mut center = point + offset;
+                center.x = center.x.clamp(radius, self.width - radius);
+
+                // keep the chokes narrow, and the chambers apart
+                let room = chokes
+                    .iter()
+                    .map(|choke| choke.distance(center) - CHOKE_HALF_WIDTH)
+                    .chain(food_zones.iter().map(|zone: &FoodZone| {
+                        zone.center.distance(center) - zone.radius - PASSAGE_STEP
+                    }))
+                    .fold(f32::MAX, f32::min);
+                radius = radius.min(room);
+
+                if radius < self.chamber_radius[0] / 2.0 {
+                    continue;
+                }
+
+                open_spaces.push(OpenSpace::Chamber { center, radius });
+                food_zones.push(FoodZone { center, radius });
+                chambers += 1;
+            }
+        }
+
+        ///// checkpoints, past each choke and its guardians
+        let checkpoints = points
+            .iter()
+            .enumerate()
+            .filter(|(_, (point, _))| choke_heights.contains(&point.y))
+            .filter_map(|(k, _)| points.get(k + 2))
+            .filter(|(point, _)| point.y < self.win_height)
+            .map(|(point, _)| Checkpoint { position: *point })
+            .collect::<Vec<_>>();
+
+        ///// the chambers are the homes of the tribes
+        let territories = food_zones
+            .iter()
+            .zip(Tribe::iter().cycle())
+            .map(|(zone, tribe)| Territory {
+                tribe,
+                center: zone.center,
+                radius: zone.radius,
+            })
+            .collect::<Vec<_>>();
+
+        ///// currents: upwellings along the passage help the ascent, jets cross it
+        let top_points = points
+            .iter()
+            .filter(|(point, _)| {
+                self.stage_index(point.y) == 2
+                    && !choke_heights.contains(&point.y)
+                    && point.y < self.win_height
+            })
+            .cloned()
+            .collect::<Vec<_>>();
+
+        let upwellings = top_points
+            .choose_multiple(&mut rng, self.upwellings)
+            .map(|(point, half_width)| Upwelling {
+                center: *point,
+                size: Vec2::new(*half_width * 1.5, PASSAGE_STEP * 2.0),
+                strength: rng.gen_range(2.0..4.0),
+            })
+            .collect::<Vec<_>>();
+
+        let jets = top_points
+            .choose_multiple(&mut rng, self.jets)
+            .map(|(point, half_width)| {
+                let side = if rng.gen::<bool>() { 1.0 } else { -1.0 };
+                let reach = Vec2::new(side * (*half_width + 600.0), 0.0);
+                Jet {
+                    from: *point - reach,
+                    to: *point + reach,
+                    width: 120.0,
+                    strength: rng.gen_range(3.0..5.0),
+                }
+            })
+            .collect::<Vec<_>>();
+
+        ///// guardians, across the chokes
+        let squads = chokes
+            .iter()
+            .enumerate()
+            .map(|(k, choke)| {
+                let patrol = Vec2::new(CHOKE_HALF_WIDTH * 2.0, 0.0);
+                let lookout = Vec2::new(0.0, CHOKE_HALF_WIDTH * 2.0);
+                GuardianSquadDescription {
+                    name: format!("choke {}", k + 1),
+                    count: 3 + k as u32,
+                    waypoints: vec![
+                        *choke + lookout - patrol,
+                        *choke + lookout + patrol,
+                        *choke + lookout,
+                    ],
+                    spacing: 100.0,
+                    detection_radius: 700.0 + 300.0 * k as f32,
+                    suspicion_time: 1.0 - 0.5 * k as f32 / chokes.len() as f32,
+                    max_chase_time: 12.0 + 8.0 * k as f32,
+                    lose_sight_time: 3.0 + 2.0 * k as f32,
+                    leash_radius: 1800.0 + 700.0 * k as f32,
+                }
+            })
+            .filter(|squad| {
+                squad
+                    .waypoints
+                    .iter()
+                    .all(|waypoint| waypoint.y < self.height)
+            })
+            .collect::<Vec<_>>();
+
+        // the rocks keep clear of the patrols, the way they do of the passage
+        for squad in squads.iter() {
+            let waypoints = &squad.waypoints;
+            for (k, waypoint) in waypoints.iter().enumerate() {
+                open_spaces.push(OpenSpace::Passage {
+                    a: *waypoint,
+                    b: waypoints[(k + 1) % waypoints.len()],
+                    half_width: PATROL_HALF_WIDTH,
+                });
+            }
+        }
+
+        ///// cave walls
+        let mut obstacles = Vec::new();
+        for _ in 0..self.rock_attempts {
+            let center = Vec2::new(
+                rng.gen::<f32>() * self.width,
+                rng.gen::<f32>() * self.height,
+            );
+            let radius = rng.gen_range(self.rock_radius[0]..self.rock_radius[1]);
+
+            // the whole rock fits in a circle of that radius, so the open space stays clear
+            let clear = open_spaces
+                .iter()
+                .all(|space| space.distance(center) > radius + ROCK_MARGIN);
+            let overlapping = obstacles
+                .iter()
+                .any(|obstacle: &Obstacle| obstacle.distance(center) < -radius * 0.5);
+
+            if !clear || overlapping {
+                continue;
+            }
+
+            obstacles.push(random_rock(&mut rng, center, radius));
+        }
+
+        ///// ore deposits, along the passage against the cave walls
+        let mut ore_deposits = Vec::new();
+        for stage in 0..3 {
+            let stage_points = points
+                .iter()
+                .filter(|(point, _)| {
+                    self.stage_index(point.y) == stage && point.y < self.win_height
+                })
+                .collect::<Vec<_>>();
+
+            let mut placed = 0;
+            for _ in 0..self.ore_deposits_per_stage[stage] * 20 {
+                if placed >= self.ore_deposits_per_stage[stage] {
+                    break;
+                }
+                let (point, half_width) = match stage_points.choose(&mut rng) {
+                    Some(point) => **point,
+                    None => break,
+                };
+
+                let side = if rng.gen::<bool>() { 1.0 } else { -1.0 };
+                let position = point
+                    + Vec2::new(
+                        side * half_width * rng.gen_range(0.5..1.0),
+                        PASSAGE_STEP * rng.gen_range(-0.5..0.5),
+                    );
+
+                let wall = obstacles
+                    .iter()
+                    .map(|obstacle| obstacle.distance(position))
+                    .fold(f32::MAX, f32::min);
+                if !(ORE_DEPOSIT_RADIUS..ORE_WALL_DISTANCE).contains(&wall)
+                    || !(0.0..self.width).contains(&position.x)
+                {
+                    continue;
+                }
+
+                ore_deposits.push(OreDeposit {
+                    kind: OreKind::iter().choose(&mut rng).unwrap(),
+                    position,
+                    amount: rng.gen_range(self.ore_amount[0]..self.ore_amount[1]),
+                    remaining: 0,
+                });
+                placed += 1;
+            }
+        }
+
+        Level {
+            name: format!("Generated cave #{}", seed),
+            width: self.width,
+            height: self.height,
+            stage_limits: self.stage_limits.clone(),
+            bottom_limits_x: self.bottom_limits_x,
+            start_position,
+            win_height: self.win_height,
+            spawns: self.spawns.clone(),
+            guardians: GuardianLayout { squads },
+            obstacles,
+            food_zones,
+            currents: CurrentsDescription {
+                seed,
+                upwellings,
+                jets,
+                ..Default::default()
+            },
+            ore_deposits,
+            territories,
+            checkpoints,
+        }
+    }
+}
+
+/// A rock that fits in the circle of `radius` around `center`
+fn random_rock(rng: &mut StdRng, center: Vec2, radius: f32) -> Obstacle {
+    match rng.gen_range(0..3) {
+        0 => Obstacle::Circle { center, radius },
+        1 => {
+            let angle = rng.gen_range(0.2..1.37_f32);
+            Obstacle::Rect {
+                center,
+                size: Vec2::new(angle.cos(), angle.sin()) * radius * 2.0,
+            }
+        }
+        _ => {
+            let n = rng.gen_range(5..9);
+            let points = (0..n)
+                .map(|k| {
+                    let angle = std::f32::consts::TAU * k as f32 / n as f32;
+                    center + Vec2::new(angle.cos(), angle.sin()) * radius * rng.gen_range(0.6..1.0)
+                })
+                .collect::<Vec<_>>();
+            Obstacle::Polygon { points }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn same_seed_same_level() {
+        let a = LevelGenerator::new(1234).generate().unwrap();
+        let b = LevelGenerator::new(1234).generate().unwrap();
+        assert_eq!(
+            serde_json::to_string(&a).unwrap(),
+            serde_json::to_string(&b).unwrap()
+        );
+
+        let c = LevelGenerator::new(1235).generate().unwrap();
+        assert_ne!(
+            serde_json::to_string(&a).unwrap(),
+            serde_json::to_string(&c).unwrap()
+        );
+    }
+
+    #[test]
+    fn generated_levels_are_valid() {
+        for seed in (0..8).map(|k| k * 7919) {
+            let level = LevelGenerator::new(seed)
+                .generate()
+                .unwrap_or_else(|error| panic!("seed {}: {}", seed, error));
+            assert!(level.validate().is_ok(), "seed {}", seed);
+            assert!(
+                level.surface_is_reachable(PASSAGE_CLEARANCE),
+                "seed {}",
+                seed
+            );
+        }
+    }
+}
@@ -28,7 +28,28 @@ use bevy::{
 
 pub mod agent;
 pub mod cam;
+pub mod checkpoints;
+pub mod currents;
+pub mod ending;
+pub mod guardians;
+pub mod health;
+pub mod hud;
 pub mod inputs;
+pub mod level;
+pub mod levelgen;
+pub mod metabolism;
+pub mod minimap;
+pub mod music;
+pub mod nav;
+pub mod ore;
+pub mod population;
+pub mod replay;
+pub mod save;
+pub mod sfx;
+pub mod softbody;
+pub mod states;
+pub mod terrain;
+pub mod tribe;
 pub mod util;
 pub use inputs::*;
 
@@ -16,7 +16,7 @@ use bevy::{
     sprite::MaterialMesh2dBundle,
     sprite::Mesh2dHandle,
 };
-use bevy_kira_audio::{Audio, AudioPlugin, InstanceHandle};
+use bevy_kira_audio::AudioPlugin;
 
 pub use agent::*;
 use cam::*;
@@ -28,9 +28,54 @@ pub use util::*;
 
 pub use inputs::*;
 
+pub use softbody::*;
+
+pub use metabolism::*;
+
+pub use population::*;
+
+pub use health::*;
+
+pub use guardians::*;
+
+pub use level::*;
+
+pub use levelgen::*;
+
+pub use currents::*;
+
+pub use terrain::*;
+
+pub use nav::*;
+
+pub use ore::*;
+
+pub use tribe::*;
+
+pub use checkpoints::*;
+
+pub use states::*;
+
+pub use save::*;
+
+pub use replay::*;
+
+pub use minimap::*;
+
+pub use hud::*;
+
+pub use music::*;
+
+pub use sfx::*;
+
+pub use ending::*;
+
 // pub use libaaa::*;
 
 use rand::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::Path;
 
 // use std::fs::File;
 // use std::io::Read;
@@ -58,7 +103,11 @@ use rand::prelude::*;
 
 // use bevy_inspector_egui::{Inspectable, InspectorPlugin};
 
+// Turning harder than this turns at the full rate, below it the rate is proportional
+pub const SOFT_TURNING: f32 = 0.5;
+
 // #[derive(Inspectable)]
+#[derive(Serialize, Deserialize, Clone)]
 pub struct MovementParams {
     // #[inspectable(min = 0.00, max = 1.0, speed = 0.001)]
     pub friction1: f32,
@@ -112,6 +161,23 @@ impl Default for MovementParams {
 }
 
 impl MovementParams {
+    /// Angle turned in one step, positive to the left. It grows with the speed, and
+    /// with how hard the creature turns up to SOFT_TURNING.
+    pub fn turn_angle(&self, turning: &Turning, speed: f32, boost_value: f32) -> f32 {
+        let (direction, delta_angle) = match turning {
+            Turning::Left(delta_angle) => (1.0, *delta_angle),
+            Turning::Right(delta_angle) => (-1.0, *delta_angle),
+            Turning::None => return 0.0,
+        };
+
+        let amount = (delta_angle / SOFT_TURNING).min(1.0);
+        let speed_turn = speed * self.turning_speed_dependence * (1.0 - boost_value);
+        direction
+            * amount
+            * (self.rest_turn_speed
+                + speed_turn.clamp(0.0, self.max_turn_speed - self.rest_turn_speed))
+    }
+
     pub fn stage1() -> Self {
         Self {
             friction1: 0.2,
@@ -161,21 +227,38 @@ impl MovementParams {
     }
 }
 
-#[derive(Debug, Clone, Eq, PartialEq, Hash)]
-pub enum AppState {
-    InGame,
-    Ending,
-}
-struct LoopAudioInstanceHandle {
-    instance_handle: InstanceHandle,
-}
-
-pub struct GameEndTime {
-    time: f32,
-    do_start_music: bool,
+// Adds the systems to the set so that they run one after the other, in the order given.
+// The scheduler orders the others differently from one launch to the next, which a
+// replay cannot follow.
+macro_rules! in_order {
+    (@after $set:expr; $previous:ident, $next:ident $(, $rest:ident)*) => {
+        in_order!(
+            @after $set.with_system($next.label(stringify!($next)).after(stringify!($previous)));
+            $next $(, $rest)*
+        )
+    };
+    (@after $set:expr; $last:ident) => {
+        $set
+    };
+    ($set:expr; $first:ident $(, $system:ident)* $(,)?) => {
+        in_order!(@after $set.with_system($first.label(stringify!($first))); $first $(, $system)*)
+    };
 }
 
 fn main() {
+    let level = match Level::load_from_args() {
+        Ok(level) => level,
+        Err(error) => {
+            eprintln!("{}", error);
+            std::process::exit(1);
+        }
+    };
+
+    let terrain = Terrain::from_level(&level);
+    let nav_grid = NavGrid::from_terrain(&level, &terrain);
+    // the runs are seeded with the level, see WorldRng
+    let mut world_rng = WorldRng::new(level.currents.seed);
+
     App::new()
         .insert_resource(WindowDescriptor {
             title: "Rise Above".to_string(),
@@ -185,114 +268,149 @@ fn main() {
             ..Default::default()
         })
         .add_plugins(DefaultPlugins)
+        .add_plugin(ActionsPlugin)
         .add_plugin(CamPlugin)
+        .add_plugin(MinimapPlugin)
+        .add_plugin(HudPlugin)
+        .add_plugin(MusicPlugin)
+        .add_plugin(SfxPlugin)
         .add_plugin(MarkerMesh2dPlugin)
         .add_plugin(AudioPlugin)
-        .add_state(AppState::InGame)
+        .add_state(AppState::Title)
         // .add_plugin(InspectorPlugin::<MovementParams>::new())
         .add_event::<CollisionEvent>()
+        .add_event::<AgentDeathEvent>()
+        .add_event::<BoostEvent>()
+        .add_event::<FoodEatenEvent>()
+        .add_event::<GuardianChargeEvent>()
+        .add_event::<StageChangeEvent>()
         .insert_resource(Cursor::default())
         .insert_resource(MovementParams::stage1())
-        .insert_resource(Game::new())
+        .insert_resource(Game::new(&level, &mut world_rng.rng))
+        .insert_resource(world_rng)
+        .insert_resource(WorldTime::default())
         .insert_resource(KdTrees::new())
-        .insert_resource(GameEndTime {
-            time: 0.0,
-            do_start_music: true,
+        .insert_resource(PopulationTargets::from_level(&level))
+        .insert_resource(PopulationTimers::default())
+        .insert_resource(CurrentField::from_level(&level))
+        .insert_resource(CurrentsOverlay::default())
+        .insert_resource(OreDeposits::from_level(&level))
+        .insert_resource(TribeRelations::from_level(&level))
+        .insert_resource(Progress::default())
+        .insert_resource(ReplayRecorder {
+            replay: Replay::new(&level, level.currents.seed),
         })
+        .insert_resource(ReplayPlayer::default())
+        .insert_resource(terrain)
+        .insert_resource(nav_grid)
+        .insert_resource(level)
+        .insert_resource(GameEndTime { time: 0.0 })
+        .insert_resource(Ending::default())
+        .insert_resource(EndingPlayer::default())
         .add_startup_system(setup)
-        .add_startup_system(start_background_audio.system())
+        .add_startup_system(spawn_current_visuals)
+        .add_startup_system(spawn_terrain)
+        .add_startup_system(spawn_ore_deposits)
+        .add_system_set(SystemSet::on_enter(AppState::Title).with_system(show_title))
+        .add_system_set(SystemSet::on_update(AppState::Title).with_system(leave_title))
+        .add_system_set(SystemSet::on_exit(AppState::Title).with_system(despawn_state_text))
         .add_system_set(
+            SystemSet::on_enter(AppState::InGame)
+                .with_system(start_run)
+                .with_system(start_replay_run),
+        )
+        .add_system_set(in_order!(
             SystemSet::on_update(AppState::InGame)
-                .with_system(collisions.exclusive_system().at_start())
-                .with_system(main_character_inputs)
-                .with_system(main_char_movement)
-                .with_system(agents_movement)
+                .with_system(
+                    advance_world_time
+                        .exclusive_system()
+                        .at_start()
+                        .label("advance_world_time"),
+                )
+                .with_system(
+                    collisions
+                        .exclusive_system()
+                        .at_start()
+                        .after("advance_world_time"),
+                )
                 .with_system(record_mouse_events_system)
-                .with_system(see)
-                .with_system(update_agent_kdtree)
-                .with_system(forget)
-                .with_system(agent_decisions)
-                .with_system(agent_action)
-                .with_system(update_agent_properties)
-                .with_system(energy_ground_state)
-                .with_system(winning_condition)
-                .with_system(send_guardians)
+                .with_system(show_damage)
+                .with_system(show_ore_deposits)
+                .with_system(drift_particles)
+                .with_system(toggle_currents_overlay)
                 .with_system(update_time)
                 .with_system(update_character_frequency)
-                .with_system(adjust_playback_rate)
-                .with_system(print_status),
+                .with_system(show_boost_readiness)
+                .with_system(pause_game)
+                .with_system(quicksave)
+                .with_system(quickload)
+                .with_system(replay_controls);
+            // the world, one step after the other
+            main_character_inputs,
+            main_char_movement,
+            agents_movement,
+            terrain_collisions,
+            simulate_soft_bodies,
+            see,
+            update_agent_kdtree,
+            forget,
+            agent_decisions,
+            agent_action,
+            navigate,
+            update_agent_properties,
+            apply_collision_damage,
+            tribe_reactions,
+            defend_territories,
+            regenerate_health,
+            energy_ground_state,
+            metabolism,
+            eat_food,
+            regrow_food,
+            see_ores,
+            collect_ore,
+            regrow_ore,
+            upgrade_traits,
+            drift_food,
+            reap_dead_agents,
+            respawn_population,
+            winning_condition,
+            reach_checkpoints,
+            track_stage,
+            lose_conditions,
+            respawn_main_character,
+            guardian_squads_behaviour,
+        ))
+        .add_system_set(SystemSet::on_enter(AppState::Paused).with_system(show_pause))
+        .add_system_set(
+            SystemSet::on_update(AppState::Paused)
+                .with_system(resume_game)
+                .with_system(rebind_actions)
+                .with_system(refresh_pause_text),
         )
         .add_system_set(
-            SystemSet::on_enter(AppState::Ending).with_system(play_ending), // .with_system(fade_out_in),
+            SystemSet::on_exit(AppState::Paused)
+                .with_system(despawn_state_text)
+                .with_system(stop_rebinding),
         )
+        .add_system_set(SystemSet::on_enter(AppState::GameOver).with_system(show_game_over))
+        .add_system_set(SystemSet::on_update(AppState::GameOver).with_system(request_restart))
+        .add_system_set(SystemSet::on_exit(AppState::GameOver).with_system(despawn_state_text))
+        .add_system_set(SystemSet::on_enter(AppState::Ending).with_system(start_ending))
         .add_system_set(
             SystemSet::on_update(AppState::Ending)
-                // .with_system(play_ending)
-                .with_system(fade_out_in),
-        ) //
+                .with_system(play_ending)
+                .with_system(request_restart),
+        )
+        .add_system_set(SystemSet::on_exit(AppState::Ending).with_system(finish_ending))
+        .add_system_set(SystemSet::on_enter(AppState::Restarting).with_system(restart_run))
+        .add_system_set(SystemSet::on_update(AppState::Restarting).with_system(finish_restart)) //
         // .add_system(agent_movement_debug)
         .run();
 }
 
-fn start_background_audio(
-    mut commands: Commands,
-    asset_server: Res<AssetServer>,
-    audio: Res<Audio>,
-) {
-    let intro_song_handle = asset_server.load("Rise Above_Song.ogg");
-    let instance_handle = audio.play_looped(intro_song_handle.clone());
-    // let instance_handle = audio.play_looped(asset_server.load("Rise Above Action V1.ogg"));
-    commands.insert_resource(LoopAudioInstanceHandle { instance_handle });
-    commands.insert_resource(intro_song_handle);
-}
-
-fn print_status(
-    audio: Res<Audio>,
-    loop_audio: Res<LoopAudioInstanceHandle>,
-    asset_server: Res<AssetServer>,
-) {
-    let state = audio.state(loop_audio.instance_handle.clone());
-    // println!("Loop audio {:?}", state);
-    if let bevy_kira_audio::PlaybackState::Playing { position: pos } = state {
-        // println!("{:?}", pos);
-        if pos > 212.0 {
-            audio.stop();
-            audio.play_looped(asset_server.load("Rise Above Action V1.ogg"));
-        }
-    }
-}
-
-use kdtree::distance::squared_euclidean;
-pub fn adjust_playback_rate(
-    audio: Res<Audio>,
-    time: Res<Time>,
-    game: Res<Game>,
-    kdtrees: Res<KdTrees>,
-) {
-    let main_character = game.agents.get(&1).unwrap();
-
-    if let Ok(dist_id_array) = kdtrees.agent_kdtree.nearest(
-        &[main_character.position.x, main_character.position.y],
-        // (agent.sensors.sight_range).powf(2.0),
-        2,
-        // (100.0_f32).powf(2.0),
-        &squared_euclidean,
-    ) {
-        let t = time.seconds_since_startup() as f32;
-        let start = 0.0;
-        if t > start {
-            let (dist, id) = dist_id_array[1];
-            let closest_agent = game.agents.get(&id).unwrap();
-            if closest_agent.is_guardian {
-                let distance = dist.sqrt();
-                let playback_delta = (10.0 / distance) * 3.0;
-                let playback = (1.0 + playback_delta).clamp(1.0, 3.0);
-                audio.set_playback_rate(playback);
-
-                // audio.set_playback_rate(1.0 + (t - start) * 0.0);
-            }
-        }
-    }
+// Runs are timed from here. The music follows the state of the run, see MusicDirector
+fn start_run(mut game: ResMut<Game>, time: Res<WorldTime>) {
+    game.time = time.seconds_since_startup() as f32;
 }
 
 pub fn update_time(time: Res<Time>, mut query: Query<(&mut CharacterUniform,)>) {
@@ -327,62 +445,36 @@ fn update_character_frequency(
     }
 }
 
-fn spawn_character(
-    commands: &mut Commands,
-    meshes: &mut ResMut<Assets<Mesh>>,
-    quad_position: Vec2,
-    quad_size: f32,
-    character_in_save_format: CharacterSaveFormat,
-    // character_parent: Entity,
-) -> Entity {
-    let mut instance_data_vec: MarkerInstanceMatData = character_in_save_format.into();
-    for (k, instance) in instance_data_vec.0.iter_mut().enumerate() {
-        instance.set_frequency(40.0, k);
-    }
+// The boost bar of the HUD fills up over time_between_boosts
+fn show_boost_readiness(
+    game: Res<Game>,
+    time: Res<WorldTime>,
+    move_params: Res<MovementParams>,
+    mut query: Query<(&mut Sprite, &mut Transform), With<HudBoostBar>>,
+) {
+    let agent = match game.agents.get(&1) {
+        Some(agent) => agent,
+        None => return,
+    };
+    let now = time.seconds_since_startup() as f32;
+    let readiness = (now - agent.boost_time) / move_params.time_between_boosts;
 
-    // let quad_position = Vec2::ZERO;
-    let entity = commands
-        .spawn_bundle((
-            Mesh2dHandle(meshes.add(Mesh::from(shape::Quad {
-                size: Vec2::splat(quad_size),
-                flip: false,
-            }))),
-            GlobalTransform::default(),
-            Transform::from_translation(Vec3::new(quad_position.x, quad_position.y, 0.12)),
-            // Transform::from_translation(Vec3::new(0.0, 0.0, -0.12)),
-            Visibility::default(),
-            ComputedVisibility::default(),
-            instance_data_vec,
-            // NoFrustumCulling,
-        ))
-        .insert(AgentId { kdtree_hash: 1 })
-        .insert(MainCharacter { id: 1 })
-        .insert(InstanceDataNotEncoded::default())
-        .insert(CharacterUniform {
-            character_size: 0.1,
-            core_size: 1.0,
-            zoom: 1.0,
-            time: 0.0,
-            character_point_color: Vec4::new(0.0, 1.0, 0.0, 1.0),
-            color: Color::hex("8c114a").unwrap().into(),
-            quad_size,
-            inner_canvas_size_in_pixels: Vec2::new(300.0, 300.0),
-            // outer_border: plot.outer_border,
-            canvas_position: quad_position,
-            contour: 1.0,
-        })
-        .id();
-    // commands.entity(character_parent).push_children(&[entity]);
-    return entity;
+    for (mut sprite, mut transform) in query.iter_mut() {
+        set_bar(&mut sprite, &mut transform, readiness);
+        sprite.color = if readiness >= 1.0 {
+            Color::CYAN
+        } else {
+            Color::GRAY
+        };
+    }
 }
 
-fn spawn_agent(
+fn spawn_character(
     commands: &mut Commands,
     meshes: &mut ResMut<Assets<Mesh>>,
     quad_position: Vec2,
     quad_size: f32,
     character_in_save_format: CharacterSaveFormat,
-    id: u32,
     // character_parent: Entity,
 ) -> Entity {
     let mut instance_data_vec: MarkerInstanceMatData = character_in_save_format.into();
@@ -405,9 +497,8 @@ fn spawn_agent(
             instance_data_vec,
             // NoFrustumCulling,
         ))
-        .insert(AgentId { kdtree_hash: id })
-        // .insert(MainCharacter { id: 1 })
-        .insert(NPC)
+        .insert(AgentId { kdtree_hash: 1 })
+        .insert(MainCharacter { id: 1 })
         .insert(InstanceDataNotEncoded::default())
         .insert(CharacterUniform {
             character_size: 0.1,
@@ -427,42 +518,18 @@ fn spawn_agent(
     return entity;
 }
 
-pub fn send_guardians(mut game: ResMut<Game>, time: Res<Time>) {
-    if time.seconds_since_startup() > 0.0 {
-        let main_char = game.agents.get(&1).unwrap();
-        let main_char_height = main_char.position.y;
-        if main_char_height > LEVEL_HEIGHT / 2.0 {
-            for (id, agent) in game.agents.iter_mut() {
-                //
-                // guardians
-                if *id >= 20 && *id < 40 {
-                    agent.goal = Goal::Bully(1); // main_char.id;
-                    agent.goal_time = time.seconds_since_startup() as f32;
-                }
-            }
-        } else {
-            for (id, agent) in game.agents.iter_mut() {
-                //
-                // guardians
-                if *id >= 20 && *id < 35 {
-                    agent.goal = Goal::GoTo(agent.guardian_pos); // main_char.id;
-                    agent.goal_time = time.seconds_since_startup() as f32;
-                } else if *id < 40 {
-                    agent.goal = Goal::Bully(1);
-                    agent.goal_time = time.seconds_since_startup() as f32;
-                }
-            }
-        }
-    }
-}
-
-pub fn update_agent_kdtree(mut kdtrees: ResMut<KdTrees>, mut game: ResMut<Game>) {
-    let mut rng = rand::thread_rng();
+pub fn update_agent_kdtree(
+    mut kdtrees: ResMut<KdTrees>,
+    mut game: ResMut<Game>,
+    mut world_rng: ResMut<WorldRng>,
+    level: Res<Level>,
+) {
+    let rng = &mut world_rng.rng;
     for (_id, mut agent) in game.agents.iter_mut() {
         if !agent.position.y.is_finite() {
             agent.position = Vec2::new(
-                rng.gen::<f32>() * LEVEL_WIDTH,
-                rng.gen::<f32>() * LEVEL_HEIGHT,
+                rng.gen::<f32>() * level.width,
+                rng.gen::<f32>() * level.height,
             );
         }
     }
@@ -483,8 +550,10 @@ fn setup(
     mut commands: Commands,
     mut game: ResMut<Game>,
     mut kdtrees: ResMut<KdTrees>,
+    mut world_rng: ResMut<WorldRng>,
     asset_server: Res<AssetServer>,
     mut meshes: ResMut<Assets<Mesh>>,
+    level: Res<Level>,
     // time: Res<Time>,
 ) {
     // commands
@@ -492,7 +561,7 @@ fn setup(
     //     .insert(Cam::default());
     kdtrees.as_mut().populate(game.as_ref());
 
-    let mut cam_trans = Transform::from_translation(Vec3::new(LEVEL_WIDTH / 2.0, 0.0, 10.0));
+    let mut cam_trans = Transform::from_translation(Vec3::new(level.width / 2.0, 0.0, 10.0));
     cam_trans.scale.x = 0.5;
     cam_trans.scale.y = 0.5;
 
@@ -510,21 +579,22 @@ fn setup(
         })
         .insert(Cam::default());
 
-    let world_size = Vec2::new(LEVEL_WIDTH, LEVEL_HEIGHT);
-    let main_creature_size = Vec2::new(10.0, 5.);
-    let floor_size = Vec2::new(LEVEL_WIDTH + 4000.0, 2000.);
-    let wall_size = Vec2::new(2000.0, LEVEL_HEIGHT);
+    let world_size = Vec2::new(level.width, level.height);
+    // the ocean goes from below the floor up to its surface
+    let ocean_size = Vec2::new(level.width, level.win_height + 1000.0);
+    let floor_size = Vec2::new(level.width + 4000.0, 2000.);
+    let wall_size = Vec2::new(2000.0, level.height);
 
     // ocean
     commands.spawn_bundle(SpriteBundle {
         sprite: Sprite {
             color: Color::rgb(0.25, 0.25, 0.75),
-            custom_size: Some(world_size),
+            custom_size: Some(ocean_size),
             ..Default::default()
         },
         transform: Transform::from_translation(Vec3::new(
-            LEVEL_WIDTH / 2.0,
-            world_size.y / 2.0 - 1000.0,
+            level.width / 2.0,
+            ocean_size.y / 2.0 - 1000.0,
             0.0,
         )),
         ..Default::default()
@@ -537,7 +607,7 @@ fn setup(
             custom_size: Some(floor_size),
             ..Default::default()
         },
-        transform: Transform::from_translation(Vec3::new(LEVEL_WIDTH / 2.0, -1010.0, 0.01)),
+        transform: Transform::from_translation(Vec3::new(level.width / 2.0, -1010.0, 0.01)),
         ..Default::default()
     });
 
@@ -549,7 +619,7 @@ fn setup(
             ..Default::default()
         },
         transform: Transform::from_translation(Vec3::new(
-            LEVEL_WIDTH + 1000.0,
+            level.width + 1000.0,
             world_size.y / 2.0 - 1000.0,
             0.01,
         )),
@@ -572,28 +642,131 @@ fn setup(
 
     ///// Load Creatures
     let creatures_map = load_creatures();
-    let creatures_vec = creatures_map.values().collect::<Vec<_>>();
 
-    let guardian: CharacterSaveFormat =
-        serde_json::from_str(&include_str!("guardian.cha")).unwrap();
+    let templates = CreatureTemplates {
+        main_character: creatures_map.get("franky").unwrap().clone(),
+        creatures: creatures_map,
+        guardian: serde_json::from_str(&include_str!("guardian.cha")).unwrap(),
+    };
+
+    spawn_creatures(
+        &mut commands,
+        &mut meshes,
+        game.as_mut(),
+        &level,
+        &templates,
+        &mut world_rng.rng,
+    );
+    commands.insert_resource(templates);
+
+    ////////////////////////////// text /////////////////////////////
+    let text_style = TextStyle {
+        font: asset_server.load("fonts/Roboto-Regular.ttf"),
+        font_size: 44.0,
+        color: Color::BLACK,
+    };
+    let text_alignment = TextAlignment {
+        vertical: VerticalAlign::Bottom,
+        horizontal: HorizontalAlign::Center,
+    };
+
+    commands
+        .spawn_bundle(Text2dBundle {
+            text: Text::with_section(
+                "Come in the moshpit. It will rejuvenate you.          Once a friend, twice a foe.           Rise above the surface.",
+                text_style.clone(),
+                text_alignment,
+            ),
+            transform: Transform::from_translation(Vec3::new(
+                level.width / 2.0 + 800.0,
+                -100.0,
+                10.0,
+            )),
+            ..Default::default()
+        })
+        .insert(StartText);
+
+    let text_style = TextStyle {
+        font: asset_server.load("fonts/Roboto-Regular.ttf"),
+        font_size: 44.0,
+        color: Color::BLACK,
+    };
+    let text_alignment = TextAlignment {
+        vertical: VerticalAlign::Bottom,
+        horizontal: HorizontalAlign::Center,
+    };
 
-    let mut rng = rand::thread_rng();
-    ////////
+    commands
+        .spawn_bundle(Text2dBundle {
+            text: Text::with_section(
+                "Use Space or right click to boost. Use WASD or the left click to steer",
+                text_style.clone(),
+                text_alignment,
+            ),
+            transform: Transform::from_translation(Vec3::new(
+                level.width / 2.0 - 800.0,
+                -100.0,
+                10.0,
+            )),
+            ..Default::default()
+        })
+        .insert(StartText);
+}
 
+// The main character, the NPCs and the food of a new run
+pub fn spawn_creatures(
+    commands: &mut Commands,
+    meshes: &mut ResMut<Assets<Mesh>>,
+    game: &mut Game,
+    level: &Level,
+    templates: &CreatureTemplates,
+    rng: &mut StdRng,
+) {
     //////////////////// main character////////////////////////////////////////////////////////////////////////
 
-    let main_creature = creatures_map.get("franky").unwrap();
     // the 1 is for the main character's id
-    let mut main_agent = Agent::gen_random(&GameStage::Bottom, 1);
-    // main_agent.position = Vec2::new(LEVEL_WIDTH / 2.0, 20.0);
-    // main_agent.position = Vec2::new(LEVEL_WIDTH / 2.0, 20.0);
-    main_agent.position = Vec2::new(LEVEL_WIDTH / 2.0, LEVEL_HEIGHT - 1000.0 - 20.0);
+    let mut main_agent = Agent::gen_random(&GameStage::Bottom, 1, level, rng);
+    main_agent.position = level.start_position;
     main_agent.last_position = main_agent.position;
     main_agent.mass = STARTING_MASS;
+    // the main character belongs to no tribe, it earns its standing with each of them
+    main_agent.tribe = None;
     // main_agent.mass = 0.1;
     main_agent.update_mass_properties();
     // main_agent.radius = main_agent.mass * MASS_MULT * 0.5;
+    game.agents.insert(1, main_agent);
+
+    spawn_game_entities(commands, meshes, game, templates, rng);
+}
+
+// The creature and food entities of the agents and foods in `game`, for a new run or a
+// loaded session
+pub fn spawn_game_entities(
+    commands: &mut Commands,
+    meshes: &mut ResMut<Assets<Mesh>>,
+    game: &mut Game,
+    templates: &CreatureTemplates,
+    rng: &mut StdRng,
+) {
+    for (id, agent) in game.agents.iter_mut() {
+        if id == &1 {
+            spawn_main_character(commands, meshes, agent, &templates.main_character);
+        } else {
+            spawn_npc(commands, meshes, agent, templates, rng);
+        }
+    }
+
+    for (_id, food) in game.foods.iter() {
+        spawn_food(commands, food);
+    }
+}
 
+pub fn spawn_main_character(
+    commands: &mut Commands,
+    meshes: &mut ResMut<Assets<Mesh>>,
+    main_agent: &mut Agent,
+    main_creature: &CharacterSaveFormat,
+) {
     let atom_size = Vec2::splat(ATOM_MULT * main_agent.mass * MASS_MULT);
     main_agent.body = take_pos(main_creature.clone())
         .iter()
@@ -622,38 +795,30 @@ fn setup(
     main_agent.position = transform.translation.truncate();
     main_agent.last_position = main_agent.position;
 
-    let core_id = commands
-        .spawn_bundle(SpriteBundle {
-            sprite: Sprite {
-                color: Color::rgb(0.75, 0.25, 0.55),
-                custom_size: Some(main_creature_size * 0.3),
-
-                ..Default::default()
-            },
-            visibility: Visibility { is_visible: false },
-            transform,
-            ..Default::default()
-        })
-        // .insert(AgentId { kdtree_hash: 1 })
-        // .insert(MainCharacter { id: 1 })
-        .id();
-
     let parent_entity = spawn_character(
-        &mut commands,
-        &mut meshes,
+        commands,
+        meshes,
         main_agent.position,
         MASS_MULT * main_agent.mass * 1.05,
         main_creature.clone(),
-        // core_id,
     );
 
     main_agent.entity = Some(parent_entity);
 
+    commands
+        .entity(parent_entity)
+        .insert(SoftBody::from_character(
+            main_creature,
+            main_agent.mass,
+            main_agent.position,
+            main_agent.look_at_angle,
+        ));
+
     take_pos(main_creature.clone())
         .iter()
         .enumerate()
         .for_each(|(k, pos)| {
-            if pos.length() < 0.49 * MASS_MULT {
+            if pos.length() < PARKED_NODE_DISTANCE * MASS_MULT {
                 let transform = Transform::from_translation(pos.extend(0.05) * main_agent.mass);
 
                 let child_id = commands
@@ -674,443 +839,301 @@ fn setup(
                 main_agent.body[k].entity = Some(child_id);
                 main_agent.body[k].is_used = true;
 
-                // commands.entity(core_id).push_children(&[child_id]);
                 commands.entity(parent_entity).push_children(&[child_id]);
             }
         });
-    game.agents.insert(1, main_agent);
-
-    //////////////////// main character ////////////////////////////////////////////////////////////////////////
-
-    ////////////////////////////// spawn all npcs ////////////////////////////////////////////////
+}
 
+pub fn energy_ground_state(mut game: ResMut<Game>, time: Res<WorldTime>) {
+    // let mut game = Game::new();
     for (id, mut agent) in game.agents.iter_mut() {
-        if id == &1 {
-            continue;
+        if time.seconds_since_startup() as f32 - agent.last_collision_time > 1.5 {
+            // energy lost below the ground state has to be eaten back, see `metabolism`
+            if agent.energy > ENERGY_GROUND_STATE {
+                agent.energy =
+                    agent.energy - ENERGY_DECAY_RATE * (agent.energy - ENERGY_GROUND_STATE);
+            }
+            if *id == 1 {
+                // println!("energy: {:?}", agent.energy);
+            }
         }
+    }
+}
 
-        let creature_index = rng.gen_range(0..creatures_vec.len());
-        let mut creature = creatures_vec[creature_index].clone();
-        if agent.is_guardian {
-            creature = guardian.clone();
-        }
+pub fn winning_condition(
+    game: ResMut<Game>,
+    time: Res<WorldTime>,
+    mut app_state: ResMut<State<AppState>>,
+    mut game_end_time: ResMut<GameEndTime>,
+    level: Res<Level>,
+) {
+    let agent = game.agents.get(&1).unwrap();
 
-        let creature_pos = agent.position;
-        // println!("creature pos: {:?}", creature_pos);
+    if agent.position.y > level.win_height && !game.won {
+        // the ending takes it from there, see ending.json. Tried again on the next frame
+        // when another transition is already queued.
+        if app_state.set(AppState::Ending).is_ok() {
+            game_end_time.time = time.seconds_since_startup() as f32;
+        }
+    }
+}
 
-        let color = Color::rgb(rng.gen::<f32>(), rng.gen::<f32>(), rng.gen::<f32>());
+// Tears down the creatures and the food of the last run, and rebuilds everything that
+// changed during it. The level, terrain and templates are kept.
+pub fn restart_run(
+    mut commands: Commands,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut game_end_time: ResMut<GameEndTime>,
+    mut world_rng: ResMut<WorldRng>,
+    mut world_time: ResMut<WorldTime>,
+    player: Res<ReplayPlayer>,
+    level: Res<Level>,
+    templates: Res<CreatureTemplates>,
+    agent_query: Query<Entity, With<AgentId>>,
+    food_query: Query<Entity, With<FoodComp>>,
+) {
+    for entity in agent_query.iter() {
+        commands.entity(entity).despawn_recursive();
+    }
+    for entity in food_query.iter() {
+        commands.entity(entity).despawn();
+    }
 
-        // TODO: remove, only useful for testing
-        let creature_size = Vec2::splat(MASS_MULT * agent.mass * 0.001);
+    // the same seed, the same run. A replay brings the seed of the run that it recorded.
+    let seed = player
+        .pending
+        .as_ref()
+        .map_or(level.currents.seed, |replay| replay.world_seed);
+    *world_rng = WorldRng::new(seed);
+    *world_time = WorldTime::default();
+    let mut game = Game::new(&level, &mut world_rng.rng);
+    spawn_creatures(
+        &mut commands,
+        &mut meshes,
+        &mut game,
+        &level,
+        &templates,
+        &mut world_rng.rng,
+    );
 
-        // agent.radius = agent.mass * MASS_MULT * 0.5;
+    let mut kdtrees = KdTrees::new();
+    kdtrees.populate(&game);
 
-        // let contents = include_str!("piko.cha");
+    commands.insert_resource(game);
+    commands.insert_resource(kdtrees);
+    commands.insert_resource(MovementParams::stage1());
+    commands.insert_resource(PopulationTimers::default());
+    commands.insert_resource(OreDeposits::from_level(&level));
+    commands.insert_resource(TribeRelations::from_level(&level));
+    commands.insert_resource(Progress::default());
 
-        let mut agent_trans = Transform::from_translation(creature_pos.extend(0.09));
+    *game_end_time = GameEndTime { time: 0.0 };
+}
 
-        agent_trans.rotation = Quat::from_rotation_z(agent.look_at_angle);
+/// A session saved mid-ascent with F5, and loaded back with F9
+#[derive(Serialize, Deserialize)]
+pub struct SaveFile {
+    pub version: u32,
+    /// Name of the level, a session only loads in the level it was saved in
+    pub level: String,
+    /// Seconds since startup when the session was saved
+    pub saved_at: f32,
+    pub movement_params: MovementParams,
+    pub progress: Progress,
+    pub game: Game,
+    pub population_timers: PopulationTimers,
+    /// Units left in each deposit of the level, by index
+    pub ore_remaining: Vec<u32>,
+    /// Standing of each tribe with the main character
+    pub standing: Vec<(Tribe, f32)>,
+}
 
-        let npc_entity = commands
-            .spawn_bundle(SpriteBundle {
-                sprite: Sprite {
-                    color,
-                    custom_size: Some(creature_size),
+impl SaveFile {
+    pub fn ore_deposits(&self, level: &Level) -> OreDeposits {
+        let mut ore_deposits = OreDeposits::from_level(level);
+        for (deposit, remaining) in ore_deposits.deposits.iter_mut().zip(&self.ore_remaining) {
+            deposit.remaining = (*remaining).min(deposit.amount);
+        }
+        ore_deposits
+    }
 
-                    ..Default::default()
-                },
-                transform: agent_trans,
-                visibility: Visibility { is_visible: false },
-                ..Default::default()
-            })
-            // .insert(NPC)
-            // .insert(AgentId { kdtree_hash: *id })
-            .id();
-
-        if agent.is_guardian {}
-
-        let parent_entity_npc = spawn_agent(
-            &mut commands,
-            &mut meshes,
-            agent.position,
-            MASS_MULT * agent.mass * 1.35,
-            creature.clone(),
-            *id,
-            // core_id,
-        );
-        agent.entity = Some(parent_entity_npc);
-
-        // agent.entity = Some(npc_entity);
-
-        let atom_size = Vec2::splat(ATOM_MULT * agent.mass * MASS_MULT);
-
-        agent.body = take_pos(creature.clone())
-            .iter()
-            .enumerate()
-            .map(|(k, node)| Body {
-                atom_pos: *node * agent.mass,
-                rotation: Quat::from_rotation_z(agent.look_at_angle),
-                atom_size: atom_size.length(),
-                acceleration: Vec2::new(0.0, 0.0),
-                entity: None,
-                is_used: false,
-            })
-            .collect::<Vec<_>>();
-        // main_agent.mass = 0.05;
-
-        take_pos(creature).iter().enumerate().for_each(|(k, pos)| {
-            if pos.length() < 0.49 * MASS_MULT {
-                let mut transform = Transform::from_translation(pos.extend(4.0) * agent.mass);
-
-                let npc_child_id = commands
-                    .spawn_bundle(SpriteBundle {
-                        sprite: Sprite {
-                            color,
-                            custom_size: Some(atom_size),
-
-                            ..Default::default()
-                        },
-                        visibility: Visibility { is_visible: false },
-                        transform,
-                        ..Default::default()
-                    })
-                    // .insert(MainCharacter { id: 1 })
-                    .insert(Atom)
-                    .id();
-
-                agent.body[k].entity = Some(npc_child_id);
-                agent.body[k].is_used = true;
-
-                commands
-                    .entity(parent_entity_npc)
-                    .push_children(&[npc_child_id]);
-                // commands.entity(npc_entity).push_children(&[npc_child_id]);
-            }
-        });
+    pub fn tribe_relations(&self, level: &Level) -> TribeRelations {
+        let mut relations = TribeRelations::from_level(level);
+        relations.standing.extend(self.standing.iter().cloned());
+        relations
     }
-    ////////////////////////////// spawn all npcs ////////////////////////////////////////////////
-
-    ////////////////////////////// spawn food ////////////////////////////////////////////////
-    let mut rng = rand::thread_rng();
-    // println!("food len: {:?}, ", game.foods.len());
-    for (id, mut food) in game.foods.iter_mut() {
-        let food_pos = food.position;
-        // println!("creature pos: {:?}", creature_pos);
-
-        let color = Color::rgb(
-            rng.gen::<f32>() * 0.1,
-            rng.gen::<f32>() * 0.1,
-            rng.gen::<f32>() * 0.6,
-        );
-
-        // TODO: remove, only useful for testing
-        let food_size = Vec2::splat(MASS_MULT * food.mass.powf(0.5) * 0.1);
-        // let food_size = Vec2::splat(5.0);
-
-        // food.radius = food.mass * MASS_MULT * 0.5;
-
-        let food_trans = Transform::from_translation(food_pos.extend(0.03));
-        // println!("food: {:?}, ", food_trans.translation);
-
-        let _food_entity = commands
-            .spawn_bundle(SpriteBundle {
-                sprite: Sprite {
-                    color,
-                    custom_size: Some(food_size),
+}
 
-                    ..Default::default()
-                },
-                transform: food_trans,
-                ..Default::default()
-            })
-            .insert(FoodComp)
-            // .insert(foodId { kdtree_hash: *id })
-            .id();
+fn quicksave(
+    actions: Res<Actions>,
+    game: Res<Game>,
+    movement_params: Res<MovementParams>,
+    progress: Res<Progress>,
+    population_timers: Res<PopulationTimers>,
+    ore_deposits: Res<OreDeposits>,
+    relations: Res<TribeRelations>,
+    level: Res<Level>,
+    time: Res<WorldTime>,
+    mut notifications: EventWriter<HudNotification>,
+) {
+    if !actions.just_pressed(Action::Quicksave) {
+        return;
     }
-    ////////////////////////////// spawn food ////////////////////////////////////////////////
 
-    ////////////////////////////// text /////////////////////////////
-    let text_style = TextStyle {
-        font: asset_server.load("fonts/Roboto-Regular.ttf"),
-        font_size: 44.0,
-        color: Color::BLACK,
-    };
-    let text_alignment = TextAlignment {
-        vertical: VerticalAlign::Bottom,
-        horizontal: HorizontalAlign::Center,
+    let mut game = game.clone();
+    let dropped = game.sanitize();
+
+    let save = SaveFile {
+        version: SAVE_VERSION,
+        level: level.name.clone(),
+        saved_at: time.seconds_since_startup() as f32,
+        movement_params: movement_params.clone(),
+        progress: progress.clone(),
+        game,
+        population_timers: population_timers.clone(),
+        ore_remaining: ore_deposits.remaining(),
+        standing: relations.standings(),
     };
 
-    commands
-        .spawn_bundle(Text2dBundle {
-            text: Text::with_section(
-                "Come in the moshpit. It will rejuvenate you.          Once a friend, twice a foe.           Rise above the surface.",
-                text_style.clone(),
-                text_alignment,
-            ),
-            transform: Transform::from_translation(Vec3::new(
-                LEVEL_WIDTH / 2.0 + 800.0,
-                -100.0,
-                10.0,
-            )),
-            ..Default::default()
-        })
-        .insert(StartText);
-
-    let text_style = TextStyle {
-        font: asset_server.load("fonts/Roboto-Regular.ttf"),
-        font_size: 44.0,
-        color: Color::BLACK,
-    };
-    let text_alignment = TextAlignment {
-        vertical: VerticalAlign::Bottom,
-        horizontal: HorizontalAlign::Center,
+    let message = match write_save(Path::new(QUICKSAVE_PATH), &save) {
+        Ok(()) if dropped > 0 => format!("Saved, without {} broken creatures or food", dropped),
+        Ok(()) => "Saved".to_string(),
+        Err(error) => format!("Quicksave failed: {}", error),
     };
-
-    commands
-        .spawn_bundle(Text2dBundle {
-            text: Text::with_section(
-                "Use Space or right click to boost. Use WASD or the left click to steer",
-                text_style.clone(),
-                text_alignment,
-            ),
-            transform: Transform::from_translation(Vec3::new(
-                LEVEL_WIDTH / 2.0 - 800.0,
-                -100.0,
-                10.0,
-            )),
-            ..Default::default()
-        })
-        .insert(StartText);
+    notifications.send(HudNotification(message));
 }
 
-pub fn energy_ground_state(mut game: ResMut<Game>, time: Res<Time>) {
-    // let mut game = Game::new();
-    for (id, mut agent) in game.agents.iter_mut() {
-        if time.seconds_since_startup() as f32 - agent.last_collision_time > 1.5 {
-            if agent.energy < 1.0 {
-                agent.energy = agent.energy + ENERGY_REGAIN_RATE;
-            } else {
-                agent.energy =
-                    agent.energy - ENERGY_DECAY_RATE * (agent.energy - ENERGY_GROUND_STATE);
-            }
-            if *id == 1 {
-                // println!("energy: {:?}", agent.energy);
-            }
-        }
-    }
-}
-
-#[derive(Component)]
-pub struct EndText;
-
-pub fn play_ending(
+// Replaces the running session by the saved one. The creatures are respawned from their
+// templates, and take back the body state that they had when saved.
+fn quickload(
     mut commands: Commands,
-    game: Res<Game>,
-    audio: Res<Audio>,
-    asset_server: Res<AssetServer>,
-    // loop_audio: Res<Handle<AudioSource>>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    actions: Res<Actions>,
+    level: Res<Level>,
+    templates: Res<CreatureTemplates>,
+    mut world_rng: ResMut<WorldRng>,
+    time: Res<WorldTime>,
+    agent_query: Query<Entity, With<AgentId>>,
+    food_query: Query<Entity, With<FoodComp>>,
+    mut notifications: EventWriter<HudNotification>,
 ) {
-    // audio.stop();
-    // // audio.play_looped(loop_audio);
-
-    // println!("won");
-
-    let main_character = game.agents.get(&1).unwrap();
-    let mut pos = Vec3::new(
-        main_character.position.x,
-        main_character.position.y - 50.0,
-        50.0,
-    );
+    if !actions.just_pressed(Action::Quickload) {
+        return;
+    }
 
-    let text_style = TextStyle {
-        font: asset_server.load("fonts/Roboto-Regular.ttf"),
-        font_size: 17.0,
-        color: Color::rgba(0.0, 1.0, 1.0, 0.0),
-    };
-    let text_alignment = TextAlignment {
-        vertical: VerticalAlign::Bottom,
-        horizontal: HorizontalAlign::Center,
+    let save = read_save::<SaveFile>(Path::new(QUICKSAVE_PATH), SAVE_VERSION).and_then(|save| {
+        if save.level == level.name {
+            Ok(save)
+        } else {
+            Err(SaveError::Level(save.level))
+        }
+    });
+    let mut save = match save {
+        Ok(save) => save,
+        Err(error) => {
+            notifications.send(HudNotification(format!("Quickload failed: {}", error)));
+            return;
+        }
     };
 
-    commands
-        .spawn_bundle(Text2dBundle {
-            text: Text::with_section("Rise Above", text_style.clone(), text_alignment),
-            transform: Transform::from_translation(pos),
-            ..Default::default()
-        })
-        .insert(EndText);
-
-    pos.y = pos.y - 30.0;
-
-    commands
-        .spawn_bundle(Text2dBundle {
-            text: Text::with_section(
-                "A game made by Eliot Bolduc",
-                text_style.clone(),
-                text_alignment,
-            ),
-            transform: Transform::from_translation(pos),
-            ..Default::default()
-        })
-        .insert(EndText);
+    for entity in agent_query.iter() {
+        commands.entity(entity).despawn_recursive();
+    }
+    for entity in food_query.iter() {
+        commands.entity(entity).despawn();
+    }
 
-    pos.y = pos.y - 30.0;
+    let offset = time.seconds_since_startup() as f32 - save.saved_at;
+    save.game.shift_times(offset);
+    save.progress.shift_times(offset);
+    save.population_timers.shift_times(offset);
 
-    commands
-        .spawn_bundle(Text2dBundle {
-            text: Text::with_section(
-                "Song 1: Rise Above Song by Isaac Wylder  ",
-                text_style.clone(),
-                text_alignment,
-            ),
-            transform: Transform::from_translation(pos),
-            ..Default::default()
-        })
-        .insert(EndText);
-
-    pos.y = pos.y - 30.0;
-    commands
-        .spawn_bundle(Text2dBundle {
-            text: Text::with_section(
-                "Song 2: Rise Above Action by Francis Grégoire",
-                text_style.clone(),
-                text_alignment,
-            ),
-            transform: Transform::from_translation(pos),
-            ..Default::default()
-        })
-        .insert(EndText);
-}
+    let ore_deposits = save.ore_deposits(&level);
+    let relations = save.tribe_relations(&level);
+    let mut game = save.game;
+    let bodies = game
+        .agents
+        .iter()
+        .map(|(id, agent)| (*id, agent.body.clone()))
+        .collect::<HashMap<_, _>>();
 
-// pub struct StartMusicEvent;
+    // the saved creatures already have their templates
+    spawn_game_entities(
+        &mut commands,
+        &mut meshes,
+        &mut game,
+        &templates,
+        &mut world_rng.rng,
+    );
 
-pub fn fade_out_in(
-    audio: Res<Audio>,
-    time: Res<Time>,
-    mut game_end_time: ResMut<GameEndTime>,
-    // start_mus_event_writer: EventWriter<StartMusicEvent>,
-    asset_server: Res<AssetServer>,
-    mut query: Query<&mut Text, With<EndText>>,
-) {
-    let fade_time = 2.0;
-    let delta = time.seconds_since_startup() as f32 - game_end_time.time;
-
-    if delta < fade_time {
-        audio.set_volume((1.0 - (delta - fade_time)) / fade_time);
-        for mut text in query.iter_mut() {
-            // println!("nothing?");
-            for section in text.sections.iter_mut() {
-                section.style.color.set_a(delta / fade_time * 0.5);
-                // println!("{:?}", section);
+    for (id, agent) in game.agents.iter_mut() {
+        if let Some(mut body) = bodies.get(id).cloned() {
+            if body.len() == agent.body.len() {
+                for (saved, spawned) in body.iter_mut().zip(agent.body.iter()) {
+                    saved.entity = spawned.entity;
+                    saved.is_used = spawned.is_used;
+                }
+                agent.body = body;
             }
         }
-    } else if game_end_time.do_start_music {
-        game_end_time.do_start_music = false;
-        audio.stop();
-        audio.play_looped(asset_server.load("Rise Above_Song.ogg"));
     }
-}
-
-pub fn winning_condition(
-    game: ResMut<Game>,
-    time: Res<Time>,
-    asset_server: Res<AssetServer>,
-    mut commands: Commands,
-    mut app_state: ResMut<State<AppState>>,
-    audio: Res<Audio>,
-    mut game_end_time: ResMut<GameEndTime>,
-) {
-    let agent = game.agents.get(&1).unwrap();
-
-    if agent.position.y > LEVEL_HEIGHT - 1000.0 && !game.won {
-        let text_style = TextStyle {
-            font: asset_server.load("fonts/Roboto-Regular.ttf"),
-            font_size: 44.0,
-            color: Color::BLACK,
-        };
-        let text_alignment = TextAlignment {
-            vertical: VerticalAlign::Bottom,
-            horizontal: HorizontalAlign::Center,
-        };
-
-        println!("you won!");
-
-        let mut text = Text::with_section("You win! You win! You win! You win! You win! You win! You win! You win! You win! You win! You win! You win! You win! You win! You win! You win! You win! You win! You win! You win! You win! You win! You win! You win! You win! You win! You win! You win! You win! You win! You win! You win! You win! You win! You win! You win! You win! You win! You win! You win! You win! You win! You win! You win! You win! You win! You win! You win! You win! You win! You win! You win! You win! You win! You win! You win! You win! You win! You win! You win! You win! You win! You win! You win! You win! You win! You win! You win! You win! You win! You win! You win! You win! You win! You win! You win! You win! You win! You win! You win! You win! You win! You win! You win! You win! You win! You win! You win! You win! You win! You win! You win!", text_style.clone(), text_alignment);
-
-        if time.seconds_since_startup() < 40.0 {
-            text = Text::with_section(
-                "You win! ... but you didn't experience the game, you might want to restart... or not. You did win... so...",
-                text_style.clone(),
-                text_alignment,
-            );
-        }
 
-        game_end_time.time = time.seconds_since_startup() as f32;
-        app_state.set(AppState::Ending).unwrap();
-        audio.stop();
+    let mut kdtrees = KdTrees::new();
+    kdtrees.populate(&game);
 
-        // audio.play_looped(asset_server.load("Rise Above_Song.ogg"));
+    commands.insert_resource(game);
+    commands.insert_resource(kdtrees);
+    commands.insert_resource(ore_deposits);
+    commands.insert_resource(relations);
+    commands.insert_resource(save.movement_params);
+    commands.insert_resource(save.progress);
+    commands.insert_resource(save.population_timers);
 
-        commands
-            .spawn_bundle(Text2dBundle {
-                text,
-                transform: Transform::from_translation(Vec3::new(
-                    LEVEL_WIDTH / 2.0,
-                    LEVEL_HEIGHT + 100.0 - 1000.0,
-                    10.0,
-                )),
-                ..Default::default()
-            })
-            .insert(StartText);
-    }
+    notifications.send(HudNotification("Loaded".to_string()));
 }
 
 pub fn main_character_inputs(
     mut game: ResMut<Game>,
     // mut commands: Commands,
-    keyboard_input: Res<Input<KeyCode>>,
-    mouse_click: Res<Input<MouseButton>>,
+    actions: Res<Actions>,
     mut query: Query<&MainCharacter>,
 
-    time: Res<Time>,
+    time: Res<WorldTime>,
     move_params: Res<MovementParams>,
     cursor: Res<Cursor>,
+    mut recorder: ResMut<ReplayRecorder>,
+    mut player: ResMut<ReplayPlayer>,
+    mut boost_events: EventWriter<BoostEvent>,
+    mut notifications: EventWriter<HudNotification>,
 ) {
     let main_char = query.single_mut();
     let mut agent = game.agents.get_mut(&main_char.id).unwrap();
+    let now = time.seconds_since_startup() as f32;
+
+    // a replay is recorded again while it plays, it can be saved just the same
+    let was_playing = player.playing.is_some();
+    let frame = match player.next_frame() {
+        Some(frame) => {
+            frame.apply(agent, now);
+            frame
+        }
+        None => {
+            if was_playing {
+                notifications.send(HudNotification(
+                    "Replay over, back to the controls".to_string(),
+                ));
+            }
+            read_controls(agent, &actions, now, &move_params, &cursor);
+            ReplayFrame::of(agent, agent.boost_time == now)
+        }
+    };
+    recorder.replay.push(frame);
 
-    if keyboard_input.pressed(KeyCode::S) {
-        agent.acc = Acceleration::Backward;
-        agent.main_char_target_pos = None;
-    } else if keyboard_input.pressed(KeyCode::W) {
-        agent.acc = Acceleration::Forward;
-        agent.main_char_target_pos = None;
-    } else {
-        agent.acc = Acceleration::None;
-    }
-
-    if keyboard_input.pressed(KeyCode::A) && !keyboard_input.pressed(KeyCode::D) {
-        agent.turning = Turning::Left(1.0);
-        agent.main_char_target_pos = None;
-    } else if keyboard_input.pressed(KeyCode::D) && !keyboard_input.pressed(KeyCode::A) {
-        agent.turning = Turning::Right(1.0);
-        agent.main_char_target_pos = None;
-    } else {
-        agent.turning = Turning::None;
-    }
-
-    if (mouse_click.just_pressed(MouseButton::Right) || keyboard_input.pressed(KeyCode::Space))
-        && time.seconds_since_startup() as f32 - agent.boost_time > move_params.time_between_boosts
-    // && agent.energy > 1.0
-    {
-        agent.boost = true;
-        agent.boost_time = time.seconds_since_startup() as f32;
-        // agent.energy -= 1.0;
-        agent.main_char_target_pos = None;
-    }
-
-    if mouse_click.pressed(MouseButton::Left) {
-        agent.main_char_target_pos = Some(cursor.position);
+    if agent.boost_time == now {
+        boost_events.send(BoostEvent {
+            agent_id: agent.id,
+            position: agent.position,
+        });
     }
 
     if let Some(pos) = agent.main_char_target_pos {
@@ -1132,12 +1155,68 @@ pub fn main_character_inputs(
                 agent.turning = Turning::Left(dot_dirs.abs());
             }
 
-            agent.acc = Acceleration::Forward;
+            agent.acc = Acceleration::Forward(1.0);
             // println!("target angle: {:?}", dot_dirs);
         }
     }
 }
 
+// The player's actions drive the main character, unless a replay is playing
+fn read_controls(
+    agent: &mut Agent,
+    actions: &Actions,
+    now: f32,
+    move_params: &MovementParams,
+    cursor: &Cursor,
+) {
+    if actions.pressed(Action::Reverse) {
+        agent.acc = Acceleration::Backward(1.0);
+        agent.main_char_target_pos = None;
+    } else if actions.pressed(Action::Thrust) {
+        agent.acc = Acceleration::Forward(1.0);
+        agent.main_char_target_pos = None;
+    } else if actions.thrust < 0.0 {
+        // the triggers thrust proportionally
+        agent.acc = Acceleration::Backward(-actions.thrust);
+        agent.main_char_target_pos = None;
+    } else if actions.thrust > 0.0 {
+        agent.acc = Acceleration::Forward(actions.thrust);
+        agent.main_char_target_pos = None;
+    } else {
+        agent.acc = Acceleration::None;
+    }
+
+    if actions.pressed(Action::TurnLeft) && !actions.pressed(Action::TurnRight) {
+        agent.turning = Turning::Left(1.0);
+        agent.main_char_target_pos = None;
+    } else if actions.pressed(Action::TurnRight) && !actions.pressed(Action::TurnLeft) {
+        agent.turning = Turning::Right(1.0);
+        agent.main_char_target_pos = None;
+    } else if actions.steer < 0.0 {
+        // the stick steers proportionally
+        agent.turning = Turning::Left(-actions.steer);
+        agent.main_char_target_pos = None;
+    } else if actions.steer > 0.0 {
+        agent.turning = Turning::Right(actions.steer);
+        agent.main_char_target_pos = None;
+    } else {
+        agent.turning = Turning::None;
+    }
+
+    if actions.pressed(Action::Boost) && now - agent.boost_time > move_params.time_between_boosts
+    // && agent.energy > 1.0
+    {
+        agent.boost = true;
+        agent.boost_time = now;
+        // agent.energy -= 1.0;
+        agent.main_char_target_pos = None;
+    }
+
+    if actions.pressed(Action::SteerToCursor) {
+        agent.main_char_target_pos = Some(cursor.position);
+    }
+}
+
 #[derive(Component)]
 pub struct DebugQuad;
 
@@ -1158,11 +1237,11 @@ pub fn agent_movement_debug(
         let mut turn_angle = 0.0;
 
         match agent.acc {
-            Acceleration::Forward => {
-                acc = forward;
+            Acceleration::Forward(amount) => {
+                acc = forward * amount;
             }
-            Acceleration::Backward => {
-                acc = -forward;
+            Acceleration::Backward(amount) => {
+                acc = -forward * amount;
             }
             Acceleration::None => {}
         }
@@ -1198,17 +1277,22 @@ pub fn main_char_movement(
     // mut commands: Commands,
     // mut query_debug: Query<Entity, With<DebugQuad>>,
     mut game: ResMut<Game>,
-    time: Res<Time>,
+    time: Res<WorldTime>,
     mut query: Query<(&mut Transform, &MainCharacter), Without<Cam>>,
-
-    mut cam_query: Query<&mut Transform, With<Cam>>,
     move_params: Res<MovementParams>,
+    level: Res<Level>,
+    currents: Res<CurrentField>,
 ) {
     // let mut rng = rand::thread_rng();
     // for (id, agent) in game.agents.iter_mut() {
     for (mut transform, main_char) in &mut query.iter_mut() {
         let mut agent = game.agents.get_mut(&main_char.id).unwrap();
 
+        // dead creatures don't move anymore
+        if !agent.alive {
+            continue;
+        }
+
         agent.compute_self_velocity();
 
         let timestep = time.delta_seconds() as f32;
@@ -1223,18 +1307,13 @@ pub fn main_char_movement(
 
         let friction1 = move_params.friction1;
         let friction2 = move_params.friction2;
-        let turning_speed_dependence = move_params.turning_speed_dependence;
         let backwards_mult = move_params.backwards_mult;
         let boost_mult = move_params.boost_mult + (agent.mass / 0.05);
-        let rest_turn_speed = move_params.rest_turn_speed;
-        let max_turn_speed = move_params.max_turn_speed;
-        let throttle = move_params.throttle;
+        let throttle = move_params.throttle * agent.thrust_mult();
         // let downcurrent = move_params.downcurrent ;
 
         let bottom_bounce = move_params.bottom_bounce;
 
-        let downcurrent = 0.1 + agent.position.y / LEVEL_HEIGHT * 3.0;
-
         let verlet_velocity = agent.position - agent.last_position;
         agent.speed = verlet_velocity.length();
 
@@ -1243,16 +1322,15 @@ pub fn main_char_movement(
         let mut new_position = agent.position;
 
         let mut acc = Vec2::ZERO;
-        let mut turn_angle = 0.0;
 
         let forward = agent.compute_look_at_dir();
 
         match agent.acc {
-            Acceleration::Forward => {
-                acc = forward * agent.energy;
+            Acceleration::Forward(amount) => {
+                acc = forward * amount * agent.energy.max(STARVING_THRUST);
             }
-            Acceleration::Backward => {
-                acc = -forward * backwards_mult;
+            Acceleration::Backward(amount) => {
+                acc = -forward * amount * backwards_mult;
             }
             Acceleration::None => {}
         }
@@ -1260,66 +1338,35 @@ pub fn main_char_movement(
         let mut boost_value = 0.0;
         if agent.boost {
             boost_value = boost_impulse(time.seconds_since_startup() as f32 - agent.boost_time);
-            acc = acc * (1.0 + boost_value * boost_mult * agent.energy);
+            acc = acc * (1.0 + boost_value * boost_mult * agent.energy.max(STARVING_THRUST));
         }
 
         // let (left, right) = agent.compute_left_and_right_dir();
 
         // apply turning
 
-        let soft_angular = 0.5;
-
-        match agent.turning {
-            Turning::Left(mut delta_angle) => {
-                if delta_angle < soft_angular {
-                    delta_angle = delta_angle / soft_angular;
-                } else {
-                    delta_angle = 1.0;
-                }
-                let speed_turn = agent.speed * turning_speed_dependence * (1.0 - boost_value);
-                turn_angle = delta_angle
-                    * (rest_turn_speed + speed_turn.clamp(0.0, max_turn_speed - rest_turn_speed));
-            }
-            Turning::Right(mut delta_angle) => {
-                if delta_angle < soft_angular {
-                    delta_angle = delta_angle / soft_angular;
-                } else {
-                    delta_angle = 1.0;
-                }
-                let speed_turn = agent.speed * turning_speed_dependence * (1.0 - boost_value);
-                turn_angle =
-                    -rest_turn_speed - speed_turn.clamp(0.0, max_turn_speed - rest_turn_speed);
-            }
-            Turning::None => {}
-        }
+        let turn_angle = move_params.turn_angle(&agent.turning, agent.speed, boost_value);
 
         let friction_force = -friction1 * verlet_velocity.length() * velocity_dir
             - friction2 * verlet_velocity.length().powf(2.0) * velocity_dir;
 
-        let downcurrent_force = downcurrent * Vec2::new(0.0, -1.0);
+        let current_force = currents.sample(agent.position) * agent.current_drag();
 
-        acc += friction_force + downcurrent_force;
+        acc += friction_force + current_force;
 
         new_position += verlet_velocity + acc * timestep * timestep * throttle;
 
-        // cannot fall below the ground
-        let bottom_most_pos = agent.radius;
-        if new_position.y < bottom_most_pos {
-            new_position.y = bottom_most_pos + bottom_bounce;
-        }
-
-        // bounce off the walls
+        // bounce off the floor and the walls
         // TODO: make wall_bounce part of the movement params
         let wall_bounce = 4.0;
-        let left_most_pos = agent.radius;
-        if new_position.x < left_most_pos {
-            new_position.x = left_most_pos + wall_bounce;
-        }
-
-        let right_most_pos = LEVEL_WIDTH - agent.radius;
-        if new_position.x > right_most_pos {
-            new_position.x = right_most_pos - wall_bounce;
-        }
+        new_position = sweep_level_bounds(
+            agent.position,
+            new_position,
+            agent.radius,
+            level.width,
+            wall_bounce,
+            bottom_bounce,
+        );
 
         agent.speed = (new_position - agent.position).length();
 
@@ -1332,11 +1379,6 @@ pub fn main_char_movement(
         transform.translation = agent.position.extend(MAIN_CHARA_Z);
 
         transform.rotation = Quat::from_rotation_z(agent.look_at_angle);
-
-        // TODO: smooth out the camera
-        let mut cam_transform = cam_query.single_mut();
-        cam_transform.translation.x = agent.position.x;
-        cam_transform.translation.y = agent.position.y;
     }
 }
 
@@ -1344,19 +1386,32 @@ pub fn agents_movement(
     // mut commands: Commands,
     // mut query_debug: Query<Entity, With<DebugQuad>>,
     mut game: ResMut<Game>,
-    time: Res<Time>,
+    time: Res<WorldTime>,
     mut query: Query<(&mut Transform, &AgentId), (With<NPC>, Without<Cam>)>,
 
     // mut cam_query: Query<&mut Transform, With<Cam>>,
     move_params: Res<MovementParams>,
+    level: Res<Level>,
+    currents: Res<CurrentField>,
 ) {
     // let mut rng = rand::thread_rng();
     // for (id, agent) in game.agents.iter_mut() {
     for (mut transform, agent_id) in &mut query.iter_mut() {
-        let mut agent = game.agents.get_mut(&agent_id.kdtree_hash).unwrap();
+        // reaped this frame, the entity is despawned at the end of the stage
+        let agent = match game.agents.get_mut(&agent_id.kdtree_hash) {
+            Some(agent) => agent,
+            None => continue,
+        };
+
+        // dead creatures don't move anymore
+        if !agent.alive {
+            continue;
+        }
 
         // if let Some(pos) = agent.target_position {
-        let target_dir = agent.target_position - agent.position;
+        // the navigation layer steers around the rocks and the crowd
+        let steer_position = agent.nav.steer_position.unwrap_or(agent.target_position);
+        let target_dir = steer_position - agent.position;
         if target_dir != Vec2::ZERO {
             // let target_angle = target_dir.y.atan2(target_dir.x);
             let look_at_dir = agent.compute_look_at_dir();
@@ -1374,7 +1429,7 @@ pub fn agents_movement(
                 agent.turning = Turning::Left(dot_dirs.abs());
             }
 
-            agent.acc = Acceleration::Forward;
+            agent.acc = Acceleration::Forward(1.0);
             // println!("target angle: {:?}", dot_dirs);
         }
         // }
@@ -1393,13 +1448,10 @@ pub fn agents_movement(
 
         let friction1 = move_params.friction1;
         let friction2 = move_params.friction2;
-        let turning_speed_dependence = move_params.turning_speed_dependence;
         let backwards_mult = move_params.backwards_mult;
         let boost_mult = move_params.boost_mult;
-        let rest_turn_speed = move_params.rest_turn_speed;
-        let max_turn_speed = move_params.max_turn_speed;
 
-        let mut throttle = move_params.throttle;
+        let mut throttle = move_params.throttle * agent.thrust_mult();
 
         if agent.is_guardian {
             throttle *= 4.0;
@@ -1415,16 +1467,15 @@ pub fn agents_movement(
         let mut new_position = agent.position;
 
         let mut acc = Vec2::ZERO;
-        let mut turn_angle = 0.0;
 
         let forward = agent.compute_look_at_dir();
 
         match agent.acc {
-            Acceleration::Forward => {
-                acc = forward;
+            Acceleration::Forward(amount) => {
+                acc = forward * amount;
             }
-            Acceleration::Backward => {
-                acc = -forward * backwards_mult;
+            Acceleration::Backward(amount) => {
+                acc = -forward * amount * backwards_mult;
             }
             Acceleration::None => {}
         }
@@ -1439,60 +1490,28 @@ pub fn agents_movement(
 
         // apply turning
 
-        let soft_angular = 0.5;
-
-        match agent.turning {
-            Turning::Left(mut delta_angle) => {
-                if delta_angle < soft_angular {
-                    delta_angle = delta_angle / soft_angular;
-                } else {
-                    delta_angle = 1.0;
-                }
-                let speed_turn = agent.speed * turning_speed_dependence * (1.0 - boost_value);
-                turn_angle = delta_angle
-                    * (rest_turn_speed + speed_turn.clamp(0.0, max_turn_speed - rest_turn_speed));
-            }
-            Turning::Right(mut delta_angle) => {
-                if delta_angle < soft_angular {
-                    delta_angle = delta_angle / soft_angular;
-                } else {
-                    delta_angle = 1.0;
-                }
-                let speed_turn = agent.speed * turning_speed_dependence * (1.0 - boost_value);
-                turn_angle =
-                    -rest_turn_speed - speed_turn.clamp(0.0, max_turn_speed - rest_turn_speed);
-            }
-            Turning::None => {}
-        }
+        let turn_angle = move_params.turn_angle(&agent.turning, agent.speed, boost_value);
 
         let friction_force = -friction1 * verlet_velocity.length() * velocity_dir
             - friction2 * verlet_velocity.length().powf(2.0) * velocity_dir;
 
-        // no downcurrent force for agents
-        let downcurrent_force = 0.0; // downcurrent * Vec2::new(0.0, -1.0);
+        let current_force = currents.sample(agent.position) * agent.current_drag();
 
-        acc += friction_force + downcurrent_force;
+        acc += friction_force + current_force;
 
         new_position += verlet_velocity + acc * timestep * timestep * throttle;
 
-        // cannot fall below the ground
-        let bottom_most_pos = agent.radius;
-        if new_position.y < bottom_most_pos {
-            new_position.y = bottom_most_pos + bottom_bounce;
-        }
-
-        // bounce off the walls
+        // bounce off the floor and the walls
         // TODO: make wall_bounce part of the movement params
         let wall_bounce = 4.0;
-        let left_most_pos = agent.radius;
-        if new_position.x < left_most_pos {
-            new_position.x = left_most_pos + wall_bounce;
-        }
-
-        let right_most_pos = LEVEL_WIDTH - agent.radius;
-        if new_position.x > right_most_pos {
-            new_position.x = right_most_pos - wall_bounce;
-        }
+        new_position = sweep_level_bounds(
+            agent.position,
+            new_position,
+            agent.radius,
+            level.width,
+            wall_bounce,
+            bottom_bounce,
+        );
 
         agent.speed = (new_position - agent.position).length();
 
@@ -1508,13 +1527,125 @@ pub fn agents_movement(
     }
 }
 
-pub fn boost_impulse(time: f32) -> f32 {
-    let rise_time = 0.7 * TOTAL_BOOST_TIME;
-    let fall_time = 0.3 * TOTAL_BOOST_TIME;
-    if time < rise_time {
-        return time / rise_time;
-    } else if time < TOTAL_BOOST_TIME {
-        return 1.0 - (time - rise_time) / fall_time;
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn save_file(level: &Level, game: Game) -> SaveFile {
+        let mut ore_deposits = OreDeposits::from_level(level);
+        if let Some(deposit) = ore_deposits.deposits.first_mut() {
+            deposit.remaining = 0;
+        }
+        let mut relations = TribeRelations::from_level(level);
+        relations.change_standing(Tribe::B, -0.7);
+
+        SaveFile {
+            version: SAVE_VERSION,
+            level: level.name.clone(),
+            saved_at: 12.5,
+            movement_params: MovementParams::stage1(),
+            progress: Progress {
+                checkpoint: Some(1),
+                checkpoint_mass: 0.3,
+                deaths: 2,
+                death_time: Some(11.0),
+                bumped: [4, 9].into_iter().collect(),
+                ..Default::default()
+            },
+            game,
+            population_timers: PopulationTimers {
+                bottom: 3.0,
+                mid: 7.0,
+                top: 0.0,
+            },
+            ore_remaining: ore_deposits.remaining(),
+            standing: relations.standings(),
+        }
+    }
+
+    #[test]
+    fn stick_turns_as_much_to_the_left_as_to_the_right() {
+        let params = MovementParams::stage1();
+        for deflection in [0.1, 0.3, 0.5, 1.0] {
+            for speed in [0.0, 0.5, 3.0] {
+                let left = params.turn_angle(&Turning::Left(deflection), speed, 0.0);
+                let right = params.turn_angle(&Turning::Right(deflection), speed, 0.0);
+                assert!(left > 0.0);
+                assert_eq!(left, -right, "deflection {}, speed {}", deflection, speed);
+            }
+        }
+
+        // proportional below SOFT_TURNING
+        let half = params.turn_angle(&Turning::Right(SOFT_TURNING / 2.0), 1.0, 0.0);
+        let full = params.turn_angle(&Turning::Right(1.0), 1.0, 0.0);
+        assert!((half * 2.0 - full).abs() < 1e-6);
+        assert_eq!(params.turn_angle(&Turning::None, 1.0, 0.0), 0.0);
+    }
+
+    #[test]
+    fn save_file_round_trip() {
+        let level = Level::load_default().unwrap();
+        let game = Game::new(&level, &mut StdRng::seed_from_u64(7));
+        let save = save_file(&level, game);
+        let path = std::env::temp_dir().join("rise_above_save_round_trip.json");
+
+        write_save(&path, &save).unwrap();
+        let loaded = read_save::<SaveFile>(&path, SAVE_VERSION).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(loaded.level, level.name);
+        assert_eq!(loaded.saved_at, 12.5);
+        assert_eq!(loaded.progress.checkpoint, Some(1));
+        assert_eq!(loaded.progress.deaths, 2);
+        assert_eq!(loaded.progress.death_time, Some(11.0));
+        assert_eq!(loaded.progress.bumped, save.progress.bumped);
+        assert_eq!(loaded.population_timers.mid, 7.0);
+        assert_eq!(loaded.game.agents.len(), save.game.agents.len());
+        assert!(save
+            .game
+            .agents
+            .keys()
+            .all(|id| loaded.game.agents.contains_key(id)));
+        assert_eq!(loaded.game.foods, save.game.foods);
+
+        let ore_deposits = loaded.ore_deposits(&level);
+        assert_eq!(ore_deposits.remaining(), save.ore_remaining);
+        let relations = loaded.tribe_relations(&level);
+        assert_eq!(relations.standing[&Tribe::B], -0.7);
+        assert_eq!(relations.with_main_character(Tribe::B), Relation::Hostile);
+    }
+
+    #[test]
+    fn sanitize_drops_broken_npcs_and_food() {
+        let level = Level::load_default().unwrap();
+        let mut game = Game::new(&level, &mut StdRng::seed_from_u64(7));
+        let npc = *game.agents.keys().find(|id| **id != 1).unwrap();
+        let food = *game.foods.keys().next().unwrap();
+        game.agents.get_mut(&npc).unwrap().velocity.x = f32::NAN;
+        game.foods.get_mut(&food).unwrap().position.y = f32::INFINITY;
+        // the main character is kept, its save fails instead
+        let mut main_character = game.agents[&npc].clone();
+        main_character.id = 1;
+        game.agents.insert(1, main_character);
+
+        assert_eq!(game.sanitize(), 2);
+        assert!(!game.agents.contains_key(&npc));
+        assert!(!game.foods.contains_key(&food));
+        assert!(game.agents.contains_key(&1));
+    }
+
+    #[test]
+    fn unreadable_save_is_not_written() {
+        let level = Level::load_default().unwrap();
+        let mut game = Game::new(&level, &mut StdRng::seed_from_u64(7));
+        let npc = *game.agents.keys().next().unwrap();
+        game.agents.get_mut(&npc).unwrap().position.x = f32::NAN;
+        let save = save_file(&level, game);
+        let path = std::env::temp_dir().join("rise_above_save_unreadable.json");
+        let _ = std::fs::remove_file(&path);
+
+        let result = write_save(&path, &save);
+        assert!(matches!(result, Err(SaveError::Unreadable(_))));
+        assert!(!path.exists());
     }
-    return 0.0;
 }
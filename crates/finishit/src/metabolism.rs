@@ -0,0 +1,206 @@
+use bevy::prelude::*;
+
+use rand::prelude::*;
+
+use kdtree::distance::squared_euclidean;
+
+use crate::agent::*;
+use crate::level::*;
+use crate::population::*;
+use crate::util::*;
+
+pub struct FoodEatenEvent {
+    pub agent_id: u32,
+    pub position: Vec2,
+}
+
+// Energy spent per second by an agent of STARTING_MASS with a power_usage of 1.0
+pub const METABOLISM_BASE_COST: f32 = 0.004;
+pub const METABOLISM_THRUST_COST: f32 = 0.01;
+pub const METABOLISM_BOOST_COST: f32 = 0.25;
+pub const METABOLISM_TURN_COST: f32 = 0.004;
+
+// Energy gained per unit of food energy
+pub const FOOD_ENERGY_MULT: f32 = 5.0;
+// Number of foods that grow back every second, up to the total of the level's spawn tables
+pub const FOOD_REGROWTH_RATE: f32 = 2.0;
+
+// Fraction of its mass that a starving agent loses every second
+pub const STARVATION_MASS_RATE: f32 = 0.02;
+pub const STARVATION_MIN_MASS: f32 = 0.01;
+
+// A starving creature can still crawl towards food
+pub const STARVING_THRUST: f32 = 0.3;
+
+impl Agent {
+    /// Energy spent per second with the current inputs
+    pub fn metabolic_cost(&self, boost_value: f32) -> f32 {
+        let thrust = match self.acc {
+            Acceleration::Forward(amount) => amount,
+            Acceleration::Backward(amount) => 0.5 * amount,
+            Acceleration::None => 0.0,
+        };
+
+        let turn = match self.turning {
+            Turning::Left(delta_angle) | Turning::Right(delta_angle) => delta_angle.min(1.0),
+            Turning::None => 0.0,
+        };
+
+        self.power_usage
+            * (self.mass / STARTING_MASS)
+            * (METABOLISM_BASE_COST
+                + METABOLISM_THRUST_COST * thrust
+                + METABOLISM_BOOST_COST * boost_value
+                + METABOLISM_TURN_COST * turn)
+    }
+
+    pub fn is_starving(&self) -> bool {
+        self.energy <= 0.0
+    }
+}
+
+// Thrust, boost and turning burn energy. Once it runs out, the agent burns its own mass
+// instead, and dies when there is not enough of it left.
+pub fn metabolism(mut game: ResMut<Game>, time: Res<WorldTime>) {
+    let timestep = time.delta_seconds();
+    let now = time.seconds_since_startup() as f32;
+
+    for (_id, agent) in game.agents.iter_mut() {
+        if !agent.alive || agent.power_usage == 0.0 {
+            continue;
+        }
+
+        let mut boost_value = 0.0;
+        if agent.boost {
+            boost_value = boost_impulse(now - agent.boost_time);
+        }
+
+        agent.energy -= agent.metabolic_cost(boost_value) * timestep;
+
+        if agent.is_starving() {
+            agent.energy = 0.0;
+            agent.mass -= agent.mass * STARVATION_MASS_RATE * timestep;
+            agent.update_mass_properties();
+
+            if agent.mass < STARVATION_MIN_MASS {
+                agent.die(DeathCause::Starvation);
+            }
+        }
+    }
+}
+
+// Agents eat every food that their body touches
+pub fn eat_food(
+    mut commands: Commands,
+    mut game: ResMut<Game>,
+    mut kdtrees: ResMut<KdTrees>,
+    food_query: Query<(Entity, &FoodComp)>,
+    mut eaten_events: EventWriter<FoodEatenEvent>,
+) {
+    let mut eaten_foods: Vec<u32> = Vec::new();
+
+    let Game { agents, foods, .. } = game.as_mut();
+
+    for (_id, agent) in agents.iter_mut() {
+        // guardians don't eat
+        if !agent.alive || agent.power_usage == 0.0 {
+            continue;
+        }
+
+        if let Ok(dist_id_array) = kdtrees.food_kdtree.within(
+            &[agent.position.x, agent.position.y],
+            agent.radius * agent.radius,
+            &squared_euclidean,
+        ) {
+            for (_dist, food_id) in dist_id_array {
+                if let Some(food) = foods.remove(food_id) {
+                    agent.energy += food.energy * FOOD_ENERGY_MULT;
+                    eaten_foods.push(food.id);
+                    eaten_events.send(FoodEatenEvent {
+                        agent_id: agent.id,
+                        position: food.position,
+                    });
+                }
+            }
+        }
+    }
+
+    if eaten_foods.is_empty() {
+        return;
+    }
+
+    for (_id, agent) in agents.iter_mut() {
+        for food_id in eaten_foods.iter() {
+            agent.sensors.food_sight.remove(food_id);
+        }
+    }
+
+    for (entity, food_comp) in food_query.iter() {
+        if eaten_foods.contains(&food_comp.id) {
+            commands.entity(entity).despawn();
+        }
+    }
+
+    kdtrees.gen_food_kdtree(&game.foods);
+}
+
+// Food slowly grows back at random places of the level
+pub fn regrow_food(
+    mut commands: Commands,
+    mut game: ResMut<Game>,
+    mut kdtrees: ResMut<KdTrees>,
+    mut world_rng: ResMut<WorldRng>,
+    level: Res<Level>,
+    time: Res<WorldTime>,
+) {
+    if game.foods.len() >= level.total_foods() {
+        return;
+    }
+
+    let rng = &mut world_rng.rng;
+    if rng.gen::<f32>() > FOOD_REGROWTH_RATE * time.delta_seconds() {
+        return;
+    }
+
+    // food grows back where it is meant to be abundant
+    let stages = [GameStage::Bottom, GameStage::Mid, GameStage::Top];
+    let stage = match stages.choose_weighted(rng, |stage| level.spawn_table(stage).foods) {
+        Ok(stage) => stage,
+        Err(_) => return,
+    };
+
+    let food = Food::gen_random(&level, stage, rng);
+    spawn_food(&mut commands, &food);
+    game.foods.insert(food.id, food);
+
+    kdtrees.gen_food_kdtree(&game.foods);
+}
+
+pub fn spawn_food(commands: &mut Commands, food: &Food) -> Entity {
+    let mut rng = rand::thread_rng();
+
+    let color = Color::rgb(
+        rng.gen::<f32>() * 0.1,
+        rng.gen::<f32>() * 0.1,
+        rng.gen::<f32>() * 0.6,
+    );
+
+    // TODO: remove, only useful for testing
+    let food_size = Vec2::splat(MASS_MULT * food.mass.powf(0.5) * 0.1);
+
+    let food_trans = Transform::from_translation(food.position.extend(0.03));
+
+    commands
+        .spawn_bundle(SpriteBundle {
+            sprite: Sprite {
+                color,
+                custom_size: Some(food_size),
+
+                ..Default::default()
+            },
+            transform: food_trans,
+            ..Default::default()
+        })
+        .insert(FoodComp { id: food.id })
+        .id()
+}
@@ -0,0 +1,332 @@
+use bevy::{prelude::*, sprite::MaterialMesh2dBundle};
+
+use kdtree::distance::squared_euclidean;
+
+use crate::agent::*;
+use crate::cam::*;
+use crate::checkpoints::*;
+use crate::level::*;
+use crate::terrain::*;
+use crate::util::*;
+
+// Height of the minimap on screen, in pixels. Its width follows the shape of the level.
+pub const MINIMAP_HEIGHT: f32 = 240.0;
+// Distance to the corner of the window, in pixels
+pub const MINIMAP_MARGIN: f32 = 16.0;
+// In front of the world and of the state texts, relative to the camera
+pub const MINIMAP_Z: f32 = 80.0;
+
+// Creatures closer than this to the main character show up on the minimap
+pub const RADAR_RANGE: f32 = 2500.0;
+// Number of creatures that the minimap can show at once
+pub const MAX_BLIPS: usize = 64;
+pub const BLIP_SIZE: f32 = 4.0;
+
+/// Root of the minimap, a child of the camera so that it stays in the corner of the window
+#[derive(Component)]
+pub struct Minimap {
+    /// Minimap units per world unit
+    pub scale: f32,
+    pub size: Vec2,
+}
+
+#[derive(Component)]
+pub struct MinimapPlayer;
+
+/// One of the sprites that the creatures around the main character are drawn with
+#[derive(Component)]
+pub struct MinimapBlip(pub usize);
+
+#[derive(Component)]
+pub struct MinimapDepthText;
+
+impl Minimap {
+    pub fn from_level(level: &Level) -> Self {
+        let scale = MINIMAP_HEIGHT / level.height;
+        Self {
+            scale,
+            size: Vec2::new(level.width, level.height) * scale,
+        }
+    }
+
+    /// Position of a point of the world on the minimap, relative to its center
+    pub fn to_minimap(&self, position: Vec2) -> Vec2 {
+        position * self.scale - self.size / 2.0
+    }
+}
+
+pub struct MinimapPlugin;
+
+impl Plugin for MinimapPlugin {
+    fn build(&self, app: &mut App) {
+        // the camera is spawned by a startup system
+        app.add_startup_system_to_stage(StartupStage::PostStartup, spawn_minimap)
+            .add_system(place_minimap)
+            .add_system(update_minimap);
+    }
+}
+
+fn line_sprite(
+    minimap: &Minimap,
+    height: f32,
+    thickness: f32,
+    color: Color,
+    z: f32,
+) -> SpriteBundle {
+    SpriteBundle {
+        sprite: Sprite {
+            color,
+            custom_size: Some(Vec2::new(minimap.size.x, thickness)),
+            ..Default::default()
+        },
+        transform: Transform::from_translation(Vec3::new(
+            0.0,
+            minimap.to_minimap(Vec2::new(0.0, height)).y,
+            z,
+        )),
+        ..Default::default()
+    }
+}
+
+pub fn spawn_minimap(
+    mut commands: Commands,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<ColorMaterial>>,
+    asset_server: Res<AssetServer>,
+    level: Res<Level>,
+    terrain: Res<Terrain>,
+    cam_query: Query<Entity, With<Cam>>,
+) {
+    let cam_entity = cam_query.single();
+    let minimap = Minimap::from_level(&level);
+    let limits = &level.stage_limits;
+
+    let mut children = Vec::new();
+
+    // the ocean
+    children.push(
+        commands
+            .spawn_bundle(SpriteBundle {
+                sprite: Sprite {
+                    color: Color::rgba(0.05, 0.1, 0.25, 0.7),
+                    custom_size: Some(minimap.size),
+                    ..Default::default()
+                },
+                ..Default::default()
+            })
+            .id(),
+    );
+
+    // the rocks, with the meshes of the terrain shrunk down to the minimap
+    let rock_material = materials.add(ColorMaterial::from(Color::rgb(0.3, 0.22, 0.24)));
+    for obstacle in terrain.obstacles.iter() {
+        let mut transform = Transform::from_translation(minimap.to_minimap(Vec2::ZERO).extend(0.1));
+        transform.scale = Vec3::new(minimap.scale, minimap.scale, 1.0);
+
+        children.push(
+            commands
+                .spawn_bundle(MaterialMesh2dBundle {
+                    mesh: meshes.add(obstacle.to_mesh()).into(),
+                    material: rock_material.clone(),
+                    transform,
+                    ..Default::default()
+                })
+                .id(),
+        );
+    }
+
+    // the limits of the stages, and the surface that the main character has to reach
+    for height in [limits.bottom, limits.mid, limits.top] {
+        let sprite = line_sprite(
+            &minimap,
+            height * level.height,
+            1.0,
+            Color::rgba(1.0, 1.0, 1.0, 0.4),
+            0.2,
+        );
+        children.push(commands.spawn_bundle(sprite).id());
+    }
+    let sprite = line_sprite(&minimap, level.win_height, 2.0, Color::GOLD, 0.3);
+    children.push(commands.spawn_bundle(sprite).id());
+
+    for k in 0..MAX_BLIPS {
+        children.push(
+            commands
+                .spawn_bundle(SpriteBundle {
+                    sprite: Sprite {
+                        custom_size: Some(Vec2::splat(BLIP_SIZE)),
+                        ..Default::default()
+                    },
+                    visibility: Visibility { is_visible: false },
+                    transform: Transform::from_translation(Vec3::new(0.0, 0.0, 0.4)),
+                    ..Default::default()
+                })
+                .insert(MinimapBlip(k))
+                .id(),
+        );
+    }
+
+    children.push(
+        commands
+            .spawn_bundle(SpriteBundle {
+                sprite: Sprite {
+                    color: Color::WHITE,
+                    custom_size: Some(Vec2::splat(BLIP_SIZE * 1.5)),
+                    ..Default::default()
+                },
+                transform: Transform::from_translation(Vec3::new(0.0, 0.0, 0.5)),
+                ..Default::default()
+            })
+            .insert(MinimapPlayer)
+            .id(),
+    );
+
+    let text_style = TextStyle {
+        font: asset_server.load("fonts/Roboto-Regular.ttf"),
+        font_size: 16.0,
+        color: Color::WHITE,
+    };
+    let text_alignment = TextAlignment {
+        vertical: VerticalAlign::Top,
+        horizontal: HorizontalAlign::Center,
+    };
+    children.push(
+        commands
+            .spawn_bundle(Text2dBundle {
+                text: Text::with_section("", text_style, text_alignment),
+                transform: Transform::from_translation(Vec3::new(
+                    0.0,
+                    -minimap.size.y / 2.0 - 4.0,
+                    0.5,
+                )),
+                ..Default::default()
+            })
+            .insert(MinimapDepthText)
+            .id(),
+    );
+
+    let root = commands
+        .spawn_bundle((
+            Transform::from_translation(Vec3::new(0.0, 0.0, MINIMAP_Z)),
+            GlobalTransform::default(),
+        ))
+        .insert(minimap)
+        .push_children(&children)
+        .id();
+    commands.entity(cam_entity).push_children(&[root]);
+}
+
+// Keeps the minimap in the upper right corner of the window. The camera scale applies to
+// its children, so they are laid out in pixels of the window.
+pub fn place_minimap(windows: Res<Windows>, mut query: Query<(&Minimap, &mut Transform)>) {
+    let window = match windows.get_primary() {
+        Some(window) => window,
+        None => return,
+    };
+    let corner = Vec2::new(window.width(), window.height()) / 2.0;
+
+    for (minimap, mut transform) in query.iter_mut() {
+        let center = corner - Vec2::splat(MINIMAP_MARGIN) - minimap.size / 2.0;
+        transform.translation.x = center.x;
+        transform.translation.y = center.y;
+    }
+}
+
+// Guardians are a threat whatever their size. Other creatures are prey when they are lighter
+// than the main character, and can swallow it whole past SWALLOW_MASS_RATIO.
+fn threat_color(agent: &Agent, main_char_mass: f32) -> Color {
+    if agent.is_guardian || agent.mass > main_char_mass * SWALLOW_MASS_RATIO {
+        Color::RED
+    } else if agent.mass > main_char_mass {
+        Color::ORANGE
+    } else {
+        Color::GREEN
+    }
+}
+
+pub fn update_minimap(
+    mut game: ResMut<Game>,
+    kdtrees: Res<KdTrees>,
+    level: Res<Level>,
+    minimap_query: Query<&Minimap>,
+    mut player_query: Query<&mut Transform, With<MinimapPlayer>>,
+    mut blip_query: Query<
+        (&MinimapBlip, &mut Transform, &mut Sprite, &mut Visibility),
+        Without<MinimapPlayer>,
+    >,
+    mut text_query: Query<&mut Text, With<MinimapDepthText>>,
+) {
+    let minimap = match minimap_query.get_single() {
+        Ok(minimap) => minimap,
+        Err(_) => return,
+    };
+    let (position, mass) = match game.agents.get(&1) {
+        Some(agent) => (agent.position, agent.mass),
+        None => return,
+    };
+
+    let mut nearby = Vec::new();
+    if let Ok(close_agents) = kdtrees.agent_kdtree.within(
+        &[position.x, position.y],
+        RADAR_RANGE.powi(2),
+        &squared_euclidean,
+    ) {
+        for (_dist, id) in close_agents {
+            if *id != 1 {
+                nearby.push(*id);
+            }
+        }
+    }
+
+    // a squad is revealed for the rest of the run once one of its guardians is on the radar
+    for squad in game.guardian_squads.iter_mut() {
+        if !squad.revealed && squad.members.iter().any(|id| nearby.contains(id)) {
+            squad.revealed = true;
+        }
+    }
+    let mut shown = game
+        .guardian_squads
+        .iter()
+        .filter(|squad| squad.revealed)
+        .flat_map(|squad| squad.members.iter().copied())
+        .filter(|id| !nearby.contains(id))
+        .collect::<Vec<u32>>();
+    shown.extend(nearby);
+
+    let blips = shown
+        .iter()
+        .filter_map(|id| game.agents.get(id))
+        .filter(|agent| agent.alive)
+        .take(MAX_BLIPS)
+        .map(|agent| (agent.position, threat_color(agent, mass)))
+        .collect::<Vec<(Vec2, Color)>>();
+
+    for (blip, mut transform, mut sprite, mut visibility) in blip_query.iter_mut() {
+        match blips.get(blip.0) {
+            Some((position, color)) => {
+                let position = minimap.to_minimap(*position);
+                transform.translation.x = position.x;
+                transform.translation.y = position.y;
+                sprite.color = *color;
+                visibility.is_visible = true;
+            }
+            None => visibility.is_visible = false,
+        }
+    }
+
+    for mut transform in player_query.iter_mut() {
+        let position = minimap.to_minimap(position);
+        transform.translation.x = position.x;
+        transform.translation.y = position.y;
+    }
+
+    let stage = match level.stage_at(position.y) {
+        GameStage::Bottom => "bottom",
+        GameStage::Mid => "middle",
+        GameStage::Top => "top",
+    };
+    let to_surface = (level.win_height - position.y).max(0.0);
+    for mut text in text_query.iter_mut() {
+        text.sections[0].value = format!("{} stage, {:.0} below the surface", stage, to_surface);
+    }
+}
@@ -0,0 +1,226 @@
+use bevy::prelude::*;
+use bevy_kira_audio::{Audio, AudioChannel};
+use serde::{Deserialize, Serialize};
+
+use crate::guardians::*;
+use crate::level::*;
+use crate::states::*;
+use crate::util::*;
+
+// Duration of a crossfade between two tracks, in seconds
+pub const CROSSFADE_TIME: f32 = 2.0;
+
+// Guardians start to raise the tension from this distance to the main character
+pub const TENSION_RANGE: f32 = 1500.0;
+// Tension of a squad that is suspicious of the main character, and of one chasing it
+pub const SUSPICIOUS_TENSION: f32 = 0.5;
+pub const CHASE_TENSION: f32 = 1.0;
+// Rate of the exponential smoothing of the tension, per second
+pub const TENSION_RATE: f32 = 2.0;
+// Playback rate of the music at full tension. It plays at 1.0 without tension.
+pub const MAX_TENSION_PLAYBACK: f32 = 1.25;
+
+// The two channels that the music alternates between, so that the tracks can be crossfaded
+const MUSIC_CHANNELS: [&str; 2] = ["music_a", "music_b"];
+
+/// What the music is about at the moment
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum MusicCue {
+    Title,
+    Bottom,
+    Mid,
+    Top,
+    /// A guardian squad is chasing the main character
+    Chase,
+    Ending,
+}
+
+impl MusicCue {
+    /// Asset path of the track of the cue. Cues with the same track play on without a break.
+    pub fn track(&self) -> &'static str {
+        match self {
+            MusicCue::Title => "Rise Above_Intro.ogg",
+            MusicCue::Bottom | MusicCue::Mid | MusicCue::Ending => "Rise Above_Song.ogg",
+            MusicCue::Top | MusicCue::Chase => "Rise Above Action V1.ogg",
+        }
+    }
+}
+
+/// Picks the music from the state of the game, and crossfades between the tracks
+pub struct MusicDirector {
+    pub cue: Option<MusicCue>,
+    /// Asset path of the track that is fading in, or playing
+    pub track: Option<String>,
+    /// Index in MUSIC_CHANNELS of the channel of the track
+    pub channel: usize,
+    /// Start of the crossfade in progress
+    pub fade_start: Option<f32>,
+    /// Between 0 and 1, how close the guardians are to catching the main character
+    pub tension: f32,
+    pub playback_rate: f32,
+    /// Set by the steps of the ending, which plays MusicCue::Ending otherwise
+    pub ending_cue: Option<MusicCue>,
+}
+
+impl Default for MusicDirector {
+    fn default() -> Self {
+        Self {
+            cue: None,
+            track: None,
+            channel: 0,
+            fade_start: None,
+            tension: 0.0,
+            playback_rate: 1.0,
+            ending_cue: None,
+        }
+    }
+}
+
+pub fn music_channel(index: usize) -> AudioChannel {
+    AudioChannel::new(MUSIC_CHANNELS[index].to_string())
+}
+
+pub fn pause_music(audio: &Audio) {
+    for index in 0..MUSIC_CHANNELS.len() {
+        audio.pause_channel(&music_channel(index));
+    }
+}
+
+pub fn resume_music(audio: &Audio) {
+    for index in 0..MUSIC_CHANNELS.len() {
+        audio.resume_channel(&music_channel(index));
+    }
+}
+
+pub struct MusicPlugin;
+
+impl Plugin for MusicPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(MusicDirector::default())
+            .add_system(update_tension.label("update_tension"))
+            .add_system(direct_music.after("update_tension"));
+    }
+}
+
+// The cue of the running game: a chase overrides the stage that the main character is in
+fn game_cue(game: &Game, level: &Level) -> Option<MusicCue> {
+    let chased = game
+        .guardian_squads
+        .iter()
+        .any(|squad| squad.alert == AlertState::Chasing);
+    if chased {
+        return Some(MusicCue::Chase);
+    }
+
+    let agent = game.agents.get(&1)?;
+    Some(match level.stage_at(agent.position.y) {
+        GameStage::Bottom => MusicCue::Bottom,
+        GameStage::Mid => MusicCue::Mid,
+        GameStage::Top => MusicCue::Top,
+    })
+}
+
+// The tension rises as the guardians get closer and more alert, and speeds the music up
+pub fn update_tension(
+    mut director: ResMut<MusicDirector>,
+    game: Res<Game>,
+    app_state: Res<State<AppState>>,
+    time: Res<Time>,
+) {
+    match app_state.current() {
+        AppState::InGame => {}
+        // held until the game resumes
+        AppState::Paused => return,
+        _ => {
+            director.tension = 0.0;
+            return;
+        }
+    }
+    let main_char_position = match game.agents.get(&1) {
+        Some(agent) => agent.position,
+        None => return,
+    };
+
+    let mut tension: f32 = 0.0;
+    for squad in game.guardian_squads.iter() {
+        match squad.alert {
+            AlertState::Chasing => tension = tension.max(CHASE_TENSION),
+            AlertState::Suspicious => tension = tension.max(SUSPICIOUS_TENSION),
+            _ => {}
+        }
+
+        for agent in squad.members.iter().filter_map(|id| game.agents.get(id)) {
+            let distance = agent.position.distance(main_char_position);
+            tension = tension.max(1.0 - distance / TENSION_RANGE);
+        }
+    }
+
+    let smoothing = 1.0 - (-TENSION_RATE * time.delta_seconds()).exp();
+    director.tension += (tension.clamp(0.0, 1.0) - director.tension) * smoothing;
+}
+
+pub fn direct_music(
+    mut director: ResMut<MusicDirector>,
+    app_state: Res<State<AppState>>,
+    game: Res<Game>,
+    level: Res<Level>,
+    audio: Res<Audio>,
+    asset_server: Res<AssetServer>,
+    time: Res<Time>,
+) {
+    let now = time.seconds_since_startup() as f32;
+
+    // the paused game, the game over screen and the restart keep the music of the run
+    let cue = match app_state.current() {
+        AppState::Title => Some(MusicCue::Title),
+        AppState::InGame => game_cue(&game, &level),
+        AppState::Ending => Some(director.ending_cue.unwrap_or(MusicCue::Ending)),
+        AppState::Paused | AppState::GameOver | AppState::Restarting => director.cue,
+    };
+
+    if cue != director.cue {
+        director.cue = cue;
+
+        match cue.map(|cue| cue.track()) {
+            Some(track) if director.track.as_deref() != Some(track) => {
+                if director.fade_start.is_some() {
+                    // the track that was fading out is cut short
+                    audio.stop_channel(&music_channel(1 - director.channel));
+                }
+
+                director.channel = 1 - director.channel;
+                let channel = music_channel(director.channel);
+                audio.set_volume_in_channel(0.0, &channel);
+                audio.set_playback_rate_in_channel(director.playback_rate, &channel);
+                audio.play_looped_in_channel(asset_server.load(track), &channel);
+
+                director.track = Some(track.to_string());
+                director.fade_start = Some(now);
+            }
+            _ => {}
+        }
+    }
+
+    if let Some(fade_start) = director.fade_start {
+        let fade = ((now - fade_start) / CROSSFADE_TIME).clamp(0.0, 1.0);
+        let fading_in = music_channel(director.channel);
+        let fading_out = music_channel(1 - director.channel);
+
+        audio.set_volume_in_channel(fade, &fading_in);
+        if fade < 1.0 {
+            audio.set_volume_in_channel(1.0 - fade, &fading_out);
+        } else {
+            audio.stop_channel(&fading_out);
+            director.fade_start = None;
+        }
+    }
+
+    let playback_rate = 1.0 + director.tension * (MAX_TENSION_PLAYBACK - 1.0);
+    if (playback_rate - director.playback_rate).abs() > 0.01 {
+        director.playback_rate = playback_rate;
+        for index in 0..MUSIC_CHANNELS.len() {
+            audio.set_playback_rate_in_channel(playback_rate, &music_channel(index));
+        }
+    }
+}
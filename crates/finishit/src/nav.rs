@@ -0,0 +1,457 @@
+use bevy::{core::FloatOrd, prelude::*};
+
+use kdtree::distance::squared_euclidean;
+use serde::{Deserialize, Serialize};
+
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+
+use crate::agent::*;
+use crate::level::*;
+use crate::terrain::*;
+use crate::util::*;
+
+pub const NAV_CELL_SIZE: f32 = 100.0;
+// Room kept between the center of a creature and the rocks when planning
+pub const NAV_CLEARANCE: f32 = 40.0;
+
+// A* gives up after expanding this many cells, the agent then goes in a straight line
+pub const NAV_MAX_EXPANSIONS: usize = 5000;
+// Path planning is spread over several frames
+pub const NAV_MAX_PLANS_PER_FRAME: usize = 8;
+pub const NAV_REPLAN_INTERVAL: f32 = 2.0;
+// A path is planned again when its destination moved farther than this
+pub const NAV_REPLAN_DISTANCE: f32 = 300.0;
+
+// Distance, relative to the creature size, at which the other agents and the rocks
+// are avoided
+pub const AVOID_RANGE: f32 = 1.0;
+pub const AVOID_AGENTS_WEIGHT: f32 = 0.8;
+pub const AVOID_TERRAIN_WEIGHT: f32 = 1.5;
+
+/// The path an agent follows towards its target position
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct NavPath {
+    pub waypoints: Vec<Vec2>,
+    /// Target position that the waypoints lead to
+    pub destination: Option<Vec2>,
+    pub plan_time: f32,
+    /// Where the agent actually heads this frame, around rocks and crowds
+    pub steer_position: Option<Vec2>,
+}
+
+/// Coarse walkability grid of the level, for path planning
+pub struct NavGrid {
+    pub columns: usize,
+    pub rows: usize,
+    pub walkable: Vec<bool>,
+}
+
+impl NavGrid {
+    pub fn from_terrain(level: &Level, terrain: &Terrain) -> Self {
+        let columns = (level.width / NAV_CELL_SIZE).ceil() as usize;
+        let rows = (level.height / NAV_CELL_SIZE).ceil() as usize;
+
+        let mut walkable = Vec::with_capacity(columns * rows);
+        for row in 0..rows {
+            for column in 0..columns {
+                let center = Self::center_of(column, row);
+                walkable.push(terrain.contact(center, NAV_CLEARANCE).is_none());
+            }
+        }
+
+        Self {
+            columns,
+            rows,
+            walkable,
+        }
+    }
+
+    pub fn center_of(column: usize, row: usize) -> Vec2 {
+        (Vec2::new(column as f32, row as f32) + 0.5) * NAV_CELL_SIZE
+    }
+
+    pub fn cell_of(&self, position: Vec2) -> (usize, usize) {
+        let p = (position / NAV_CELL_SIZE).max(Vec2::ZERO);
+        (
+            (p.x as usize).min(self.columns - 1),
+            (p.y as usize).min(self.rows - 1),
+        )
+    }
+
+    pub fn is_walkable(&self, position: Vec2) -> bool {
+        let (column, row) = self.cell_of(position);
+        self.walkable[row * self.columns + column]
+    }
+
+    pub fn is_clear(&self, from: Vec2, to: Vec2) -> bool {
+        let steps = (from.distance(to) / (NAV_CELL_SIZE * 0.5)).ceil() as usize;
+        (1..=steps).all(|k| self.is_walkable(from.lerp(to, k as f32 / steps as f32)))
+    }
+
+    /// Closest walkable cell, searched in growing rings around `position`
+    pub fn nearest_walkable(&self, position: Vec2) -> Option<(usize, usize)> {
+        let (column, row) = self.cell_of(position);
+        let max_ring = self.columns.max(self.rows) as i64;
+
+        for ring in 0..max_ring {
+            let mut best: Option<((usize, usize), f32)> = None;
+            for dr in -ring..=ring {
+                for dc in -ring..=ring {
+                    if dr.abs() != ring && dc.abs() != ring {
+                        continue;
+                    }
+                    let (c, r) = (column as i64 + dc, row as i64 + dr);
+                    if c < 0 || r < 0 || c >= self.columns as i64 || r >= self.rows as i64 {
+                        continue;
+                    }
+                    let (c, r) = (c as usize, r as usize);
+                    if !self.walkable[r * self.columns + c] {
+                        continue;
+                    }
+                    let distance = Self::center_of(c, r).distance(position);
+                    if best.map_or(true, |(_, d)| distance < d) {
+                        best = Some(((c, r), distance));
+                    }
+                }
+            }
+            if let Some((cell, _)) = best {
+                return Some(cell);
+            }
+        }
+
+        None
+    }
+
+    /// A* over the 8-connected grid, followed by string pulling
+    pub fn find_path(&self, from: Vec2, to: Vec2) -> Option<Vec<Vec2>> {
+        let start = self.nearest_walkable(from)?;
+        let goal = self.nearest_walkable(to)?;
+        let index = |(column, row): (usize, usize)| row * self.columns + column;
+        let heuristic = |(column, row): (usize, usize)| {
+            Self::center_of(column, row).distance(Self::center_of(goal.0, goal.1))
+        };
+
+        let mut cost = vec![f32::MAX; self.columns * self.rows];
+        let mut came_from = vec![usize::MAX; self.columns * self.rows];
+        let mut open = BinaryHeap::new();
+
+        cost[index(start)] = 0.0;
+        open.push((Reverse(FloatOrd(heuristic(start))), index(start)));
+
+        let mut expansions = 0;
+        let mut found = false;
+
+        while let Some((_, current)) = open.pop() {
+            if current == index(goal) {
+                found = true;
+                break;
+            }
+
+            expansions += 1;
+            if expansions > NAV_MAX_EXPANSIONS {
+                return None;
+            }
+
+            let (column, row) = (current % self.columns, current / self.columns);
+            for (dc, dr) in [
+                (-1, 0),
+                (1, 0),
+                (0, -1),
+                (0, 1),
+                (-1, -1),
+                (1, -1),
+                (-1, 1),
+                (1, 1),
+            ] {
+                let (c, r) = (column as i64 + dc, row as i64 + dr);
+                if c < 0 || r < 0 || c >= self.columns as i64 || r >= self.rows as i64 {
+                    continue;
+                }
+                let (c, r) = (c as usize, r as usize);
+                if !self.walkable[r * self.columns + c] {
+                    continue;
+                }
+
+                // no cutting corners
+                if dc != 0
+                    && dr != 0
+                    && (!self.walkable[row * self.columns + c]
+                        || !self.walkable[r * self.columns + column])
+                {
+                    continue;
+                }
+
+                let step = if dc != 0 && dr != 0 {
+                    std::f32::consts::SQRT_2
+                } else {
+                    1.0
+                };
+                let new_cost = cost[current] + step * NAV_CELL_SIZE;
+                let next = r * self.columns + c;
+
+                if new_cost < cost[next] {
+                    cost[next] = new_cost;
+                    came_from[next] = current;
+                    open.push((Reverse(FloatOrd(new_cost + heuristic((c, r)))), next));
+                }
+            }
+        }
+
+        if !found {
+            return None;
+        }
+
+        let mut cells = vec![index(goal)];
+        while let Some(previous) = cells.last().map(|cell| came_from[*cell]) {
+            if previous == usize::MAX {
+                break;
+            }
+            cells.push(previous);
+        }
+        cells.reverse();
+
+        let mut waypoints = cells
+            .into_iter()
+            .map(|cell| Self::center_of(cell % self.columns, cell / self.columns))
+            .collect::<Vec<_>>();
+        if let Some(last) = waypoints.last_mut() {
+            if self.is_walkable(to) {
+                *last = to;
+            }
+        }
+
+        // only keep the corners
+        let mut pulled = Vec::new();
+        let mut anchor = from;
+        let mut k = 0;
+        while k < waypoints.len() {
+            let mut furthest = k;
+            while furthest + 1 < waypoints.len() && self.is_clear(anchor, waypoints[furthest + 1]) {
+                furthest += 1;
+            }
+            pulled.push(waypoints[furthest]);
+            anchor = waypoints[furthest];
+            k = furthest + 1;
+        }
+
+        Some(pulled)
+    }
+}
+
+impl Agent {
+    /// Id of the agent that self wants to bump into, if any. It is not avoided.
+    pub fn contact_target(&self) -> Option<u32> {
+        match self.goal {
+            Goal::GoToAgent(id) | Goal::Bully(id) => Some(id),
+            _ => None,
+        }
+    }
+}
+
+// Turns the target position of each NPC into a steering position: along a path around
+// the rocks, and away from the crowd
+pub fn navigate(
+    mut game: ResMut<Game>,
+    kdtrees: Res<KdTrees>,
+    nav_grid: Res<NavGrid>,
+    terrain: Res<Terrain>,
+    time: Res<WorldTime>,
+) {
+    let now = time.seconds_since_startup() as f32;
+    let mut plans = 0;
+
+    let neighbours = game
+        .agents
+        .iter()
+        .map(|(id, agent)| (*id, (agent.position, agent.mass)))
+        .collect::<std::collections::HashMap<_, _>>();
+
+    for (id, agent) in game.agents.iter_mut() {
+        if *id == 1 || !agent.alive {
+            continue;
+        }
+
+        let destination = agent.target_position;
+        let size = agent.mass * MASS_MULT;
+
+        ///// global path
+        let mut heading = destination;
+        if nav_grid.is_clear(agent.position, destination) {
+            agent.nav.waypoints.clear();
+            agent.nav.destination = None;
+        } else {
+            let stale = match agent.nav.destination {
+                Some(planned) => {
+                    planned.distance(destination) > NAV_REPLAN_DISTANCE
+                        || now - agent.nav.plan_time > NAV_REPLAN_INTERVAL
+                        // ran out of waypoints, or no path was found last time
+                        || (agent.nav.waypoints.is_empty()
+                            && now - agent.nav.plan_time > NAV_REPLAN_INTERVAL / 4.0)
+                }
+                None => true,
+            };
+
+            if stale && plans < NAV_MAX_PLANS_PER_FRAME {
+                plans += 1;
+                agent.nav.waypoints = nav_grid
+                    .find_path(agent.position, destination)
+                    .unwrap_or_default();
+                agent.nav.destination = Some(destination);
+                agent.nav.plan_time = now;
+            }
+
+            // skip the waypoints that are reached, or that can be cut
+            while let Some(waypoint) = agent.nav.waypoints.first().cloned() {
+                let reached = agent.position.distance(waypoint) < NAV_CELL_SIZE;
+                let can_cut = agent
+                    .nav
+                    .waypoints
+                    .get(1)
+                    .map_or(false, |next| nav_grid.is_clear(agent.position, *next));
+                if reached || can_cut {
+                    agent.nav.waypoints.remove(0);
+                } else {
+                    break;
+                }
+            }
+
+            if let Some(waypoint) = agent.nav.waypoints.first() {
+                heading = *waypoint;
+            }
+        }
+
+        let to_heading = heading - agent.position;
+        if to_heading == Vec2::ZERO {
+            agent.nav.steer_position = None;
+            continue;
+        }
+        let mut direction = to_heading.normalize();
+
+        ///// local avoidance of the crowd
+        let avoid_range = size * AVOID_RANGE;
+        let mut avoidance = Vec2::ZERO;
+        if let Ok(close_agents) = kdtrees.agent_kdtree.within(
+            &[agent.position.x, agent.position.y],
+            (avoid_range + size).powi(2),
+            &squared_euclidean,
+        ) {
+            for (_dist, other_id) in close_agents {
+                if other_id == id || Some(*other_id) == agent.contact_target() {
+                    continue;
+                }
+                if let Some((other_position, other_mass)) = neighbours.get(other_id) {
+                    let away = agent.position - *other_position;
+                    let gap = away.length() - (size + other_mass * MASS_MULT) * 0.5;
+                    if away != Vec2::ZERO && gap < avoid_range {
+                        avoidance += away.normalize() * (1.0 - gap.max(0.0) / avoid_range);
+                    }
+                }
+            }
+        }
+        direction += avoidance * AVOID_AGENTS_WEIGHT;
+
+        ///// local avoidance of the rocks
+        if let Some((depth, normal)) = terrain.contact(agent.position, size * 0.5 + avoid_range) {
+            let closeness = (depth / avoid_range).min(1.0);
+            // only push away when heading into the rock, so that the agent can slide along it
+            if direction.dot(normal) < 0.0 {
+                direction += normal * closeness * AVOID_TERRAIN_WEIGHT;
+            }
+        }
+
+        let direction = direction.normalize_or_zero();
+        agent.nav.steer_position = if direction == Vec2::ZERO {
+            Some(heading)
+        } else {
+            Some(agent.position + direction * NAV_CELL_SIZE)
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // '#' for the rocks, row 0 first
+    fn grid(rows: &[&str]) -> NavGrid {
+        NavGrid {
+            columns: rows[0].len(),
+            rows: rows.len(),
+            walkable: rows
+                .iter()
+                .flat_map(|row| row.chars().map(|cell| cell != '#'))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn path_goes_around_a_rock() {
+        let grid = grid(&[
+            "...#...", //
+            "...#...", "...#...", "...#...", ".......",
+        ]);
+        let from = NavGrid::center_of(0, 0);
+        let to = NavGrid::center_of(6, 0);
+
+        let path = grid.find_path(from, to).unwrap();
+        assert_eq!(path.last(), Some(&to));
+        assert!(path.iter().any(|waypoint| grid.cell_of(*waypoint).1 == 4));
+
+        let mut anchor = from;
+        for waypoint in path {
+            assert!(
+                grid.is_clear(anchor, waypoint),
+                "{} to {}",
+                anchor,
+                waypoint
+            );
+            anchor = waypoint;
+        }
+    }
+
+    #[test]
+    fn unreachable_goal_has_no_path() {
+        let grid = grid(&[
+            "...#...", //
+            "...#...", "...#...",
+        ]);
+        let path = grid.find_path(NavGrid::center_of(0, 1), NavGrid::center_of(6, 1));
+        assert_eq!(path, None);
+    }
+
+    #[test]
+    fn nearest_walkable_from_inside_a_rock() {
+        let grid = grid(&[
+            ".....", //
+            ".###.", ".###.", ".###.", ".....",
+        ]);
+        assert_eq!(
+            grid.nearest_walkable(NavGrid::center_of(0, 0)),
+            Some((0, 0))
+        );
+
+        let inside = NavGrid::center_of(2, 2) + Vec2::new(30.0, 0.0);
+        assert_eq!(grid.nearest_walkable(inside), Some((4, 2)));
+
+        let solid = self::grid(&["###", "###"]);
+        assert_eq!(solid.nearest_walkable(NavGrid::center_of(1, 1)), None);
+    }
+
+    #[test]
+    fn string_pulling_keeps_only_the_corners() {
+        let open = grid(&["........"]);
+        let to = NavGrid::center_of(7, 0);
+        assert_eq!(open.find_path(NavGrid::center_of(0, 0), to), Some(vec![to]));
+
+        let corridor = grid(&[
+            ".....", //
+            "####.", "####.", "####.", "####.",
+        ]);
+        let to = NavGrid::center_of(4, 4);
+        assert_eq!(
+            corridor.find_path(NavGrid::center_of(0, 0), to),
+            Some(vec![NavGrid::center_of(4, 0), to])
+        );
+    }
+}
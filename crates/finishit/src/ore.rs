@@ -0,0 +1,443 @@
+use bevy::prelude::*;
+
+use rand::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use strum::IntoEnumIterator;
+use strum_macros::EnumIter;
+
+use crate::agent::*;
+use crate::hud::*;
+use crate::inputs::*;
+use crate::level::*;
+use crate::terrain::*;
+use crate::util::*;
+
+// Size of a deposit, a creature mines it when its body touches it
+pub const ORE_DEPOSIT_RADIUS: f32 = 40.0;
+pub const ORE_Z: f32 = 0.04;
+
+// A creature takes one unit of ore from a deposit every MINING_INTERVAL seconds
+pub const MINING_INTERVAL: f32 = 0.5;
+// Probability per second that one unit of ore grows back in a mined deposit
+pub const ORE_REGROWTH_RATE: f32 = 0.2;
+
+pub const MAX_UPGRADE_LEVEL: u32 = 5;
+// Throttle gained, and damage taken off, per level of upgrade
+pub const THRUST_PER_LEVEL: f32 = 0.15;
+pub const ARMOR_PER_LEVEL: f32 = 0.25;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash, EnumIter)]
+#[serde(rename_all = "snake_case")]
+pub enum OreKind {
+    Iron,
+    Copper,
+    Coal,
+    Petrol,
+}
+
+impl OreKind {
+    pub fn color(&self) -> Color {
+        match self {
+            OreKind::Iron => Color::rgb(0.55, 0.35, 0.3),
+            OreKind::Copper => Color::rgb(0.85, 0.5, 0.2),
+            OreKind::Coal => Color::rgb(0.12, 0.12, 0.14),
+            OreKind::Petrol => Color::rgb(0.3, 0.1, 0.4),
+        }
+    }
+}
+
+/// A vein of ore against the rocks
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct OreDeposit {
+    pub kind: OreKind,
+    pub position: Vec2,
+    /// Units of ore in the deposit when it is full
+    pub amount: u32,
+    #[serde(skip)]
+    pub remaining: u32,
+}
+
+/// The deposits of the level and what is left in them. Deposits are identified by
+/// their index.
+pub struct OreDeposits {
+    pub deposits: Vec<OreDeposit>,
+}
+
+impl OreDeposits {
+    pub fn from_level(level: &Level) -> Self {
+        let deposits = level
+            .ore_deposits
+            .iter()
+            .map(|deposit| OreDeposit {
+                remaining: deposit.amount,
+                ..deposit.clone()
+            })
+            .collect();
+
+        Self { deposits }
+    }
+
+    /// What is left in each deposit, by index
+    pub fn remaining(&self) -> Vec<u32> {
+        self.deposits
+            .iter()
+            .map(|deposit| deposit.remaining)
+            .collect()
+    }
+}
+
+/// Traits that can be improved by spending ores
+#[derive(Clone, Copy, Debug, PartialEq, EnumIter)]
+pub enum Upgrade {
+    Thrust,
+    Armor,
+}
+
+impl Upgrade {
+    /// Ores needed to go from `level` to the next level
+    pub fn cost(&self, level: u32) -> Vec<(OreKind, u32)> {
+        let n = level + 1;
+        match self {
+            Upgrade::Thrust => vec![(OreKind::Petrol, 2 * n), (OreKind::Copper, 3 * n)],
+            Upgrade::Armor => vec![(OreKind::Iron, 3 * n), (OreKind::Coal, 2 * n)],
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct Upgrades {
+    pub thrust: u32,
+    pub armor: u32,
+}
+
+impl Upgrades {
+    pub fn level(&self, upgrade: Upgrade) -> u32 {
+        match upgrade {
+            Upgrade::Thrust => self.thrust,
+            Upgrade::Armor => self.armor,
+        }
+    }
+}
+
+/// Ores carried by a creature
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct Inventory {
+    pub ores: HashMap<OreKind, u32>,
+    pub last_mining_time: f32,
+}
+
+impl Inventory {
+    pub fn count(&self, kind: OreKind) -> u32 {
+        self.ores.get(&kind).cloned().unwrap_or(0)
+    }
+
+    pub fn has(&self, cost: &[(OreKind, u32)]) -> bool {
+        cost.iter().all(|(kind, count)| self.count(*kind) >= *count)
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct OreSight {
+    pub time_of_last_sight: f32,
+    pub distance: f32,
+    pub id: u32,
+    pub kind: OreKind,
+    pub position: Vec2,
+}
+
+impl Agent {
+    /// Multiplies the throttle
+    pub fn thrust_mult(&self) -> f32 {
+        1.0 + THRUST_PER_LEVEL * self.upgrades.thrust as f32
+    }
+
+    /// Multiplies the damage taken
+    pub fn armor_mult(&self) -> f32 {
+        1.0 / (1.0 + ARMOR_PER_LEVEL * self.upgrades.armor as f32)
+    }
+
+    pub fn can_upgrade(&self, upgrade: Upgrade) -> bool {
+        let level = self.upgrades.level(upgrade);
+        level < MAX_UPGRADE_LEVEL && self.inventory.has(&upgrade.cost(level))
+    }
+
+    /// Spends the ores for the next level of `upgrade`. Returns false if self cannot
+    /// afford it.
+    pub fn buy_upgrade(&mut self, upgrade: Upgrade) -> bool {
+        if !self.can_upgrade(upgrade) {
+            return false;
+        }
+
+        for (kind, count) in upgrade.cost(self.upgrades.level(upgrade)) {
+            if let Some(carried) = self.inventory.ores.get_mut(&kind) {
+                *carried -= count;
+            }
+        }
+
+        match upgrade {
+            Upgrade::Thrust => self.upgrades.thrust += 1,
+            Upgrade::Armor => self.upgrades.armor += 1,
+        }
+        true
+    }
+
+    pub fn forget_ores(&mut self, time: f32) {
+        let memory_time = self.memory_time;
+        self.sensors
+            .ore_sight
+            .retain(|_id, sight_data| time - sight_data.time_of_last_sight <= memory_time);
+    }
+}
+
+#[derive(Component)]
+pub struct OreDepositComp {
+    pub id: u32,
+}
+
+pub fn spawn_ore_deposits(mut commands: Commands, ore_deposits: Res<OreDeposits>) {
+    for (id, deposit) in ore_deposits.deposits.iter().enumerate() {
+        commands
+            .spawn_bundle(SpriteBundle {
+                sprite: Sprite {
+                    color: deposit.kind.color(),
+                    custom_size: Some(Vec2::splat(ORE_DEPOSIT_RADIUS * 2.0)),
+                    ..Default::default()
+                },
+                transform: Transform::from_translation(deposit.position.extend(ORE_Z))
+                    .with_rotation(Quat::from_rotation_z(std::f32::consts::FRAC_PI_4)),
+                ..Default::default()
+            })
+            .insert(OreDepositComp { id: id as u32 });
+    }
+}
+
+// NPCs notice the deposits in sight that still have some ore
+pub fn see_ores(
+    mut game: ResMut<Game>,
+    ore_deposits: Res<OreDeposits>,
+    terrain: Res<Terrain>,
+    time: Res<WorldTime>,
+) {
+    let now = time.seconds_since_startup() as f32;
+
+    for (id, agent) in game.agents.iter_mut() {
+        if *id == 1 || !agent.alive || agent.is_guardian {
+            continue;
+        }
+
+        for (deposit_id, deposit) in ore_deposits.deposits.iter().enumerate() {
+            let distance = agent.position.distance(deposit.position);
+            if deposit.remaining == 0
+                || distance > agent.sensors.sight_range
+                || !terrain.line_of_sight(agent.position, deposit.position)
+            {
+                continue;
+            }
+
+            agent.sensors.ore_sight.insert(
+                deposit_id as u32,
+                OreSight {
+                    time_of_last_sight: now,
+                    distance,
+                    id: deposit_id as u32,
+                    kind: deposit.kind,
+                    position: deposit.position,
+                },
+            );
+        }
+    }
+}
+
+// Creatures mine the deposits that their body touches
+pub fn collect_ore(
+    mut game: ResMut<Game>,
+    mut ore_deposits: ResMut<OreDeposits>,
+    time: Res<WorldTime>,
+    mut notifications: EventWriter<HudNotification>,
+) {
+    let now = time.seconds_since_startup() as f32;
+    let mut exhausted: Vec<u32> = Vec::new();
+
+    for (_id, agent) in game.agents.iter_mut() {
+        // guardians don't mine
+        if !agent.alive || agent.is_guardian {
+            continue;
+        }
+        if now - agent.inventory.last_mining_time < MINING_INTERVAL {
+            continue;
+        }
+
+        for (deposit_id, deposit) in ore_deposits.deposits.iter_mut().enumerate() {
+            if deposit.remaining == 0
+                || agent.position.distance(deposit.position) > agent.radius + ORE_DEPOSIT_RADIUS
+            {
+                continue;
+            }
+
+            deposit.remaining -= 1;
+            *agent.inventory.ores.entry(deposit.kind).or_insert(0) += 1;
+            agent.inventory.last_mining_time = now;
+
+            if agent.id == 1 {
+                // the same text while mining, so it stays on screen as a single line
+                notifications.send(HudNotification(format!("Mining {:?}", deposit.kind)));
+            }
+            if deposit.remaining == 0 {
+                exhausted.push(deposit_id as u32);
+            }
+            break;
+        }
+    }
+
+    if exhausted.is_empty() {
+        return;
+    }
+
+    for (_id, agent) in game.agents.iter_mut() {
+        for deposit_id in exhausted.iter() {
+            agent.sensors.ore_sight.remove(deposit_id);
+            if let Goal::Ore(ore_sight) = &agent.goal {
+                if ore_sight.id == *deposit_id {
+                    agent.goal = Goal::None;
+                }
+            }
+        }
+    }
+}
+
+// Mined deposits slowly fill up again
+pub fn regrow_ore(
+    mut ore_deposits: ResMut<OreDeposits>,
+    mut world_rng: ResMut<WorldRng>,
+    time: Res<WorldTime>,
+) {
+    let rng = &mut world_rng.rng;
+    if rng.gen::<f32>() > ORE_REGROWTH_RATE * time.delta_seconds() {
+        return;
+    }
+
+    let mut depleted = ore_deposits
+        .deposits
+        .iter_mut()
+        .filter(|deposit| deposit.remaining < deposit.amount)
+        .collect::<Vec<_>>();
+
+    if let Some(deposit) = depleted.choose_mut(rng) {
+        deposit.remaining += 1;
+    }
+}
+
+// Deposits shrink as they are mined, and disappear once exhausted
+pub fn show_ore_deposits(
+    ore_deposits: Res<OreDeposits>,
+    mut query: Query<(&OreDepositComp, &mut Transform, &mut Visibility)>,
+) {
+    for (deposit_comp, mut transform, mut visibility) in query.iter_mut() {
+        if let Some(deposit) = ore_deposits.deposits.get(deposit_comp.id as usize) {
+            let fill = deposit.remaining as f32 / deposit.amount.max(1) as f32;
+            transform.scale = Vec3::splat(0.4 + 0.6 * fill);
+            visibility.is_visible = deposit.remaining > 0;
+        }
+    }
+}
+
+// The main character upgrades with the upgrade actions, NPCs as soon as they can afford it
+pub fn upgrade_traits(
+    mut game: ResMut<Game>,
+    actions: Res<Actions>,
+    mut world_rng: ResMut<WorldRng>,
+    mut notifications: EventWriter<HudNotification>,
+) {
+    let rng = &mut world_rng.rng;
+
+    for (id, agent) in game.agents.iter_mut() {
+        if !agent.alive {
+            continue;
+        }
+
+        if *id == 1 {
+            for (action, upgrade) in [
+                (Action::UpgradeThrust, Upgrade::Thrust),
+                (Action::UpgradeArmor, Upgrade::Armor),
+            ] {
+                if !actions.just_pressed(action) {
+                    continue;
+                }
+                let message = if agent.buy_upgrade(upgrade) {
+                    format!(
+                        "{:?} upgraded to level {}",
+                        upgrade,
+                        agent.upgrades.level(upgrade)
+                    )
+                } else {
+                    let cost = upgrade
+                        .cost(agent.upgrades.level(upgrade))
+                        .iter()
+                        .map(|(kind, amount)| format!("{} {:?}", amount, kind))
+                        .collect::<Vec<_>>();
+                    format!("{:?} upgrade needs {}", upgrade, cost.join(", "))
+                };
+                notifications.send(HudNotification(message));
+            }
+            continue;
+        }
+
+        let affordable = Upgrade::iter()
+            .filter(|upgrade| agent.can_upgrade(*upgrade))
+            .collect::<Vec<_>>();
+        if let Some(upgrade) = affordable.choose(rng) {
+            agent.buy_upgrade(*upgrade);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn miner(ores: &[(OreKind, u32)]) -> Agent {
+        let mut agent = Agent::default();
+        agent.inventory.ores = ores.iter().cloned().collect();
+        agent
+    }
+
+    #[test]
+    fn buy_upgrade_spends_exactly_the_cost() {
+        let mut agent = miner(&[
+            (OreKind::Petrol, 5),
+            (OreKind::Copper, 3),
+            (OreKind::Iron, 1),
+        ]);
+
+        assert!(agent.buy_upgrade(Upgrade::Thrust));
+        assert_eq!(agent.upgrades.thrust, 1);
+        assert_eq!(agent.inventory.count(OreKind::Petrol), 3);
+        assert_eq!(agent.inventory.count(OreKind::Copper), 0);
+        assert_eq!(agent.inventory.count(OreKind::Iron), 1);
+    }
+
+    #[test]
+    fn buy_upgrade_is_refused_when_it_cannot_be_afforded() {
+        // the second level costs 4 petrol and 6 copper
+        let mut agent = miner(&[(OreKind::Petrol, 4), (OreKind::Copper, 5)]);
+        agent.upgrades.thrust = 1;
+
+        assert!(!agent.buy_upgrade(Upgrade::Thrust));
+        assert_eq!(agent.upgrades.thrust, 1);
+        assert_eq!(agent.inventory.count(OreKind::Petrol), 4);
+        assert_eq!(agent.inventory.count(OreKind::Copper), 5);
+
+        assert!(!agent.buy_upgrade(Upgrade::Armor));
+        assert_eq!(agent.upgrades.armor, 0);
+    }
+
+    #[test]
+    fn buy_upgrade_stops_at_the_max_level() {
+        let mut agent = miner(&[(OreKind::Iron, 1000), (OreKind::Coal, 1000)]);
+        agent.upgrades.armor = MAX_UPGRADE_LEVEL;
+
+        assert!(!agent.buy_upgrade(Upgrade::Armor));
+        assert_eq!(agent.inventory.count(OreKind::Iron), 1000);
+    }
+}
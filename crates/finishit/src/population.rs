@@ -0,0 +1,363 @@
+use bevy::{prelude::*, render::view::ComputedVisibility, sprite::Mesh2dHandle};
+
+use rand::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use crate::agent::*;
+use crate::level::*;
+use crate::softbody::*;
+use crate::util::*;
+use crate::*;
+
+// Minimum time between two respawns of the same stage
+pub const RESPAWN_INTERVAL: f32 = 0.5;
+
+// Agents don't pop into existence under the nose of the main character
+pub const RESPAWN_MIN_DISTANCE: f32 = 1500.0;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum DeathCause {
+    Starvation,
+    Wounds,
+    Swallowed,
+    Unknown,
+}
+
+pub struct AgentDeathEvent {
+    pub agent_id: u32,
+    pub position: Vec2,
+    pub mass: f32,
+    pub cause: DeathCause,
+}
+
+/// Number of living NPCs that the population manager keeps in each stage.
+/// Guardians and the main character are not counted.
+#[derive(Clone, Debug)]
+pub struct PopulationTargets {
+    pub bottom: usize,
+    pub mid: usize,
+    pub top: usize,
+}
+
+impl PopulationTargets {
+    pub fn from_level(level: &Level) -> Self {
+        Self {
+            bottom: level.spawns.bottom.agents,
+            mid: level.spawns.mid.agents,
+            top: level.spawns.top.agents,
+        }
+    }
+
+    pub fn target(&self, stage: &GameStage) -> usize {
+        match stage {
+            GameStage::Bottom => self.bottom,
+            GameStage::Mid => self.mid,
+            GameStage::Top => self.top,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct PopulationTimers {
+    pub bottom: f32,
+    pub mid: f32,
+    pub top: f32,
+}
+
+impl PopulationTimers {
+    pub fn last_respawn_mut(&mut self, stage: &GameStage) -> &mut f32 {
+        match stage {
+            GameStage::Bottom => &mut self.bottom,
+            GameStage::Mid => &mut self.mid,
+            GameStage::Top => &mut self.top,
+        }
+    }
+}
+
+/// The .cha files that creatures are built from, kept around for respawns and new runs
+pub struct CreatureTemplates {
+    pub main_character: CharacterSaveFormat,
+    /// By name, as in `load_creatures`
+    pub creatures: HashMap<String, CharacterSaveFormat>,
+    pub guardian: CharacterSaveFormat,
+}
+
+impl CreatureTemplates {
+    /// Template of an NPC. The ones that don't have a creature yet get a random one.
+    pub fn creature_of(&self, agent: &mut Agent, rng: &mut StdRng) -> &CharacterSaveFormat {
+        if agent.is_guardian {
+            agent.creature = "guardian".to_string();
+            return &self.guardian;
+        }
+
+        if !self.creatures.contains_key(&agent.creature) {
+            // sorted, the order of the map changes from one process to the next
+            let mut names = self.creatures.keys().collect::<Vec<_>>();
+            names.sort();
+            agent.creature = names.choose(rng).unwrap().to_string();
+        }
+        &self.creatures[&agent.creature]
+    }
+}
+
+impl Race {
+    pub fn stage(&self) -> GameStage {
+        match self {
+            Race::Bottom(_) => GameStage::Bottom,
+            Race::Mid(_) => GameStage::Mid,
+            Race::Top(_) => GameStage::Top,
+        }
+    }
+}
+
+impl Agent {
+    pub fn die(&mut self, cause: DeathCause) {
+        if self.alive {
+            self.alive = false;
+            self.death_cause = Some(cause);
+        }
+    }
+}
+
+pub fn spawn_agent(
+    commands: &mut Commands,
+    meshes: &mut ResMut<Assets<Mesh>>,
+    quad_position: Vec2,
+    quad_size: f32,
+    character_in_save_format: CharacterSaveFormat,
+    id: u32,
+    // character_parent: Entity,
+) -> Entity {
+    let mut instance_data_vec: MarkerInstanceMatData = character_in_save_format.into();
+    for (k, instance) in instance_data_vec.0.iter_mut().enumerate() {
+        instance.set_frequency(40.0, k);
+    }
+
+    // let quad_position = Vec2::ZERO;
+    let entity = commands
+        .spawn_bundle((
+            Mesh2dHandle(meshes.add(Mesh::from(shape::Quad {
+                size: Vec2::splat(quad_size),
+                flip: false,
+            }))),
+            GlobalTransform::default(),
+            Transform::from_translation(Vec3::new(quad_position.x, quad_position.y, 0.12)),
+            // Transform::from_translation(Vec3::new(0.0, 0.0, -0.12)),
+            Visibility::default(),
+            ComputedVisibility::default(),
+            instance_data_vec,
+            // NoFrustumCulling,
+        ))
+        .insert(AgentId { kdtree_hash: id })
+        // .insert(MainCharacter { id: 1 })
+        .insert(NPC)
+        .insert(InstanceDataNotEncoded::default())
+        .insert(CharacterUniform {
+            character_size: 0.1,
+            core_size: 1.0,
+            zoom: 1.0,
+            time: 0.0,
+            character_point_color: Vec4::new(0.0, 1.0, 0.0, 1.0),
+            color: Color::hex("8c114a").unwrap().into(),
+            quad_size,
+            inner_canvas_size_in_pixels: Vec2::new(300.0, 300.0),
+            // outer_border: plot.outer_border,
+            canvas_position: quad_position,
+            contour: 1.0,
+        })
+        .id();
+    // commands.entity(character_parent).push_children(&[entity]);
+    return entity;
+}
+
+/// Spawns the marker mesh of an NPC along with its atoms, and links them to the agent
+pub fn spawn_npc(
+    commands: &mut Commands,
+    meshes: &mut ResMut<Assets<Mesh>>,
+    agent: &mut Agent,
+    templates: &CreatureTemplates,
+    world_rng: &mut StdRng,
+) -> Entity {
+    let creature = templates.creature_of(agent, world_rng);
+
+    // only the looks, left out of the world rng
+    let mut rng = rand::thread_rng();
+    let color = Color::rgb(rng.gen::<f32>(), rng.gen::<f32>(), rng.gen::<f32>());
+
+    let parent_entity_npc = spawn_agent(
+        commands,
+        meshes,
+        agent.position,
+        MASS_MULT * agent.mass * 1.35,
+        creature.clone(),
+        agent.id,
+    );
+    agent.entity = Some(parent_entity_npc);
+
+    commands
+        .entity(parent_entity_npc)
+        .insert(SoftBody::from_character(
+            creature,
+            agent.mass,
+            agent.position,
+            agent.look_at_angle,
+        ));
+
+    let atom_size = Vec2::splat(ATOM_MULT * agent.mass * MASS_MULT);
+
+    agent.body = take_pos(creature.clone())
+        .iter()
+        .enumerate()
+        .map(|(_k, node)| Body {
+            atom_pos: *node * agent.mass,
+            rotation: Quat::from_rotation_z(agent.look_at_angle),
+            atom_size: atom_size.length(),
+            acceleration: Vec2::new(0.0, 0.0),
+            entity: None,
+            is_used: false,
+        })
+        .collect::<Vec<_>>();
+
+    take_pos(creature.clone())
+        .iter()
+        .enumerate()
+        .for_each(|(k, pos)| {
+            if pos.length() < PARKED_NODE_DISTANCE * MASS_MULT {
+                let transform = Transform::from_translation(pos.extend(4.0) * agent.mass);
+
+                let npc_child_id = commands
+                    .spawn_bundle(SpriteBundle {
+                        sprite: Sprite {
+                            color,
+                            custom_size: Some(atom_size),
+
+                            ..Default::default()
+                        },
+                        visibility: Visibility { is_visible: false },
+                        transform,
+                        ..Default::default()
+                    })
+                    .insert(Atom)
+                    .id();
+
+                agent.body[k].entity = Some(npc_child_id);
+                agent.body[k].is_used = true;
+
+                commands
+                    .entity(parent_entity_npc)
+                    .push_children(&[npc_child_id]);
+            }
+        });
+
+    parent_entity_npc
+}
+
+// Removes the dead agents from the game, the kdtree and the world.
+// The main character is never reaped here.
+pub fn reap_dead_agents(
+    mut commands: Commands,
+    mut game: ResMut<Game>,
+    mut kdtrees: ResMut<KdTrees>,
+    mut death_events: EventWriter<AgentDeathEvent>,
+) {
+    let dead_ids = game
+        .agents
+        .iter()
+        .filter(|(id, agent)| !agent.alive && **id != 1)
+        .map(|(id, _)| *id)
+        .collect::<Vec<u32>>();
+
+    if dead_ids.is_empty() {
+        return;
+    }
+
+    for id in dead_ids.iter() {
+        if let Some(agent) = game.agents.remove(id) {
+            // the atoms are children of the marker mesh, they go with it
+            if let Some(entity) = agent.entity {
+                commands.entity(entity).despawn_recursive();
+            }
+
+            death_events.send(AgentDeathEvent {
+                agent_id: agent.id,
+                position: agent.position,
+                mass: agent.mass,
+                cause: agent.death_cause.unwrap_or(DeathCause::Unknown),
+            });
+        }
+    }
+
+    // nobody remembers the dead
+    for (_id, agent) in game.agents.iter_mut() {
+        for id in dead_ids.iter() {
+            agent.sensors.agent_sight.remove(id);
+        }
+
+        match agent.goal {
+            Goal::GoToAgent(id) | Goal::Bully(id) if dead_ids.contains(&id) => {
+                agent.goal = Goal::None;
+            }
+            _ => {}
+        }
+    }
+
+    kdtrees.gen_agent_kdtree(&game.agents);
+}
+
+// Keeps the number of NPCs of each stage at its target, one respawn at a time
+pub fn respawn_population(
+    mut commands: Commands,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut game: ResMut<Game>,
+    mut timers: ResMut<PopulationTimers>,
+    targets: Res<PopulationTargets>,
+    templates: Res<CreatureTemplates>,
+    level: Res<Level>,
+    mut world_rng: ResMut<WorldRng>,
+    time: Res<WorldTime>,
+) {
+    let rng = &mut world_rng.rng;
+    let now = time.seconds_since_startup() as f32;
+
+    let main_char_position = game.agents.get(&1).map(|agent| agent.position);
+
+    for stage in [GameStage::Bottom, GameStage::Mid, GameStage::Top] {
+        let last_respawn = timers.last_respawn_mut(&stage);
+        if now - *last_respawn < RESPAWN_INTERVAL {
+            continue;
+        }
+
+        let population = game
+            .agents
+            .iter()
+            .filter(|(id, agent)| {
+                **id != 1 && agent.alive && !agent.is_guardian && agent.race.stage() == stage
+            })
+            .count();
+
+        if population >= targets.target(&stage) || templates.creatures.is_empty() {
+            continue;
+        }
+
+        let mut id: u32 = rng.gen();
+        // avoid accidentally duplicating the main character's id, or any other id
+        while id == 1 || game.agents.contains_key(&id) {
+            id = rng.gen();
+        }
+
+        let mut agent = Agent::gen_random(&stage, id, &level, rng);
+
+        if let Some(main_char_position) = main_char_position {
+            if agent.position.distance(main_char_position) < RESPAWN_MIN_DISTANCE {
+                // try again next frame
+                continue;
+            }
+        }
+
+        spawn_npc(&mut commands, &mut meshes, &mut agent, &templates, rng);
+
+        game.agents.insert(id, agent);
+        *last_respawn = now;
+    }
+}
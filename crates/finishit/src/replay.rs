@@ -0,0 +1,297 @@
+use bevy::prelude::*;
+
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+use crate::agent::*;
+use crate::hud::*;
+use crate::inputs::*;
+use crate::level::*;
+use crate::save::*;
+use crate::states::*;
+use crate::util::*;
+
+/// Bumped whenever the format of the replays changes
+pub const REPLAY_VERSION: u32 = 3;
+pub const REPLAY_PATH: &str = "replay.json";
+
+/// What the main character was told to do on one frame, before steering towards its
+/// target position
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ReplayFrame {
+    pub acc: Acceleration,
+    pub turning: Turning,
+    pub boost: bool,
+    pub target: Option<Vec2>,
+}
+
+impl ReplayFrame {
+    pub fn of(agent: &Agent, boost: bool) -> Self {
+        Self {
+            acc: agent.acc.clone(),
+            turning: agent.turning.clone(),
+            boost,
+            target: agent.main_char_target_pos,
+        }
+    }
+
+    pub fn apply(&self, agent: &mut Agent, now: f32) {
+        agent.acc = self.acc.clone();
+        agent.turning = self.turning.clone();
+        agent.main_char_target_pos = self.target;
+        if self.boost {
+            agent.boost = true;
+            agent.boost_time = now;
+        }
+    }
+}
+
+/// The inputs of one run, from its start. Runs of identical frames are stored once with
+/// their length, so holding a key costs nothing.
+///
+/// The run is played again from the same world seed, and every frame of the world lasts
+/// as long as the recorded one, so the NPCs and the food do what they did in the recorded
+/// run. A quickload in the middle of the run is not recorded, the replay of such a run
+/// parts from it there.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Replay {
+    pub version: u32,
+    pub level: String,
+    /// Seed of the level, see `LevelGenerator`
+    pub seed: u64,
+    /// Seed of the world rng of the run, see `WorldRng`
+    pub world_seed: u64,
+    pub frames: Vec<(u32, ReplayFrame)>,
+    /// Duration of every frame of the world, in seconds
+    pub deltas: Vec<f32>,
+}
+
+impl Replay {
+    pub fn new(level: &Level, world_seed: u64) -> Self {
+        Self {
+            version: REPLAY_VERSION,
+            level: level.name.clone(),
+            seed: level.currents.seed,
+            world_seed,
+            frames: Vec::new(),
+            deltas: Vec::new(),
+        }
+    }
+
+    pub fn push(&mut self, frame: ReplayFrame) {
+        match self.frames.last_mut() {
+            Some((count, last)) if *last == frame => *count += 1,
+            _ => self.frames.push((1, frame)),
+        }
+    }
+
+    pub fn len(&self) -> u32 {
+        self.frames.iter().map(|(count, _)| count).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+}
+
+/// Records the inputs of the current run
+pub struct ReplayRecorder {
+    pub replay: Replay,
+}
+
+/// Feeds a replay to the main character instead of the keyboard and mouse
+#[derive(Default)]
+pub struct ReplayPlayer {
+    /// Loaded and waiting for the next run to start
+    pub pending: Option<Replay>,
+    pub playing: Option<Replay>,
+    /// Position in the runs of frames of the replay
+    pub run: usize,
+    pub frame_in_run: u32,
+    /// Position in the frame times of the replay
+    pub delta: usize,
+}
+
+impl ReplayPlayer {
+    pub fn next_frame(&mut self) -> Option<ReplayFrame> {
+        let replay = self.playing.as_ref()?;
+
+        let frame = match replay.frames.get(self.run) {
+            Some((count, frame)) => {
+                self.frame_in_run += 1;
+                if self.frame_in_run >= *count {
+                    self.run += 1;
+                    self.frame_in_run = 0;
+                }
+                Some(frame.clone())
+            }
+            None => None,
+        };
+
+        if frame.is_none() {
+            self.playing = None;
+        }
+        frame
+    }
+
+    pub fn next_delta(&mut self) -> Option<f32> {
+        let delta = *self.playing.as_ref()?.deltas.get(self.delta)?;
+        self.delta += 1;
+        Some(delta)
+    }
+}
+
+// The world moves on by the time of the frame, or by the time of the recorded frame while
+// a replay plays
+pub fn advance_world_time(
+    time: Res<Time>,
+    mut world_time: ResMut<WorldTime>,
+    mut recorder: ResMut<ReplayRecorder>,
+    mut player: ResMut<ReplayPlayer>,
+) {
+    let delta = player.next_delta().unwrap_or_else(|| time.delta_seconds());
+    recorder.replay.deltas.push(delta);
+    world_time.advance(delta);
+}
+
+// Every run is recorded from its start, and played back if a replay was loaded for it
+pub fn start_replay_run(
+    mut recorder: ResMut<ReplayRecorder>,
+    mut player: ResMut<ReplayPlayer>,
+    world_rng: Res<WorldRng>,
+    level: Res<Level>,
+    mut notifications: EventWriter<HudNotification>,
+) {
+    recorder.replay = Replay::new(&level, world_rng.seed);
+
+    player.playing = player.pending.take();
+    player.run = 0;
+    player.frame_in_run = 0;
+    player.delta = 0;
+    if let Some(replay) = &player.playing {
+        notifications.send(HudNotification(format!(
+            "Playing back {} frames",
+            replay.len()
+        )));
+    }
+}
+
+// Writes the recording of the current run, or restarts the run to play it back
+pub fn replay_controls(
+    mut actions: ResMut<Actions>,
+    recorder: Res<ReplayRecorder>,
+    mut player: ResMut<ReplayPlayer>,
+    mut app_state: ResMut<State<AppState>>,
+    level: Res<Level>,
+    mut notifications: EventWriter<HudNotification>,
+) {
+    if actions.just_pressed(Action::SaveReplay) {
+        let message = match write_save(Path::new(REPLAY_PATH), &recorder.replay) {
+            Ok(()) => format!("{} frames recorded", recorder.replay.len()),
+            Err(error) => format!("Replay not saved: {}", error),
+        };
+        notifications.send(HudNotification(message));
+    }
+
+    if actions.just_pressed(Action::PlayReplay) {
+        let replay =
+            read_save::<Replay>(Path::new(REPLAY_PATH), REPLAY_VERSION).and_then(|replay| {
+                if replay.level == level.name && replay.seed == level.currents.seed {
+                    Ok(replay)
+                } else {
+                    Err(SaveError::Level(replay.level))
+                }
+            });
+
+        match replay {
+            // fails when the run ended or was paused on this frame
+            Ok(replay) if app_state.set(AppState::Restarting).is_ok() => {
+                actions.reset(Action::PlayReplay);
+                player.pending = Some(replay);
+            }
+            Ok(_) => {}
+            Err(error) => {
+                notifications.send(HudNotification(format!("Replay not loaded: {}", error)))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(acc: Acceleration, boost: bool) -> ReplayFrame {
+        ReplayFrame {
+            acc,
+            turning: Turning::Left(0.5),
+            boost,
+            target: Some(Vec2::new(10.0, -3.5)),
+        }
+    }
+
+    fn recorded() -> (Replay, Vec<ReplayFrame>) {
+        let level = Level::load_default().unwrap();
+        let mut replay = Replay::new(&level, 42);
+        let frames = vec![
+            frame(Acceleration::Forward(1.0), false),
+            frame(Acceleration::Forward(1.0), false),
+            frame(Acceleration::Forward(1.0), true),
+            frame(Acceleration::None, false),
+        ];
+        for frame in frames.iter() {
+            replay.push(frame.clone());
+            replay
+                .deltas
+                .push(1.0 / 60.0 + replay.deltas.len() as f32 * 0.001);
+        }
+        (replay, frames)
+    }
+
+    #[test]
+    fn identical_frames_are_stored_once() {
+        let (replay, frames) = recorded();
+        assert_eq!(replay.frames.len(), 3);
+        assert_eq!(replay.len(), frames.len() as u32);
+    }
+
+    #[test]
+    fn replay_round_trip() {
+        let (replay, _) = recorded();
+        let path = std::env::temp_dir().join("rise_above_replay_round_trip.json");
+
+        write_save(&path, &replay).unwrap();
+        let loaded = read_save::<Replay>(&path, REPLAY_VERSION).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(loaded.level, replay.level);
+        assert_eq!(loaded.seed, replay.seed);
+        assert_eq!(loaded.world_seed, 42);
+        assert_eq!(loaded.frames, replay.frames);
+        // the frame times come back to the bit, or the world would part from the run
+        assert_eq!(loaded.deltas, replay.deltas);
+    }
+
+    #[test]
+    fn player_gives_back_the_recorded_frames() {
+        let (replay, frames) = recorded();
+        let deltas = replay.deltas.clone();
+        let mut player = ReplayPlayer {
+            playing: Some(replay),
+            ..Default::default()
+        };
+
+        let mut played = Vec::new();
+        let mut played_deltas = Vec::new();
+        while let Some(delta) = player.next_delta() {
+            played_deltas.push(delta);
+            played.extend(player.next_frame());
+        }
+
+        assert_eq!(played, frames);
+        assert_eq!(played_deltas, deltas);
+        // back to the controls
+        assert_eq!(player.next_frame(), None);
+        assert!(player.playing.is_none());
+    }
+}
@@ -0,0 +1,202 @@
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use crate::agent::*;
+use crate::checkpoints::*;
+use crate::population::*;
+use crate::util::*;
+
+/// Bumped whenever the format of the saved session changes. Older files are refused.
+pub const SAVE_VERSION: u32 = 5;
+pub const QUICKSAVE_PATH: &str = "quicksave.json";
+
+#[derive(Debug)]
+pub enum SaveError {
+    Io {
+        path: PathBuf,
+        error: std::io::Error,
+    },
+    Parse(serde_json::Error),
+    Version {
+        found: u32,
+        expected: u32,
+    },
+    /// The session was saved in another level
+    Level(String),
+    /// Nothing was written, the file would fail to load. Non-finite floats end up as nulls.
+    Unreadable(serde_json::Error),
+}
+
+impl fmt::Display for SaveError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SaveError::Io { path, error } => {
+                write!(
+                    f,
+                    "could not access save file {}: {}",
+                    path.display(),
+                    error
+                )
+            }
+            SaveError::Parse(error) => write!(f, "could not parse save: {}", error),
+            SaveError::Version { found, expected } => write!(
+                f,
+                "the save has version {}, this game reads version {}",
+                found, expected
+            ),
+            SaveError::Level(name) => write!(f, "the save is from level \"{}\"", name),
+            SaveError::Unreadable(error) => {
+                write!(f, "not saved, the save would not load back: {}", error)
+            }
+        }
+    }
+}
+
+impl std::error::Error for SaveError {}
+
+impl From<serde_json::Error> for SaveError {
+    fn from(error: serde_json::Error) -> Self {
+        SaveError::Parse(error)
+    }
+}
+
+// Read first, so that a save from another version is refused instead of misread
+#[derive(Deserialize)]
+struct SaveHeader {
+    version: u32,
+}
+
+/// Writes `save` to `path`, unless it would not read back
+pub fn write_save<T: Serialize + DeserializeOwned>(path: &Path, save: &T) -> Result<(), SaveError> {
+    let contents = serde_json::to_string(save)?;
+    serde_json::from_str::<T>(&contents).map_err(SaveError::Unreadable)?;
+    std::fs::write(path, contents).map_err(|error| SaveError::Io {
+        path: path.to_path_buf(),
+        error,
+    })
+}
+
+/// Reads a file written by `write_save`, if its `version` field is `version`
+pub fn read_save<T: DeserializeOwned>(path: &Path, version: u32) -> Result<T, SaveError> {
+    let contents = std::fs::read_to_string(path).map_err(|error| SaveError::Io {
+        path: path.to_path_buf(),
+        error,
+    })?;
+
+    let header: SaveHeader = serde_json::from_str(&contents)?;
+    if header.version != version {
+        return Err(SaveError::Version {
+            found: header.version,
+            expected: version,
+        });
+    }
+    Ok(serde_json::from_str(&contents)?)
+}
+
+// Timestamps are seconds since the startup of the app. A loaded session moves them by
+// the time between its save and its load, so that timers and memories carry on.
+
+impl Agent {
+    pub fn shift_times(&mut self, offset: f32) {
+        self.last_collision_time += offset;
+        self.boost_time += offset;
+        self.goal_time += offset;
+        self.nav.plan_time += offset;
+        self.inventory.last_mining_time += offset;
+
+        let sensors = &mut self.sensors;
+        for sight in sensors.agent_sight.values_mut() {
+            sight.time_of_last_sight += offset;
+        }
+        for sight in sensors.food_sight.values_mut() {
+            sight.time_of_last_sight += offset;
+        }
+        for sight in sensors.item_sight.values_mut() {
+            sight.time_of_last_sight += offset;
+        }
+        for sight in sensors.ore_sight.values_mut() {
+            sight.time_of_last_sight += offset;
+        }
+        for hearing in sensors.hearing.values_mut() {
+            hearing.time_of_hearing += offset;
+        }
+
+        match &mut self.goal {
+            Goal::Food(sight) => sight.time_of_last_sight += offset,
+            Goal::Item(sight) => sight.time_of_last_sight += offset,
+            Goal::Ore(sight) => sight.time_of_last_sight += offset,
+            _ => {}
+        }
+    }
+}
+
+impl Game {
+    pub fn shift_times(&mut self, offset: f32) {
+        self.time += offset;
+
+        for agent in self.agents.values_mut() {
+            agent.shift_times(offset);
+        }
+
+        for squad in self.guardian_squads.iter_mut() {
+            squad.alert_time += offset;
+            squad.last_spotted_time += offset;
+        }
+    }
+}
+
+impl Progress {
+    pub fn shift_times(&mut self, offset: f32) {
+        self.death_time = self.death_time.map(|time| time + offset);
+        self.starving_since = self.starving_since.map(|time| time + offset);
+    }
+}
+
+// NaN and infinite floats are written as nulls, which fail to load. The creatures and the
+// food that diverged are left out of the save, the population manager replaces them and
+// the squads close ranks without their guardians.
+
+impl Agent {
+    pub fn is_finite(&self) -> bool {
+        [
+            self.position,
+            self.last_position,
+            self.velocity,
+            self.target_position,
+        ]
+        .iter()
+        .all(|v| v.is_finite())
+            && [
+                self.speed,
+                self.look_at_angle,
+                self.energy,
+                self.mass,
+                self.health,
+            ]
+            .iter()
+            .all(|x| x.is_finite())
+            && self.body.iter().all(|body| body.atom_pos.is_finite())
+    }
+}
+
+impl Game {
+    /// Drops the NPCs and the food with non-finite state, returns how many were dropped
+    pub fn sanitize(&mut self) -> usize {
+        let before = self.agents.len() + self.foods.len();
+        self.agents
+            .retain(|id, agent| *id == 1 || agent.is_finite());
+        self.foods.retain(|_id, food| {
+            food.position.is_finite() && food.energy.is_finite() && food.mass.is_finite()
+        });
+        before - self.agents.len() - self.foods.len()
+    }
+}
+
+impl PopulationTimers {
+    pub fn shift_times(&mut self, offset: f32) {
+        self.bottom += offset;
+        self.mid += offset;
+        self.top += offset;
+    }
+}
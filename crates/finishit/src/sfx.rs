@@ -0,0 +1,191 @@
+use bevy::prelude::*;
+use bevy_kira_audio::{Audio, AudioChannel};
+
+use std::collections::HashMap;
+
+use crate::cam::*;
+use crate::checkpoints::*;
+use crate::guardians::*;
+use crate::metabolism::*;
+use crate::util::*;
+
+// Sounds farther than this from the center of the camera are not heard, at the base zoom
+pub const HEARING_RANGE: f32 = 1500.0;
+// Number of sounds that can play at once, each with its own volume and panning
+pub const SFX_CHANNELS: usize = 8;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Sfx {
+    Collision,
+    Boost,
+    Eat,
+    GuardianCharge,
+    StageChange,
+}
+
+impl Sfx {
+    pub fn path(&self) -> &'static str {
+        match self {
+            Sfx::Collision => "sfx/collision.wav",
+            Sfx::Boost => "sfx/boost.wav",
+            Sfx::Eat => "sfx/eat.wav",
+            Sfx::GuardianCharge => "sfx/guardian_charge.wav",
+            Sfx::StageChange => "sfx/stage_change.wav",
+        }
+    }
+
+    pub fn volume(&self) -> f32 {
+        match self {
+            Sfx::Collision => 0.5,
+            Sfx::Boost => 0.6,
+            Sfx::Eat => 0.4,
+            Sfx::GuardianCharge => 0.9,
+            Sfx::StageChange => 0.8,
+        }
+    }
+
+    /// Minimum time between two plays of the sound, so that a crowd doesn't turn into noise
+    pub fn cooldown(&self) -> f32 {
+        match self {
+            Sfx::Collision => 0.12,
+            Sfx::Boost => 0.2,
+            Sfx::Eat => 0.08,
+            Sfx::GuardianCharge => 1.0,
+            Sfx::StageChange => 2.0,
+        }
+    }
+}
+
+/// Where a sound comes from. The camera is the listener.
+#[derive(Clone, Copy, Debug)]
+pub enum SfxSource {
+    At(Vec2),
+    /// Heard as is, wherever the camera is
+    Everywhere,
+}
+
+#[derive(Default)]
+pub struct SfxPlayer {
+    /// The sounds take the channels in turn
+    pub next_channel: usize,
+    pub last_played: HashMap<Sfx, f32>,
+}
+
+/// Camera-relative position of the listener, and how far it hears
+pub struct Listener {
+    pub position: Vec2,
+    /// Distance from the center of the screen to its sides, in world units
+    pub half_width: f32,
+    pub range: f32,
+}
+
+impl Listener {
+    /// Volume and panning of a sound, None when it is out of hearing range
+    pub fn spatialize(&self, source: SfxSource) -> Option<(f32, f32)> {
+        let position = match source {
+            SfxSource::At(position) => position,
+            SfxSource::Everywhere => return Some((1.0, 0.5)),
+        };
+
+        let distance = position.distance(self.position);
+        if distance > self.range {
+            return None;
+        }
+
+        let falloff = 1.0 - distance / self.range;
+        let side = ((position.x - self.position.x) / self.half_width).clamp(-1.0, 1.0);
+        Some((falloff * falloff, 0.5 + side * 0.5))
+    }
+}
+
+impl SfxPlayer {
+    pub fn play(
+        &mut self,
+        audio: &Audio,
+        asset_server: &AssetServer,
+        listener: &Listener,
+        sfx: Sfx,
+        source: SfxSource,
+        now: f32,
+    ) {
+        if let Some(last) = self.last_played.get(&sfx) {
+            if now - last < sfx.cooldown() {
+                return;
+            }
+        }
+
+        let (volume, panning) = match listener.spatialize(source) {
+            Some(spatialized) => spatialized,
+            None => return,
+        };
+
+        let channel = AudioChannel::new(format!("sfx_{}", self.next_channel));
+        self.next_channel = (self.next_channel + 1) % SFX_CHANNELS;
+
+        audio.stop_channel(&channel);
+        audio.set_volume_in_channel(volume * sfx.volume(), &channel);
+        audio.set_panning_in_channel(panning, &channel);
+        audio.play_in_channel(asset_server.load(sfx.path()), &channel);
+
+        self.last_played.insert(sfx, now);
+    }
+}
+
+pub struct SfxPlugin;
+
+impl Plugin for SfxPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(SfxPlayer::default())
+            .add_system(play_gameplay_sfx);
+    }
+}
+
+pub fn play_gameplay_sfx(
+    mut player: ResMut<SfxPlayer>,
+    audio: Res<Audio>,
+    asset_server: Res<AssetServer>,
+    game: Res<Game>,
+    time: Res<Time>,
+    windows: Res<Windows>,
+    cam_query: Query<&Transform, With<Cam>>,
+    mut collision_events: EventReader<CollisionEvent>,
+    mut boost_events: EventReader<BoostEvent>,
+    mut eaten_events: EventReader<FoodEatenEvent>,
+    mut charge_events: EventReader<GuardianChargeEvent>,
+    mut stage_events: EventReader<StageChangeEvent>,
+) {
+    let transform = match cam_query.get_single() {
+        Ok(transform) => transform,
+        Err(_) => return,
+    };
+    let window_width = windows.get_primary().map_or(900.0, |window| window.width());
+    let listener = Listener {
+        position: transform.translation.truncate(),
+        half_width: window_width / 2.0 * transform.scale.x,
+        range: HEARING_RANGE * transform.scale.x / BASE_ZOOM,
+    };
+    let now = time.seconds_since_startup() as f32;
+
+    let mut sounds = Vec::new();
+    for collision in collision_events.iter() {
+        if let Some(agent) = game.agents.get(&collision.agent_id) {
+            sounds.push((Sfx::Collision, SfxSource::At(agent.position)));
+        }
+    }
+    for boost in boost_events.iter() {
+        sounds.push((Sfx::Boost, SfxSource::At(boost.position)));
+    }
+    for eaten in eaten_events.iter() {
+        sounds.push((Sfx::Eat, SfxSource::At(eaten.position)));
+    }
+    for charge in charge_events.iter() {
+        sounds.push((Sfx::GuardianCharge, SfxSource::At(charge.position)));
+    }
+    for _stage in stage_events.iter() {
+        sounds.push((Sfx::StageChange, SfxSource::Everywhere));
+    }
+
+    for (sfx, source) in sounds {
+        player.play(&audio, &asset_server, &listener, sfx, source, now);
+    }
+}
@@ -0,0 +1,301 @@
+use bevy::prelude::*;
+
+use std::collections::HashMap;
+
+use crate::agent::*;
+use crate::terrain::*;
+use crate::util::*;
+use crate::*;
+
+// How strongly the nodes are pulled back towards their rest position in the creature frame.
+// Joints are held almost rigidly and act as hinges, the other nodes are free to lag behind.
+pub const SOFT_JOINT_ANCHOR: f32 = 0.5;
+pub const SOFT_NODE_ANCHOR: f32 = 0.06;
+
+pub const SOFT_DAMPING: f32 = 0.85;
+pub const SOFT_CONSTRAINT_ITERATIONS: usize = 4;
+pub const SOFT_NEIGHBOURS: usize = 3;
+
+// A node never drifts farther than this fraction of the creature size from its rest position
+pub const SOFT_MAX_DEVIATION: f32 = 0.25;
+
+// How far the nodes facing an impact are pushed in, relative to the creature size
+pub const SOFT_SQUASH: f32 = 0.15;
+
+// The unused nodes of a .cha file are parked outside of the quad, farther than this from
+// its center. Neither the body nor the sprites of a creature take them.
+pub const PARKED_NODE_DISTANCE: f32 = 0.49;
+
+#[derive(Clone, Debug)]
+pub struct SoftNode {
+    /// Index of the node in the `MarkerInstanceData` and in `Agent.body`
+    pub index: usize,
+    /// Position of the node in the .cha file, in quad units
+    pub rest: Vec2,
+    pub pos: Vec2,
+    pub last_pos: Vec2,
+    pub is_joint: bool,
+}
+
+#[derive(Clone, Debug)]
+pub struct DistanceConstraint {
+    pub a: usize,
+    pub b: usize,
+    /// Rest length in quad units, scaled by the agent mass at every step
+    pub rest_length: f32,
+    pub stiffness: f32,
+}
+
+/// Point masses built from the nodes of a .cha file, linked by distance constraints.
+/// The agent's rigid position and angle only drive the nodes through springs, so the
+/// body squashes on impact and trails behind when turning.
+#[derive(Component, Clone, Debug)]
+pub struct SoftBody {
+    pub nodes: Vec<SoftNode>,
+    pub constraints: Vec<DistanceConstraint>,
+}
+
+pub fn rotate(v: Vec2, angle: f32) -> Vec2 {
+    let (sin, cos) = angle.sin_cos();
+    Vec2::new(v.x * cos - v.y * sin, v.x * sin + v.y * cos)
+}
+
+impl SoftBody {
+    pub fn from_character(
+        character: &CharacterSaveFormat,
+        mass: f32,
+        position: Vec2,
+        angle: f32,
+    ) -> Self {
+        let scale = MASS_MULT * mass;
+
+        let mut nodes = character
+            .data
+            .iter()
+            .enumerate()
+            .filter(|(_, node)| node.pos.length() < PARKED_NODE_DISTANCE)
+            .map(|(index, node)| {
+                let pos = position + rotate(node.pos * scale, angle);
+                SoftNode {
+                    index,
+                    rest: node.pos,
+                    pos,
+                    last_pos: pos,
+                    is_joint: node.is_joint,
+                }
+            })
+            .collect::<Vec<_>>();
+
+        // creatures without joints hinge around the node closest to their center
+        if !nodes.is_empty() && !nodes.iter().any(|node| node.is_joint) {
+            let centroid =
+                nodes.iter().fold(Vec2::ZERO, |acc, node| acc + node.rest) / nodes.len() as f32;
+
+            let mut hinge = 0;
+            for (k, node) in nodes.iter().enumerate() {
+                if node.rest.distance(centroid) < nodes[hinge].rest.distance(centroid) {
+                    hinge = k;
+                }
+            }
+            nodes[hinge].is_joint = true;
+        }
+
+        let mut constraints: Vec<DistanceConstraint> = Vec::new();
+        let mut add_constraint = |a: usize, b: usize, stiffness: f32, nodes: &[SoftNode]| {
+            let exists = constraints
+                .iter()
+                .any(|c| (c.a == a && c.b == b) || (c.a == b && c.b == a));
+            if a != b && !exists {
+                constraints.push(DistanceConstraint {
+                    a,
+                    b,
+                    rest_length: nodes[a].rest.distance(nodes[b].rest),
+                    stiffness,
+                });
+            }
+        };
+
+        for a in 0..nodes.len() {
+            // skin: every node is linked to its closest neighbours
+            let mut neighbours = (0..nodes.len()).filter(|b| *b != a).collect::<Vec<_>>();
+            neighbours.sort_by(|b1, b2| {
+                let d1 = nodes[a].rest.distance(nodes[*b1].rest);
+                let d2 = nodes[a].rest.distance(nodes[*b2].rest);
+                d1.partial_cmp(&d2).unwrap()
+            });
+            for b in neighbours.iter().take(SOFT_NEIGHBOURS) {
+                add_constraint(a, *b, 0.5, &nodes);
+            }
+
+            // bones: every node is linked to the closest joint, around which it can swing
+            if !nodes[a].is_joint {
+                let closest_joint =
+                    (0..nodes.len())
+                        .filter(|b| nodes[*b].is_joint)
+                        .min_by(|b1, b2| {
+                            let d1 = nodes[a].rest.distance(nodes[*b1].rest);
+                            let d2 = nodes[a].rest.distance(nodes[*b2].rest);
+                            d1.partial_cmp(&d2).unwrap()
+                        });
+                if let Some(joint) = closest_joint {
+                    add_constraint(a, joint, 1.0, &nodes);
+                }
+            }
+        }
+
+        SoftBody { nodes, constraints }
+    }
+
+    pub fn target(&self, node: &SoftNode, mass: f32, position: Vec2, angle: f32) -> Vec2 {
+        position + rotate(node.rest * MASS_MULT * mass, angle)
+    }
+
+    pub fn step(&mut self, mass: f32, position: Vec2, angle: f32) {
+        let scale = MASS_MULT * mass;
+
+        // verlet integration, pulled towards the rigid pose
+        for k in 0..self.nodes.len() {
+            let target = self.target(&self.nodes[k], mass, position, angle);
+            let node = &mut self.nodes[k];
+
+            let velocity = (node.pos - node.last_pos) * SOFT_DAMPING;
+            node.last_pos = node.pos;
+            node.pos += velocity;
+
+            let anchor = if node.is_joint {
+                SOFT_JOINT_ANCHOR
+            } else {
+                SOFT_NODE_ANCHOR
+            };
+            node.pos += (target - node.pos) * anchor;
+        }
+
+        for _ in 0..SOFT_CONSTRAINT_ITERATIONS {
+            for constraint in self.constraints.iter() {
+                let pa = self.nodes[constraint.a].pos;
+                let pb = self.nodes[constraint.b].pos;
+                let delta = pb - pa;
+                let length = delta.length();
+                if length < f32::EPSILON {
+                    continue;
+                }
+
+                let rest_length = constraint.rest_length * scale;
+                let correction =
+                    delta * (length - rest_length) / length * 0.5 * constraint.stiffness;
+
+                // joints are hinges: they don't get dragged around by the nodes hanging from them
+                match (
+                    self.nodes[constraint.a].is_joint,
+                    self.nodes[constraint.b].is_joint,
+                ) {
+                    (true, false) => self.nodes[constraint.b].pos -= correction * 2.0,
+                    (false, true) => self.nodes[constraint.a].pos += correction * 2.0,
+                    _ => {
+                        self.nodes[constraint.a].pos += correction;
+                        self.nodes[constraint.b].pos -= correction;
+                    }
+                }
+            }
+        }
+
+        // keep the body in one piece, whatever happens
+        let max_deviation = SOFT_MAX_DEVIATION * scale;
+        for k in 0..self.nodes.len() {
+            let target = self.target(&self.nodes[k], mass, position, angle);
+            let node = &mut self.nodes[k];
+            let deviation = node.pos - target;
+            if !deviation.is_finite() {
+                node.pos = target;
+                node.last_pos = target;
+            } else if deviation.length() > max_deviation {
+                node.pos = target + deviation.normalize() * max_deviation;
+            }
+        }
+    }
+
+    /// Pushes in the nodes that face `impact_dir` (a unit vector from the creature's
+    /// center towards whatever it hit). `strength` is relative to the creature size.
+    pub fn squash(&mut self, mass: f32, position: Vec2, impact_dir: Vec2, strength: f32) {
+        let push = impact_dir * strength * SOFT_SQUASH * MASS_MULT * mass;
+        for node in self.nodes.iter_mut() {
+            let from_center = node.pos - position;
+            if from_center == Vec2::ZERO {
+                continue;
+            }
+            let facing = from_center.normalize().dot(impact_dir).max(0.0);
+            node.pos -= push * facing;
+        }
+    }
+
+    /// The nodes squash against the rocks
+    pub fn collide_with_terrain(&mut self, terrain: &Terrain, mass: f32, position: Vec2) {
+        // nodes are at a max distance of 0.5 * mass * MASS_MULT from the center
+        if terrain.query(position, 0.5 * MASS_MULT * mass).is_empty() {
+            return;
+        }
+
+        let node_radius = 0.5 * ATOM_MULT * MASS_MULT * mass;
+        for node in self.nodes.iter_mut() {
+            let (pos, last_pos) = terrain.resolve(node.last_pos, node.pos, node_radius);
+            node.pos = pos;
+            node.last_pos = last_pos;
+        }
+    }
+
+    /// Position of the node in the creature frame, in quad units, as expected by the shader
+    pub fn local_pos(&self, node: &SoftNode, mass: f32, position: Vec2, angle: f32) -> Vec2 {
+        rotate(node.pos - position, -angle) / (MASS_MULT * mass)
+    }
+}
+
+pub fn simulate_soft_bodies(
+    game: Res<Game>,
+    terrain: Res<Terrain>,
+    mut collision_events: EventReader<CollisionEvent>,
+    mut query: Query<(&AgentId, &mut SoftBody, &mut MarkerInstanceMatData)>,
+    mut atom_query: Query<&mut Transform, With<Atom>>,
+) {
+    let mut impacts: HashMap<u32, (Vec2, f32)> = HashMap::new();
+    for collision in collision_events.iter() {
+        if let (Some(agent), Some(other_agent)) = (
+            game.agents.get(&collision.agent_id),
+            game.agents.get(&collision.other_agent_id),
+        ) {
+            let impact_line = other_agent.position - agent.position;
+            if impact_line != Vec2::ZERO {
+                let strength = (other_agent.mass / agent.mass).clamp(0.2, 2.0);
+                impacts.insert(agent.id, (impact_line.normalize(), strength));
+            }
+        }
+    }
+
+    for (agent_id, mut soft_body, mut instance_data) in query.iter_mut() {
+        let agent = match game.agents.get(&agent_id.kdtree_hash) {
+            Some(agent) => agent,
+            None => continue,
+        };
+
+        if let Some((impact_dir, strength)) = impacts.get(&agent.id) {
+            soft_body.squash(agent.mass, agent.position, *impact_dir, *strength);
+        }
+
+        soft_body.step(agent.mass, agent.position, agent.look_at_angle);
+        soft_body.collide_with_terrain(&terrain, agent.mass, agent.position);
+
+        for node in soft_body.nodes.iter() {
+            let local_pos =
+                soft_body.local_pos(node, agent.mass, agent.position, agent.look_at_angle);
+
+            instance_data.0[0].set_pos(local_pos, node.index);
+
+            // the atoms follow the nodes, so that collisions see the deformed body
+            if let Some(atom_entity) = agent.body.get(node.index).and_then(|body| body.entity) {
+                if let Ok(mut atom_transform) = atom_query.get_mut(atom_entity) {
+                    let z = atom_transform.translation.z;
+                    atom_transform.translation = (local_pos * MASS_MULT * agent.mass).extend(z);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,185 @@
+use bevy::prelude::*;
+use bevy_kira_audio::Audio;
+
+use crate::checkpoints::*;
+use crate::inputs::*;
+use crate::level::*;
+use crate::music::*;
+use crate::util::*;
+
+/// Title -> InGame <-> Paused, then GameOver or Ending -> Restarting -> InGame
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub enum AppState {
+    Title,
+    InGame,
+    /// Pushed on top of InGame, which is frozen underneath
+    Paused,
+    GameOver,
+    Ending,
+    /// The run is torn down and rebuilt, for one frame
+    Restarting,
+}
+
+/// Text shown for the time of a state
+#[derive(Component)]
+pub struct StateText;
+
+pub fn spawn_state_text(
+    commands: &mut Commands,
+    asset_server: &AssetServer,
+    position: Vec2,
+    lines: &[(&str, f32)],
+) {
+    let text_alignment = TextAlignment {
+        vertical: VerticalAlign::Bottom,
+        horizontal: HorizontalAlign::Center,
+    };
+
+    let mut y = position.y;
+    for (line, font_size) in lines {
+        let text_style = TextStyle {
+            font: asset_server.load("fonts/Roboto-Regular.ttf"),
+            font_size: *font_size,
+            color: Color::BLACK,
+        };
+
+        commands
+            .spawn_bundle(Text2dBundle {
+                text: Text::with_section(*line, text_style, text_alignment),
+                transform: Transform::from_translation(Vec3::new(position.x, y, 50.0)),
+                ..Default::default()
+            })
+            .insert(StateText);
+
+        y -= font_size * 1.2;
+    }
+}
+
+pub fn despawn_state_text(mut commands: Commands, query: Query<Entity, With<StateText>>) {
+    for entity in query.iter() {
+        commands.entity(entity).despawn();
+    }
+}
+
+fn main_character_position(game: &Game) -> Vec2 {
+    game.agents
+        .get(&1)
+        .map_or(Vec2::ZERO, |agent| agent.position)
+}
+
+pub fn show_title(
+    mut commands: Commands,
+    asset_server: Res<AssetServer>,
+    level: Res<Level>,
+    input_map: Res<InputMap>,
+) {
+    let confirm = format!("Press {} to dive in", input_map.describe(Action::Confirm));
+    spawn_state_text(
+        &mut commands,
+        &asset_server,
+        level.start_position + Vec2::new(0.0, 180.0),
+        &[("Rise Above", 60.0), (&confirm, 24.0)],
+    );
+}
+
+pub fn leave_title(mut actions: ResMut<Actions>, mut app_state: ResMut<State<AppState>>) {
+    if actions.just_pressed(Action::Confirm) && app_state.set(AppState::InGame).is_ok() {
+        actions.reset(Action::Confirm);
+    }
+}
+
+// The pause action pauses the game, and resumes it
+pub fn pause_game(
+    mut actions: ResMut<Actions>,
+    mut app_state: ResMut<State<AppState>>,
+    audio: Res<Audio>,
+) {
+    // fails when the run just ended on this frame
+    if actions.just_pressed(Action::Pause) && app_state.push(AppState::Paused).is_ok() {
+        // or the paused state would see the same action
+        actions.reset(Action::Pause);
+        pause_music(&audio);
+    }
+}
+
+pub fn show_pause(
+    mut commands: Commands,
+    asset_server: Res<AssetServer>,
+    game: Res<Game>,
+    input_map: Res<InputMap>,
+) {
+    let resume = format!("Press {} to resume", input_map.describe(Action::Pause));
+    let rebind = format!(
+        "Press {} to rebind the controls",
+        input_map.describe(Action::Rebind)
+    );
+    spawn_state_text(
+        &mut commands,
+        &asset_server,
+        main_character_position(&game) + Vec2::new(0.0, 100.0),
+        &[("Paused", 44.0), (&resume, 20.0), (&rebind, 20.0)],
+    );
+}
+
+// Rebinding happens while paused, the text follows the new bindings
+pub fn refresh_pause_text(
+    mut commands: Commands,
+    asset_server: Res<AssetServer>,
+    game: Res<Game>,
+    input_map: Res<InputMap>,
+    query: Query<Entity, With<StateText>>,
+) {
+    // the text is not spawned yet on the frame the game is paused
+    if !input_map.is_changed() || query.is_empty() {
+        return;
+    }
+    for entity in query.iter() {
+        commands.entity(entity).despawn();
+    }
+    show_pause(commands, asset_server, game, input_map);
+}
+
+pub fn resume_game(
+    mut actions: ResMut<Actions>,
+    mut app_state: ResMut<State<AppState>>,
+    audio: Res<Audio>,
+) {
+    if actions.just_pressed(Action::Pause) && app_state.pop().is_ok() {
+        actions.reset(Action::Pause);
+        resume_music(&audio);
+    }
+}
+
+pub fn show_game_over(
+    mut commands: Commands,
+    asset_server: Res<AssetServer>,
+    game: Res<Game>,
+    progress: Res<Progress>,
+    input_map: Res<InputMap>,
+) {
+    let confirm = format!(
+        "Press {} to start a new run",
+        input_map.describe(Action::Confirm)
+    );
+    let deaths = format!("You died {} times on the way up", progress.deaths);
+    spawn_state_text(
+        &mut commands,
+        &asset_server,
+        main_character_position(&game) + Vec2::new(0.0, 100.0),
+        &[("Game over", 44.0), (&deaths, 20.0), (&confirm, 20.0)],
+    );
+}
+
+// From the game over and ending screens. The ending also restarts once it is over.
+pub fn request_restart(mut actions: ResMut<Actions>, mut app_state: ResMut<State<AppState>>) {
+    if actions.just_pressed(Action::Confirm) {
+        actions.reset(Action::Confirm);
+        app_state.overwrite_set(AppState::Restarting).unwrap();
+    }
+}
+
+// The run is rebuilt when entering Restarting, and starts once it is in place
+pub fn finish_restart(mut app_state: ResMut<State<AppState>>) {
+    // tried again on the next frame when another transition is already queued
+    let _ = app_state.set(AppState::InGame);
+}
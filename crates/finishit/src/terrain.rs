@@ -0,0 +1,424 @@
+use bevy::{
+    prelude::*,
+    render::{mesh::Indices, render_resource::PrimitiveTopology},
+    sprite::MaterialMesh2dBundle,
+};
+
+use crate::agent::*;
+use crate::level::*;
+use crate::util::*;
+use crate::*;
+
+// Size of the cells of the grid that the obstacles are bucketed in
+pub const TERRAIN_CELL_SIZE: f32 = 250.0;
+
+// Distance between two samples of a line of sight
+pub const TERRAIN_SIGHT_STEP: f32 = 40.0;
+
+// Fraction of the velocity into the rock that is bounced back. The rest is lost,
+// and the tangential part is kept, so creatures slide along the rocks.
+pub const TERRAIN_BOUNCE: f32 = 0.2;
+
+// A swept circle is in contact with a rock once it is this close to it
+pub const SWEEP_TOLERANCE: f32 = 0.5;
+// Grazing moves that haven't reached a rock after this many steps are left to the discrete test
+pub const SWEEP_MAX_STEPS: usize = 32;
+
+pub const TERRAIN_Z: f32 = 0.05;
+
+impl Obstacle {
+    /// Lower left and upper right corners of the bounding box
+    pub fn bounds(&self) -> (Vec2, Vec2) {
+        match self {
+            Obstacle::Circle { center, radius } => (
+                *center - Vec2::splat(*radius),
+                *center + Vec2::splat(*radius),
+            ),
+            Obstacle::Rect { center, size } => (*center - *size / 2.0, *center + *size / 2.0),
+            Obstacle::Polygon { points } => points.iter().fold(
+                (Vec2::splat(f32::MAX), Vec2::splat(f32::MIN)),
+                |(min, max), point| (min.min(*point), max.max(*point)),
+            ),
+        }
+    }
+
+    /// Unit vector pointing out of the obstacle, at `point`
+    pub fn normal(&self, point: Vec2) -> Vec2 {
+        let e = 0.5;
+        let gradient = Vec2::new(
+            self.distance(point + Vec2::new(e, 0.0)) - self.distance(point - Vec2::new(e, 0.0)),
+            self.distance(point + Vec2::new(0.0, e)) - self.distance(point - Vec2::new(0.0, e)),
+        );
+
+        if gradient.length_squared() > f32::EPSILON {
+            gradient.normalize()
+        } else {
+            Vec2::Y
+        }
+    }
+
+    /// Fraction of the move from `from` to `to` at which a circle of `radius` first touches
+    /// the obstacle. As in `swept_circle_toi`, a circle that already overlaps it is left to
+    /// the discrete test.
+    pub fn sweep(&self, from: Vec2, to: Vec2, radius: f32) -> Option<f32> {
+        if let Obstacle::Circle {
+            center,
+            radius: obstacle_radius,
+        } = self
+        {
+            return swept_circle_toi(from, to, *center, radius + obstacle_radius);
+        }
+
+        let length = from.distance(to);
+        if length < f32::EPSILON || self.distance(from) <= radius {
+            return None;
+        }
+
+        // the distance to the obstacle is always a safe step along the move
+        let mut toi = 0.0;
+        for _ in 0..SWEEP_MAX_STEPS {
+            let gap = self.distance(from.lerp(to, toi)) - radius;
+            if gap < SWEEP_TOLERANCE {
+                return Some(toi);
+            }
+            toi += gap / length;
+            if toi > 1.0 {
+                return None;
+            }
+        }
+        None
+    }
+
+    /// Triangle fan around the centroid. Fine for the convex and star shaped rocks
+    /// of the levels.
+    pub fn to_mesh(&self) -> Mesh {
+        let outline = match self {
+            Obstacle::Circle { center, radius } => (0..32)
+                .map(|k| {
+                    let angle = std::f32::consts::TAU * k as f32 / 32.0;
+                    *center + Vec2::new(angle.cos(), angle.sin()) * *radius
+                })
+                .collect::<Vec<_>>(),
+            Obstacle::Rect { center, size } => {
+                let half = *size / 2.0;
+                vec![
+                    *center + Vec2::new(-half.x, -half.y),
+                    *center + Vec2::new(half.x, -half.y),
+                    *center + Vec2::new(half.x, half.y),
+                    *center + Vec2::new(-half.x, half.y),
+                ]
+            }
+            Obstacle::Polygon { points } => points.clone(),
+        };
+
+        let centroid =
+            outline.iter().fold(Vec2::ZERO, |acc, point| acc + *point) / outline.len() as f32;
+
+        let mut positions = vec![[centroid.x, centroid.y, 0.0]];
+        positions.extend(outline.iter().map(|point| [point.x, point.y, 0.0]));
+
+        let n = outline.len() as u32;
+        let indices = (0..n)
+            .flat_map(|k| [0, k + 1, (k + 1) % n + 1])
+            .collect::<Vec<u32>>();
+
+        let mut mesh = Mesh::new(PrimitiveTopology::TriangleList);
+        mesh.set_attribute(
+            Mesh::ATTRIBUTE_NORMAL,
+            vec![[0.0, 0.0, 1.0]; positions.len()],
+        );
+        mesh.set_attribute(Mesh::ATTRIBUTE_UV_0, vec![[0.0, 0.0]; positions.len()]);
+        mesh.set_attribute(Mesh::ATTRIBUTE_POSITION, positions);
+        mesh.set_indices(Some(Indices::U32(indices)));
+        mesh
+    }
+}
+
+/// The rocks of the level, bucketed in a grid for fast spatial queries
+pub struct Terrain {
+    pub obstacles: Vec<Obstacle>,
+    pub columns: usize,
+    pub rows: usize,
+    /// Indices of the obstacles overlapping each cell
+    pub cells: Vec<Vec<usize>>,
+}
+
+impl Terrain {
+    pub fn from_level(level: &Level) -> Self {
+        let columns = (level.width / TERRAIN_CELL_SIZE).ceil() as usize + 1;
+        let rows = (level.height / TERRAIN_CELL_SIZE).ceil() as usize + 1;
+        let mut cells = vec![Vec::new(); columns * rows];
+
+        for (k, obstacle) in level.obstacles.iter().enumerate() {
+            let (min, max) = obstacle.bounds();
+            let (c0, r0) = Self::cell_of(min, columns, rows);
+            let (c1, r1) = Self::cell_of(max, columns, rows);
+            for row in r0..=r1 {
+                for column in c0..=c1 {
+                    cells[row * columns + column].push(k);
+                }
+            }
+        }
+
+        Self {
+            obstacles: level.obstacles.clone(),
+            columns,
+            rows,
+            cells,
+        }
+    }
+
+    fn cell_of(position: Vec2, columns: usize, rows: usize) -> (usize, usize) {
+        let p = (position / TERRAIN_CELL_SIZE).max(Vec2::ZERO);
+        (
+            (p.x as usize).min(columns - 1),
+            (p.y as usize).min(rows - 1),
+        )
+    }
+
+    /// Indices of the obstacles that may be within `radius` of `position`
+    pub fn query(&self, position: Vec2, radius: f32) -> Vec<usize> {
+        let (c0, r0) = Self::cell_of(position - Vec2::splat(radius), self.columns, self.rows);
+        let (c1, r1) = Self::cell_of(position + Vec2::splat(radius), self.columns, self.rows);
+
+        let mut found = Vec::new();
+        for row in r0..=r1 {
+            for column in c0..=c1 {
+                for k in self.cells[row * self.columns + column].iter() {
+                    if !found.contains(k) {
+                        found.push(*k);
+                    }
+                }
+            }
+        }
+        found
+    }
+
+    /// Deepest overlap of a circle with the rocks: penetration depth and the direction
+    /// to push the circle out
+    pub fn contact(&self, position: Vec2, radius: f32) -> Option<(f32, Vec2)> {
+        self.query(position, radius)
+            .into_iter()
+            .map(|k| &self.obstacles[k])
+            .map(|obstacle| (radius - obstacle.distance(position), obstacle))
+            .filter(|(depth, _)| *depth > 0.0)
+            .max_by(|(a, _), (b, _)| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal))
+            .map(|(depth, obstacle)| (depth, obstacle.normal(position)))
+    }
+
+    /// First contact of a circle moving from `from` to `to` with the rocks: the fraction of
+    /// the move at which it happens, and the direction out of the rock there
+    pub fn sweep(&self, from: Vec2, to: Vec2, radius: f32) -> Option<(f32, Vec2)> {
+        let travel = to - from;
+        self.query(from + travel * 0.5, travel.length() * 0.5 + radius)
+            .into_iter()
+            .map(|k| &self.obstacles[k])
+            .filter_map(|obstacle| obstacle.sweep(from, to, radius).map(|toi| (toi, obstacle)))
+            .min_by(|(a, _), (b, _)| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal))
+            .map(|(toi, obstacle)| (toi, obstacle.normal(from + travel * toi)))
+    }
+
+    pub fn is_blocked(&self, position: Vec2) -> bool {
+        self.query(position, 0.0)
+            .into_iter()
+            .any(|k| self.obstacles[k].distance(position) < 0.0)
+    }
+
+    pub fn line_of_sight(&self, from: Vec2, to: Vec2) -> bool {
+        let steps = (from.distance(to) / TERRAIN_SIGHT_STEP).ceil() as usize;
+        (1..steps).all(|k| !self.is_blocked(from.lerp(to, k as f32 / steps as f32)))
+    }
+
+    /// Pushes a moving circle out of the rocks, see `push_out`
+    pub fn resolve(&self, last_position: Vec2, position: Vec2, radius: f32) -> (Vec2, Vec2) {
+        match self.contact(position, radius) {
+            Some((depth, normal)) => push_out(last_position, position, depth, normal),
+            None => (position, last_position),
+        }
+    }
+}
+
+/// Moves a verlet point out of a rock. The part of the velocity going into the rock is
+/// mostly lost, so that the point slides along it. Returns the new position and last position.
+pub fn push_out(last_position: Vec2, position: Vec2, depth: f32, normal: Vec2) -> (Vec2, Vec2) {
+    let mut velocity = position - last_position;
+    let into = velocity.dot(normal);
+    if into < 0.0 {
+        velocity -= normal * into * (1.0 + TERRAIN_BOUNCE);
+    }
+
+    let position = position + normal * depth;
+    (position, position - velocity)
+}
+
+#[derive(Component)]
+pub struct TerrainMesh;
+
+pub fn spawn_terrain(
+    mut commands: Commands,
+    terrain: Res<Terrain>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<ColorMaterial>>,
+) {
+    let material = materials.add(ColorMaterial::from(Color::rgb(0.3, 0.22, 0.24)));
+
+    for obstacle in terrain.obstacles.iter() {
+        commands
+            .spawn_bundle(MaterialMesh2dBundle {
+                mesh: meshes.add(obstacle.to_mesh()).into(),
+                material: material.clone(),
+                transform: Transform::from_translation(Vec3::new(0.0, 0.0, TERRAIN_Z)),
+                ..Default::default()
+            })
+            .insert(TerrainMesh);
+    }
+}
+
+// Creatures collide with the rocks through their atoms, and slide against them
+pub fn terrain_collisions(
+    mut game: ResMut<Game>,
+    terrain: Res<Terrain>,
+    atoms_query: Query<&Children, With<AgentId>>,
+    atom_transform_query: Query<&GlobalTransform, With<Atom>>,
+) {
+    for (_id, agent) in game.agents.iter_mut() {
+        if !agent.alive {
+            continue;
+        }
+
+        // nodes are at a max distance of 0.5 * mass * MASS_MULT from the center
+        let reach = agent.mass * MASS_MULT * 0.5;
+        let travel = agent.position - agent.last_position;
+        let middle = agent.last_position + travel * 0.5;
+        if terrain
+            .query(middle, travel.length() * 0.5 + reach)
+            .is_empty()
+        {
+            continue;
+        }
+
+        let atom_radius = ATOM_MULT * agent.mass * MASS_MULT * 0.5;
+
+        // the atoms lag one frame behind: they are placed relative to the last position
+        let atom_offsets = agent
+            .entity
+            .and_then(|entity| atoms_query.get(entity).ok())
+            .map(|children| {
+                children
+                    .iter()
+                    .filter_map(|child| atom_transform_query.get(*child).ok())
+                    .map(|transform| transform.translation.truncate() - agent.last_position)
+                    .collect::<Vec<_>>()
+            })
+            .unwrap_or_default();
+
+        // a boost carries a creature through a thin rock in a single frame, so a fast one
+        // is stopped where its path first touches the rock, and slides from there
+        if travel.length() > atom_radius {
+            if let Some((toi, normal)) =
+                terrain.sweep(agent.last_position, agent.position, atom_radius)
+            {
+                let contact = agent.last_position + travel * toi;
+                let (position, last_position) = push_out(contact - travel, contact, 0.0, normal);
+                agent.position = position;
+                agent.last_position = last_position;
+            }
+        }
+
+        let atoms = atom_offsets
+            .iter()
+            .map(|offset| *offset + agent.position)
+            .collect::<Vec<_>>();
+
+        // the deepest atom pushes the whole creature
+        let deepest = atoms
+            .iter()
+            .chain(std::iter::once(&agent.position))
+            .filter_map(|atom| terrain.contact(*atom, atom_radius))
+            .max_by(|(a, _), (b, _)| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+
+        if let Some((depth, normal)) = deepest {
+            let (position, last_position) =
+                push_out(agent.last_position, agent.position, depth, normal);
+            agent.position = position;
+            agent.last_position = last_position;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sweep_stops_at_a_thin_rock() {
+        // a wall 10 wide, crossed in a single move
+        let wall = Obstacle::Rect {
+            center: Vec2::new(0.0, 0.0),
+            size: Vec2::new(10.0, 200.0),
+        };
+        let toi = wall
+            .sweep(Vec2::new(-100.0, 0.0), Vec2::new(100.0, 0.0), 5.0)
+            .expect("the move goes through the wall");
+
+        // touches it at x = -10
+        assert!((toi - 0.45).abs() < 0.01);
+    }
+
+    #[test]
+    fn sweep_circle_obstacle() {
+        let rock = Obstacle::Circle {
+            center: Vec2::ZERO,
+            radius: 20.0,
+        };
+        let toi = rock.sweep(Vec2::new(-100.0, 0.0), Vec2::new(100.0, 0.0), 5.0);
+        assert!((toi.unwrap() - 0.375).abs() < 1e-4);
+    }
+
+    #[test]
+    fn sweep_polygon_obstacle() {
+        let rock = Obstacle::Polygon {
+            points: vec![
+                Vec2::new(-10.0, -50.0),
+                Vec2::new(10.0, -50.0),
+                Vec2::new(10.0, 50.0),
+                Vec2::new(-10.0, 50.0),
+            ],
+        };
+        let toi = rock.sweep(Vec2::new(0.0, -200.0), Vec2::new(0.0, 200.0), 10.0);
+        assert!((toi.unwrap() - 0.35).abs() < 0.01);
+    }
+
+    #[test]
+    fn sweep_misses() {
+        let wall = Obstacle::Rect {
+            center: Vec2::new(0.0, 0.0),
+            size: Vec2::new(10.0, 200.0),
+        };
+        // passes above the wall
+        assert_eq!(
+            wall.sweep(Vec2::new(-100.0, 150.0), Vec2::new(100.0, 150.0), 5.0),
+            None
+        );
+        // stops short of it
+        assert_eq!(
+            wall.sweep(Vec2::new(-100.0, 0.0), Vec2::new(-50.0, 0.0), 5.0),
+            None
+        );
+    }
+
+    #[test]
+    fn sweep_leaves_overlaps_and_still_creatures_to_the_discrete_test() {
+        let wall = Obstacle::Rect {
+            center: Vec2::new(0.0, 0.0),
+            size: Vec2::new(10.0, 200.0),
+        };
+        assert_eq!(
+            wall.sweep(Vec2::new(-8.0, 0.0), Vec2::new(100.0, 0.0), 5.0),
+            None
+        );
+
+        let still = Vec2::new(-100.0, 0.0);
+        assert_eq!(wall.sweep(still, still, 5.0), None);
+    }
+}
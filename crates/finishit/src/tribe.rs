@@ -0,0 +1,299 @@
+use bevy::prelude::*;
+
+use rand::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use strum::IntoEnumIterator;
+use strum_macros::EnumIter;
+
+use crate::agent::*;
+use crate::hud::*;
+use crate::level::*;
+use crate::util::*;
+
+// Standing of the main character with a tribe, from -1 to 1. The tribe is allied above
+// ALLY_STANDING and hostile below HOSTILE_STANDING.
+pub const ALLY_STANDING: f32 = 0.3;
+pub const HOSTILE_STANDING: f32 = -0.3;
+// Standing lost with a tribe when hurting one of its members, and gained with the
+// tribes that are hostile to it
+pub const STANDING_LOSS_PER_HIT: f32 = 0.1;
+pub const STANDING_GAIN_PER_HIT: f32 = 0.05;
+
+// Tribe-mates that see a fight join it with this probability, scaled by their altruism
+pub const RALLY_PROB: f32 = 0.8;
+// Probability per second that an NPC checks its territory for intruders
+pub const PATROL_RATE: f32 = 0.5;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash, EnumIter)]
+#[serde(rename_all = "snake_case")]
+pub enum Tribe {
+    A,
+    B,
+    C,
+}
+
+impl Tribe {
+    pub fn random_tribe(rng: &mut impl Rng) -> Tribe {
+        Tribe::iter().choose(rng).unwrap()
+    }
+
+    pub fn color(&self) -> Color {
+        match self {
+            // the outsiders, the main character among them, keep 8c114a
+            Tribe::A => Color::hex("4a8c11").unwrap(),
+            Tribe::B => Color::hex("116b8c").unwrap(),
+            Tribe::C => Color::hex("8c7411").unwrap(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Relation {
+    Ally,
+    Neutral,
+    Hostile,
+}
+
+/// Home of a tribe. Its members are born there and chase the intruders away.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Territory {
+    pub tribe: Tribe,
+    pub center: Vec2,
+    pub radius: f32,
+}
+
+impl Level {
+    pub fn territory_at(&self, position: Vec2) -> Option<&Territory> {
+        self.territories
+            .iter()
+            .find(|territory| territory.center.distance(position) < territory.radius)
+    }
+
+    /// Tribe of a creature born at `position`
+    pub fn tribe_at(&self, position: Vec2, rng: &mut impl Rng) -> Tribe {
+        self.territory_at(position)
+            .map(|territory| territory.tribe)
+            .unwrap_or_else(|| Tribe::random_tribe(rng))
+    }
+}
+
+/// How the tribes feel about each other, and about the main character
+pub struct TribeRelations {
+    pub between_tribes: HashMap<(Tribe, Tribe), Relation>,
+    pub standing: HashMap<Tribe, f32>,
+    pub territories: Vec<Territory>,
+}
+
+impl TribeRelations {
+    pub fn from_level(level: &Level) -> Self {
+        let mut between_tribes = HashMap::new();
+        for (a, b, relation) in [
+            (Tribe::A, Tribe::B, Relation::Hostile),
+            (Tribe::B, Tribe::C, Relation::Neutral),
+            (Tribe::C, Tribe::A, Relation::Neutral),
+        ] {
+            between_tribes.insert((a, b), relation);
+            between_tribes.insert((b, a), relation);
+        }
+
+        Self {
+            between_tribes,
+            standing: Tribe::iter().map(|tribe| (tribe, 0.0)).collect(),
+            territories: level.territories.clone(),
+        }
+    }
+
+    /// Standing of every tribe with the main character, in the order of the tribes
+    pub fn standings(&self) -> Vec<(Tribe, f32)> {
+        Tribe::iter()
+            .map(|tribe| (tribe, self.standing.get(&tribe).cloned().unwrap_or(0.0)))
+            .collect()
+    }
+
+    pub fn between(&self, a: Tribe, b: Tribe) -> Relation {
+        if a == b {
+            return Relation::Ally;
+        }
+        self.between_tribes
+            .get(&(a, b))
+            .cloned()
+            .unwrap_or(Relation::Neutral)
+    }
+
+    /// Relation of a tribe with the main character
+    pub fn with_main_character(&self, tribe: Tribe) -> Relation {
+        let standing = self.standing.get(&tribe).cloned().unwrap_or(0.0);
+        if standing > ALLY_STANDING {
+            Relation::Ally
+        } else if standing < HOSTILE_STANDING {
+            Relation::Hostile
+        } else {
+            Relation::Neutral
+        }
+    }
+
+    /// How `agent` feels about `other`. Guardians answer to their squads only.
+    pub fn relation(&self, agent: &Agent, other: &Agent) -> Relation {
+        self.relation_of((agent.id, agent.tribe), (other.id, other.tribe))
+    }
+
+    /// Same as `relation`, from the ids and tribes of the two agents
+    pub fn relation_of(
+        &self,
+        agent: (u32, Option<Tribe>),
+        other: (u32, Option<Tribe>),
+    ) -> Relation {
+        match (agent, other) {
+            ((_, Some(a)), (_, Some(b))) => self.between(a, b),
+            ((1, None), (_, Some(tribe))) | ((_, Some(tribe)), (1, None)) => {
+                self.with_main_character(tribe)
+            }
+            _ => Relation::Neutral,
+        }
+    }
+
+    /// The new relation of the tribe with the main character, when the change flips it
+    pub fn change_standing(&mut self, tribe: Tribe, change: f32) -> Option<Relation> {
+        let before = self.with_main_character(tribe);
+        let standing = self.standing.entry(tribe).or_insert(0.0);
+        *standing = (*standing + change).clamp(-1.0, 1.0);
+
+        let after = self.with_main_character(tribe);
+        if before != after {
+            Some(after)
+        } else {
+            None
+        }
+    }
+}
+
+// Tribe-mates of a hurt creature that saw the blow join the fight, and the main
+// character's standing follows whom it hurts
+pub fn tribe_reactions(
+    mut game: ResMut<Game>,
+    mut relations: ResMut<TribeRelations>,
+    mut world_rng: ResMut<WorldRng>,
+    time: Res<WorldTime>,
+    mut collision_events: EventReader<CollisionEvent>,
+    mut notifications: EventWriter<HudNotification>,
+) {
+    let now = time.seconds_since_startup() as f32;
+    let rng = &mut world_rng.rng;
+
+    for collision in collision_events.iter() {
+        let (victim, attacker) = match (
+            game.agents.get(&collision.agent_id),
+            game.agents.get(&collision.other_agent_id),
+        ) {
+            (Some(victim), Some(attacker)) => (victim.clone(), attacker.clone()),
+            _ => continue,
+        };
+
+        if victim.impact_damage(&attacker) <= 0.0 {
+            continue;
+        }
+
+        let victim_tribe = match victim.tribe {
+            Some(tribe) => tribe,
+            None => continue,
+        };
+
+        if attacker.id == 1 {
+            let mut changes = vec![(
+                victim_tribe,
+                relations.change_standing(victim_tribe, -STANDING_LOSS_PER_HIT),
+            )];
+            for tribe in Tribe::iter() {
+                if relations.between(tribe, victim_tribe) == Relation::Hostile {
+                    changes.push((
+                        tribe,
+                        relations.change_standing(tribe, STANDING_GAIN_PER_HIT),
+                    ));
+                }
+            }
+
+            for (tribe, relation) in changes {
+                if let Some(relation) = relation {
+                    notifications.send(HudNotification(format!(
+                        "Tribe {:?} is now {:?}",
+                        tribe, relation
+                    )));
+                }
+            }
+        }
+
+        for (id, agent) in game.agents.iter_mut() {
+            if *id == victim.id || *id == attacker.id || *id == 1 || !agent.alive {
+                continue;
+            }
+            if agent.is_guardian || !agent.sensors.agent_sight.contains_key(&victim.id) {
+                continue;
+            }
+
+            let p_rally = RALLY_PROB * agent.social.social_attributes.altruism;
+            if relations.relation(agent, &victim) == Relation::Ally
+                && relations.relation(agent, &attacker) != Relation::Ally
+                && rng.gen::<f32>() < p_rally
+            {
+                agent.goal = Goal::Bully(attacker.id);
+                agent.goal_time = now;
+            }
+        }
+    }
+}
+
+// NPCs at home chase away the hostile creatures that they see in their territory
+pub fn defend_territories(
+    mut game: ResMut<Game>,
+    relations: Res<TribeRelations>,
+    mut world_rng: ResMut<WorldRng>,
+    time: Res<WorldTime>,
+) {
+    let now = time.seconds_since_startup() as f32;
+    let rng = &mut world_rng.rng;
+
+    let others = game
+        .agents
+        .iter()
+        .filter(|(_id, agent)| agent.alive)
+        .map(|(id, agent)| (*id, (agent.position, agent.tribe)))
+        .collect::<HashMap<_, _>>();
+
+    for (id, agent) in game.agents.iter_mut() {
+        if *id == 1 || !agent.alive || agent.is_guardian {
+            continue;
+        }
+        if rng.gen::<f32>() > PATROL_RATE * time.delta_seconds() {
+            continue;
+        }
+        if let Goal::Bully(_) | Goal::Flee(_) = agent.goal {
+            continue;
+        }
+
+        let territory = match relations
+            .territories
+            .iter()
+            .find(|territory| Some(territory.tribe) == agent.tribe)
+        {
+            Some(territory) => territory,
+            None => continue,
+        };
+        if agent.position.distance(territory.center) > territory.radius {
+            continue;
+        }
+
+        let intruder = agent.sensors.agent_sight.keys().find(|other_id| {
+            others.get(other_id).map_or(false, |(position, tribe)| {
+                position.distance(territory.center) < territory.radius
+                    && relations.relation_of((agent.id, agent.tribe), (**other_id, *tribe))
+                        == Relation::Hostile
+            })
+        });
+
+        if let Some(intruder) = intruder {
+            agent.goal = Goal::Bully(*intruder);
+            agent.goal_time = now;
+        }
+    }
+}
@@ -3,6 +3,7 @@
 use bevy::prelude::*;
 
 use rand::prelude::*;
+use serde::{Deserialize, Serialize};
 use strum::IntoEnumIterator;
 use strum_macros::EnumIter;
 
@@ -10,9 +11,16 @@ use kdtree::distance::squared_euclidean;
 use kdtree::ErrorKind;
 use kdtree::KdTree;
 
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 
 use crate::agent::*;
+use crate::checkpoints::*;
+use crate::guardians::*;
+use crate::hud::*;
+use crate::level::*;
+use crate::population::*;
+use crate::terrain::*;
+use crate::tribe::*;
 use crate::*;
 
 // use crate::{ATOM_MULT, MASS_MULT};
@@ -26,9 +34,6 @@ pub const NUM_AGENTS: usize = 100;
 pub const NUM_ITEMS: usize = 10;
 pub const NUM_FOODS: usize = 5000;
 
-pub const LEVEL_WIDTH: f32 = 5000.0;
-pub const LEVEL_HEIGHT: f32 = 7000.0;
-
 pub const MAIN_CHARA_Z: f32 = 0.1;
 pub const TOTAL_BOOST_TIME: f32 = 0.3;
 
@@ -36,13 +41,6 @@ pub const MASS_MULT: f32 = 1000.0;
 
 pub const ATOM_MULT: f32 = 0.14;
 
-pub const BOTTOM_STAGE_LIMIT: f32 = 0.05;
-pub const MID_STAGE_LIMIT: f32 = 0.2;
-pub const TOP_STAGE_LIMIT: f32 = 0.98;
-
-pub const BOTTOM_LIMIT_X_MIN: f32 = 0.3;
-pub const BOTTOM_LIMIT_X_MAX: f32 = 0.7;
-
 pub const MASS_EXCHANGE_RATE: f32 = 0.03;
 
 pub const COLLISION_BOUNCE: f32 = 4.0;
@@ -50,7 +48,6 @@ pub const COLLISION_BOUNCE: f32 = 4.0;
 pub const ENERGY_INCREASE_RATE: f32 = 0.03;
 
 pub const ENERGY_DECAY_RATE: f32 = 0.0001;
-pub const ENERGY_REGAIN_RATE: f32 = 0.01;
 
 pub const ENERGY_GROUND_STATE: f32 = 1.0;
 
@@ -60,7 +57,9 @@ type TeamId = u32;
 pub struct NPC;
 
 #[derive(Component)]
-pub struct FoodComp;
+pub struct FoodComp {
+    pub id: u32,
+}
 
 #[derive(Component)]
 pub struct StartText;
@@ -71,7 +70,12 @@ pub struct CollisionEvent {
     pub other_is_guardian: bool,
 }
 
-#[derive(Clone, Debug)]
+pub struct BoostEvent {
+    pub agent_id: u32,
+    pub position: Vec2,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Team {
     pub id: TeamId,
     pub total_mass: f32,
@@ -83,6 +87,10 @@ pub struct KdTrees {
     pub agent_kdtree: KdTree<f32, u32, [f32; 2]>,
     pub item_kdtree: KdTree<f32, u32, [f32; 2]>,
     pub food_kdtree: KdTree<f32, u32, [f32; 2]>,
+    /// Food moved since the food tree was built, see `drift_food`
+    pub food_drifted: bool,
+    /// World time of the last build of the food tree by `drift_food`
+    pub food_built_at: f32,
 }
 
 impl KdTrees {
@@ -91,16 +99,18 @@ impl KdTrees {
             agent_kdtree: KdTree::with_capacity(2, NUM_AGENTS),
             item_kdtree: KdTree::with_capacity(2, NUM_ITEMS),
             food_kdtree: KdTree::with_capacity(2, NUM_FOODS),
+            food_drifted: false,
+            food_built_at: 0.0,
         }
     }
 
     pub fn populate(&mut self, game: &Game) {
         self.gen_agent_kdtree(&game.agents);
         // self.gen_item_kdtree(&game.items);
-        // self.gen_food_kdtree(&game.foods);
+        self.gen_food_kdtree(&game.foods);
     }
 
-    pub fn gen_agent_kdtree(&mut self, agents: &HashMap<u32, Agent>) {
+    pub fn gen_agent_kdtree(&mut self, agents: &BTreeMap<u32, Agent>) {
         let dimensions = 2;
         // let rng = rand::thread_rng();
         let mut kdtree = KdTree::with_capacity(dimensions, NUM_AGENTS);
@@ -127,33 +137,80 @@ impl KdTrees {
     //     self.item_kdtree = kdtree;
     // }
 
-    // pub fn gen_food_kdtree(&mut self, foods: &HashMap<u32, Food>) {
-    //     let dimensions = 2;
-    //     let mut kdtree = KdTree::with_capacity(dimensions, 9);
-    //     foods.iter().for_each(|(id, food)| {
-    //         kdtree.add([food.position.x, food.position.y], *id).unwrap();
-    //     });
-    //     self.food_kdtree = kdtree;
-    //     // kdtree
-    // }
+    pub fn gen_food_kdtree(&mut self, foods: &BTreeMap<u32, Food>) {
+        let dimensions = 2;
+        let mut kdtree = KdTree::with_capacity(dimensions, NUM_FOODS);
+        foods.iter().for_each(|(id, food)| {
+            if food.position.x.is_finite() && food.position.y.is_finite() {
+                kdtree.add([food.position.x, food.position.y], *id).unwrap();
+            }
+        });
+        self.food_kdtree = kdtree;
+    }
+}
+
+/// The randomness of the world: what spawns where, and what the creatures decide.
+/// Every run is seeded, so that the same seed gives back the same run.
+pub struct WorldRng {
+    pub seed: u64,
+    pub rng: StdRng,
+}
+
+impl WorldRng {
+    pub fn new(seed: u64) -> Self {
+        Self {
+            seed,
+            rng: StdRng::seed_from_u64(seed),
+        }
+    }
 }
 
+/// The clock of the world. It only runs while the game is played, from zero at the start
+/// of each run, and replays drive it with the frame times that they recorded. Read like
+/// `Time`, see `advance_world_time`.
+#[derive(Default)]
+pub struct WorldTime {
+    delta: f32,
+    elapsed: f64,
+}
+
+impl WorldTime {
+    pub fn advance(&mut self, delta: f32) {
+        self.delta = delta;
+        self.elapsed += delta as f64;
+    }
+
+    pub fn delta_seconds(&self) -> f32 {
+        self.delta
+    }
+
+    /// Seconds since the start of the run
+    pub fn seconds_since_startup(&self) -> f64 {
+        self.elapsed
+    }
+}
+
+// The agents and the food are kept in the order of their ids, so that going through them
+// draws from the world rng in the same order on every run
+#[derive(Serialize, Deserialize, Clone)]
 pub struct Game {
     pub time: f32,
     pub game_stage: GameStage,
-    pub agents: HashMap<u32, Agent>,
+    pub agents: BTreeMap<u32, Agent>,
     // pub items: HashMap<u32, Item>,
-    pub foods: HashMap<u32, Food>,
+    pub foods: BTreeMap<u32, Food>,
 
     pub teams: HashMap<TeamId, Team>,
+    pub guardian_squads: Vec<GuardianSquad>,
     pub won: bool,
 }
 
 impl Game {
-    pub fn new() -> Game {
-        let agents = Self::gen_game_agents(NUM_AGENTS);
+    pub fn new(level: &Level, rng: &mut StdRng) -> Game {
+        let mut agents = Self::gen_game_agents(level, rng);
+        let guardian_squads = level.guardians.spawn_squads(&mut agents, rng);
         // let items = Self::gen_items(NUM_ITEMS);
-        let foods = Self::gen_foods(NUM_FOODS);
+        let foods = Self::gen_foods(level, rng);
 
         // println!("generating");
 
@@ -166,6 +223,7 @@ impl Game {
             foods: foods,
 
             teams: HashMap::new(),
+            guardian_squads,
             won: false,
         }
     }
@@ -189,71 +247,40 @@ impl Game {
     //     agents
     // }
 
-    pub fn gen_game_agents(num_agents: usize) -> HashMap<u32, Agent> {
-        let mut rng = rand::thread_rng();
-        let mut agents = HashMap::new();
-        (0..num_agents / 2).for_each(|_| {
+    pub fn gen_game_agents(level: &Level, rng: &mut StdRng) -> BTreeMap<u32, Agent> {
+        let mut agents = BTreeMap::new();
+        (0..level.spawns.bottom.agents).for_each(|_| {
             //
             // let random_stage = GameStage::iter().choose(&mut rng).unwrap();
             let id: u32 = rng.gen();
             // avoid accidentally duplicating the main character's id
             if id != 1 {
-                let random_agent = Agent::gen_random(&GameStage::Bottom, id);
+                let random_agent = Agent::gen_random(&GameStage::Bottom, id, level, rng);
 
                 agents.insert(id, random_agent);
             }
         });
 
-        (0..num_agents / 2).for_each(|_| {
+        (0..level.spawns.mid.agents).for_each(|_| {
             //
 
             let id: u32 = rng.gen();
             // avoid accidentally duplicating the main character's id
             if id != 1 {
-                let random_agent = Agent::gen_random(&GameStage::Mid, id);
+                let random_agent = Agent::gen_random(&GameStage::Mid, id, level, rng);
 
                 agents.insert(id, random_agent);
             }
         });
 
-        // guardians have ids between 20 and 40
-        (0..5).for_each(|k| {
-            //
-
-            // let id: u32 = rng.gen();
-            let x_pos = LEVEL_WIDTH / 2.0 * k as f32 / 10.0 * 0.95;
-            let pos = Vec2::new(x_pos, LEVEL_HEIGHT - 2000.0);
-            // avoid accidentally duplicating the main character's id
-
-            let random_agent = Agent::gen_guardian(pos, k + 20);
-
-            agents.insert(k + 20, random_agent);
-        });
-
-        (5..10).for_each(|k| {
-            //
-
-            // let id: u32 = rng.gen();
-            let x_pos = LEVEL_WIDTH - LEVEL_WIDTH * (k - 5) as f32 / 10.0 * 0.95;
-            let pos = Vec2::new(x_pos, LEVEL_HEIGHT - 2000.0);
-            // avoid accidentally duplicating the main character's id
-
-            let random_agent = Agent::gen_guardian(pos, k + 20);
-
-            agents.insert(k + 20, random_agent);
-        });
-
-        (0..10).for_each(|k| {
-            //
-
-            // let id: u32 = rng.gen();
-            let x_pos = LEVEL_WIDTH * k as f32 / 10.0;
-            let pos = Vec2::new(x_pos, LEVEL_HEIGHT - 4000.0);
+        (0..level.spawns.top.agents).for_each(|_| {
+            let id: u32 = rng.gen();
             // avoid accidentally duplicating the main character's id
+            if id != 1 {
+                let random_agent = Agent::gen_random(&GameStage::Top, id, level, rng);
 
-            let random_agent = Agent::gen_guardian(pos, k + 30);
-
-            agents.insert(k + 30, random_agent);
+                agents.insert(id, random_agent);
+            }
         });
 
         // (0..10).for_each(|k| {
@@ -289,79 +316,15 @@ impl Game {
     // }
 
     // TODO
-    pub fn gen_foods(num_foods: usize) -> HashMap<u32, Food> {
-        let mut rng = rand::thread_rng();
-        let mut foods = HashMap::new();
-
-        // let id: u32 = ;
-
-        (0..num_foods).for_each(|_| {
-            let y: f32 = rng.gen_range(0.0..1.0) * LEVEL_HEIGHT;
-            let x: f32 = rng.gen_range(0.0..1.0) * LEVEL_WIDTH;
-
-            foods.insert(
-                rng.gen::<u32>(),
-                Food {
-                    position: Vec2::new(x, y),
-                    energy: rng.gen_range(0.0..0.02),
-                    mass: rng.gen_range(0.0..0.02),
-                    id: rng.gen::<u32>(),
-                    acc: Vec2::new(0.0, 0.0),
-                },
-            );
-
-            // let stage = GameStage::iter().choose(&mut rng).unwrap();
-            // match stage {
-            //     GameStage::Bottom => {
-            //         let y: f32 = rng.gen_range(0.0..BOTTOM_STAGE_LIMIT) * LEVEL_HEIGHT;
-            //         let x: f32 =
-            //             rng.gen_range(BOTTOM_LIMIT_X_MIN..BOTTOM_LIMIT_X_MAX) * LEVEL_WIDTH;
-
-            //         foods.insert(
-            //             rng.gen::<u32>(),
-            //             Food {
-            //                 position: Vec2::new(x, y),
-            //                 energy: rng.gen_range(0.0..0.02),
-            //                 mass: rng.gen_range(0.0..0.02),
-            //                 id: rng.gen::<u32>(),
-            //                 acc: Vec2::new(0.0, 0.0),
-            //             },
-            //         );
-            //     }
-
-            //     GameStage::Mid => {
-            //         let y: f32 = rng.gen_range(BOTTOM_STAGE_LIMIT..MID_STAGE_LIMIT) * LEVEL_HEIGHT;
-            //         let x: f32 = rng.gen_range(0.0..1.0) * LEVEL_WIDTH;
-
-            //         foods.insert(
-            //             rng.gen::<u32>(),
-            //             Food {
-            //                 position: Vec2::new(x, y),
-            //                 energy: rng.gen_range(BOTTOM_STAGE_LIMIT..MID_STAGE_LIMIT),
-            //                 mass: rng.gen_range(BOTTOM_STAGE_LIMIT..MID_STAGE_LIMIT),
-            //                 id: rng.gen::<u32>(),
-            //                 acc: Vec2::new(0.0, 0.0),
-            //             },
-            //         );
-            //     }
+    pub fn gen_foods(level: &Level, rng: &mut StdRng) -> BTreeMap<u32, Food> {
+        let mut foods = BTreeMap::new();
 
-            //     GameStage::Top => {
-            //         let y: f32 = rng.gen_range(MID_STAGE_LIMIT..TOP_STAGE_LIMIT) * LEVEL_HEIGHT;
-            //         let x: f32 = rng.gen_range(0.0..TOP_STAGE_LIMIT) * LEVEL_WIDTH;
-
-            //         foods.insert(
-            //             rng.gen::<u32>(),
-            //             Food {
-            //                 position: Vec2::new(x, y),
-            //                 energy: rng.gen_range(MID_STAGE_LIMIT..TOP_STAGE_LIMIT),
-            //                 mass: rng.gen_range(MID_STAGE_LIMIT..TOP_STAGE_LIMIT),
-            //                 id: rng.gen::<u32>(),
-            //                 acc: Vec2::new(0.0, 0.0),
-            //             },
-            //         );
-            //     } // _ => {}
-            // }
-        });
+        for stage in GameStage::iter() {
+            (0..level.spawn_table(&stage).foods).for_each(|_| {
+                let food = Food::gen_random(level, &stage, rng);
+                foods.insert(food.id, food);
+            });
+        }
 
         foods
     }
@@ -449,7 +412,7 @@ impl Game {
 //     }
 // }
 
-#[derive(Clone, Debug, Copy, EnumIter, PartialEq)]
+#[derive(Serialize, Deserialize, Clone, Debug, Copy, EnumIter, PartialEq)]
 pub enum ItemType {
     Propeller,
     FoodVacuum,
@@ -467,14 +430,14 @@ impl ItemType {
     }
 }
 
-#[derive(Clone, Debug, PartialEq, EnumIter)]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, EnumIter)]
 pub enum GameStage {
     Bottom,
     Mid,
     Top,
 }
 
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub struct Food {
     pub position: Vec2,
     pub energy: f32,
@@ -483,7 +446,19 @@ pub struct Food {
     pub acc: Vec2,
 }
 
-#[derive(Clone, Debug)]
+impl Food {
+    pub fn gen_random(level: &Level, stage: &GameStage, rng: &mut StdRng) -> Food {
+        Food {
+            position: level.random_food_position(stage, rng),
+            energy: rng.gen_range(0.0..0.02),
+            mass: rng.gen_range(0.0..0.02),
+            id: rng.gen::<u32>(),
+            acc: Vec2::new(0.0, 0.0),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub enum Direction {
     North,
     NorthEast,
@@ -538,7 +513,8 @@ pub fn take_pos(c: CharacterSaveFormat) -> Vec<Vec2> {
 pub fn see(
     mut game: ResMut<Game>,
     kdtrees: ResMut<KdTrees>,
-    time: Res<Time>,
+    time: Res<WorldTime>,
+    terrain: Res<Terrain>,
     mut commands: Commands,
     query_debug: Query<Entity, With<DebugQuad>>,
 ) {
@@ -577,7 +553,15 @@ pub fn see(
                     continue;
                 }
 
-                let other_agent = all_agents.get(&id).unwrap();
+                let other_agent = match all_agents.get(&id) {
+                    Some(other_agent) => other_agent,
+                    None => continue,
+                };
+
+                // nobody sees through the rocks
+                if !terrain.line_of_sight(agent.position, other_agent.position) {
+                    continue;
+                }
                 // let other_agent_pos = other_agent.position;
                 let direction_to_other_agent = other_agent.position - agent.position;
 
@@ -641,51 +625,148 @@ pub fn see(
             //     }
             // }
 
-            // if let Ok(dist_id_array) = kdtrees.food_kdtree.within(
-            //     &[agent.position.x, agent.position.y],
-            //     agent.sensors.sight_range,
-            //     &squared_euclidean,
-            // ) {
-            //     //
-            //     // agent.sensors.sight.
-
-            //     for (dist, id) in dist_id_array {
-            //         let food = all_foods.get(&id).unwrap();
-            //         // let other_agent_pos = other_agent.position;
-            //         let direction_to_food = food.position - agent.position;
-
-            //         // let dir_to_other_angle = direction_to_other_agent.y.atan2(direction_to_other_agent.x);
-            //         let agent_looking_direction =
-            //             Vec2::new(agent.look_at_angle.cos(), agent.look_at_angle.sin());
-            //         // if the angles differ by more than 180 degrees, then the other agent is on the other side of the agent
-            //         // if agent_looking_direction.dot(direction_to_food) > 0.0 {
-            //         agent.update_food_sight(time.seconds_since_startup() as f32, dist, food);
-
-            //         // despawn and spawn green cube upon seeing
-            //         // }
-            //     }
-            // }
+            // only the closest food is remembered
+            if let Ok(dist_id_array) = kdtrees.food_kdtree.nearest(
+                &[agent.position.x, agent.position.y],
+                1,
+                &squared_euclidean,
+            ) {
+                for (dist, id) in dist_id_array {
+                    if dist > agent.sensors.sight_range * agent.sensors.sight_range {
+                        continue;
+                    }
+                    if let Some(food) = all_foods.get(&id) {
+                        if !terrain.line_of_sight(agent.position, food.position) {
+                            continue;
+                        }
+                        agent.update_food_sight(
+                            time.seconds_since_startup() as f32,
+                            dist.sqrt(),
+                            food,
+                        );
+                    }
+                }
+            }
         }
     }
 }
 
-pub fn forget(mut game: ResMut<Game>, kdtrees: ResMut<KdTrees>, time: Res<Time>) {
-    let mut rng = thread_rng();
+pub fn forget(
+    mut game: ResMut<Game>,
+    kdtrees: ResMut<KdTrees>,
+    mut world_rng: ResMut<WorldRng>,
+    time: Res<WorldTime>,
+) {
+    let rng = &mut world_rng.rng;
 
     for (_hash_id, mut agent) in &mut game.agents {
         // run once every ten frames on average
         if rng.gen::<f32>() < 0.1 {
             agent.forget_agents(time.seconds_since_startup() as f32);
             // agent.forget_items(time.seconds_since_startup() as f32);
-            // agent.forget_food(time.seconds_since_startup() as f32);
+            agent.forget_food(time.seconds_since_startup() as f32);
+            agent.forget_ores(time.seconds_since_startup() as f32);
         }
     }
 }
 
+pub fn boost_impulse(time: f32) -> f32 {
+    let rise_time = 0.7 * TOTAL_BOOST_TIME;
+    let fall_time = 0.3 * TOTAL_BOOST_TIME;
+    if time < rise_time {
+        return time / rise_time;
+    } else if time < TOTAL_BOOST_TIME {
+        return 1.0 - (time - rise_time) / fall_time;
+    }
+    return 0.0;
+}
+
 pub fn sigmoid(x: f32, sign: f32, up: f32, lo: f32, slope: f32, attr: f32) -> f32 {
     lo + (up - lo) * ((1.0 - sign) / 2.0 + sign / (1.0 + slope * (x - 1.0 + attr).exp()))
 }
 
+/// Fraction of the sweep from `start` to `end`, in [0, 1], at which a circle first touches
+/// a static circle centered at `center`. `radius` is the sum of the two radii.
+/// Circles that already overlap at the start of the sweep are left to the discrete test.
+pub fn swept_circle_toi(start: Vec2, end: Vec2, center: Vec2, radius: f32) -> Option<f32> {
+    let sweep = end - start;
+    let from_center = start - center;
+
+    let c = from_center.dot(from_center) - radius * radius;
+    if c <= 0.0 {
+        return None;
+    }
+
+    let a = sweep.dot(sweep);
+    if a < f32::EPSILON {
+        return None;
+    }
+
+    let b = 2.0 * from_center.dot(sweep);
+    let discriminant = b * b - 4.0 * a * c;
+    if discriminant < 0.0 {
+        return None;
+    }
+
+    let toi = (-b - discriminant.sqrt()) / (2.0 * a);
+    if (0.0..=1.0).contains(&toi) {
+        Some(toi)
+    } else {
+        None
+    }
+}
+
+/// Keeps a circle of radius `radius` moving from `last` to `new` inside the level.
+/// The move is swept against the floor and the side walls, so a fast agent is stopped
+/// at the point where it first touches a wall instead of being clamped after the fact.
+pub fn sweep_level_bounds(
+    last: Vec2,
+    new: Vec2,
+    radius: f32,
+    level_width: f32,
+    wall_bounce: f32,
+    bottom_bounce: f32,
+) -> Vec2 {
+    let bottom_most_pos = radius;
+    let left_most_pos = radius;
+    let right_most_pos = level_width - radius;
+
+    let sweep = new - last;
+    let mut toi = 1.0_f32;
+
+    let hit_bottom = new.y < bottom_most_pos;
+    let hit_left = new.x < left_most_pos;
+    let hit_right = new.x > right_most_pos;
+
+    if hit_bottom && sweep.y < 0.0 {
+        toi = toi.min(((bottom_most_pos - last.y) / sweep.y).clamp(0.0, 1.0));
+    }
+    if hit_left && sweep.x < 0.0 {
+        toi = toi.min(((left_most_pos - last.x) / sweep.x).clamp(0.0, 1.0));
+    }
+    if hit_right && sweep.x > 0.0 {
+        toi = toi.min(((right_most_pos - last.x) / sweep.x).clamp(0.0, 1.0));
+    }
+
+    // stop at the first contact point along the move
+    let mut pos = last + sweep * toi;
+
+    // cannot fall below the ground
+    if hit_bottom {
+        pos.y = pos.y.max(bottom_most_pos) + bottom_bounce;
+    }
+
+    // bounce off the walls
+    if hit_left {
+        pos.x = pos.x.max(left_most_pos) + wall_bounce;
+    }
+    if hit_right {
+        pos.x = pos.x.min(right_most_pos) - wall_bounce;
+    }
+
+    pos
+}
+
 pub struct PosMass {
     pub position: Vec2,
     pub mass: f32,
@@ -723,16 +804,6 @@ pub fn compute_acc_food(mut game: ResMut<Game>, kdtrees: Res<KdTrees>) {
     });
 }
 
-pub fn eat_food(mut game: ResMut<Game>, kdtrees: Res<KdTrees>) {
-    let mut foods_to_remove: Vec<u32> = Vec::new();
-
-    foods_to_remove.iter().for_each(|id| {
-        game.foods.remove(id);
-
-        // TODO: despawn food
-    });
-}
-
 pub fn load_character_auto(
     keyboard: Res<Input<KeyCode>>,
     mut query: Query<&mut MarkerInstanceMatData>,
@@ -770,6 +841,114 @@ pub struct AgentCollisionInfo {
     pub is_guardian2: bool,
 }
 
+impl AgentCollisionInfo {
+    pub fn new(
+        agent: &Agent,
+        other_agent: &Agent,
+        atom_entity1: Entity,
+        atom_entity2: Entity,
+    ) -> Self {
+        let m_ratio1 = 2.0 * other_agent.mass / (other_agent.mass + agent.mass);
+        let m_ratio2 = 2.0 * agent.mass / (other_agent.mass + agent.mass);
+
+        let mut collision_line = other_agent.position - agent.position;
+        if collision_line != Vec2::ZERO {
+            collision_line = collision_line.normalize();
+        } else {
+            collision_line = Vec2::new(1.0, 0.0);
+        }
+
+        let velocity1 = -collision_line * m_ratio1 * 4.0;
+        let velocity2 = collision_line * m_ratio2 * 4.0;
+
+        AgentCollisionInfo {
+            agent_id1: agent.id,
+            atom_entity1,
+            other_collision_mass1: other_agent.mass,
+            is_guardian1: agent.is_guardian,
+            velocity1,
+            agent_id2: other_agent.id,
+            atom_entity2,
+            other_collision_mass2: agent.mass,
+            velocity2,
+            is_guardian2: other_agent.is_guardian,
+        }
+    }
+}
+
+/// An agent that moved farther than one atom during the last step and whose
+/// swept path crossed another agent.
+pub struct SweptHit {
+    pub agent_id: u32,
+    pub other_agent_id: u32,
+    pub toi: f32,
+}
+
+/// Continuous collision test for fast agents (boosts, stage 3 throttle).
+/// The discrete atom test in `collisions` misses agents that jump over each other
+/// in a single frame, so the path between `last_position` and `position` is swept
+/// against the path of every agent near it.
+pub fn find_swept_hits(game: &Game, kdtrees: &KdTrees, skip: &[u32]) -> Vec<SweptHit> {
+    let mut hits: Vec<SweptHit> = Vec::new();
+
+    for (id, agent) in game.agents.iter() {
+        if skip.contains(id) {
+            continue;
+        }
+
+        let travel = agent.position - agent.last_position;
+        let travel_length = travel.length();
+
+        // the atom size is ATOM_MULT * mass * MASS_MULT, which is also the agent radius
+        if travel_length < agent.radius {
+            continue;
+        }
+
+        let mid_point = agent.last_position + travel * 0.5;
+        let search_radius = travel_length * 0.5 + agent.mass * MASS_MULT;
+
+        let mut earliest: Option<SweptHit> = None;
+
+        if let Ok(dist_id_array) = kdtrees.agent_kdtree.within(
+            &[mid_point.x, mid_point.y],
+            search_radius * search_radius,
+            &squared_euclidean,
+        ) {
+            for (_dist, other_id) in dist_id_array {
+                if other_id == id || skip.contains(other_id) {
+                    continue;
+                }
+
+                if let Some(other_agent) = game.agents.get(other_id) {
+                    // relative motion: the other agent is held still at the origin
+                    let toi = swept_circle_toi(
+                        agent.last_position - other_agent.last_position,
+                        agent.position - other_agent.position,
+                        Vec2::ZERO,
+                        agent.radius + other_agent.radius,
+                    );
+
+                    if let Some(toi) = toi {
+                        if earliest.as_ref().map_or(true, |hit| toi < hit.toi) {
+                            earliest = Some(SweptHit {
+                                agent_id: *id,
+                                other_agent_id: *other_id,
+                                toi,
+                            });
+                        }
+                    }
+                }
+            }
+        }
+
+        if let Some(hit) = earliest {
+            hits.push(hit);
+        }
+    }
+
+    hits
+}
+
 pub fn collisions(
     mut game: ResMut<Game>,
     mut kdtrees: ResMut<KdTrees>,
@@ -803,6 +982,9 @@ pub fn collisions(
             &squared_euclidean,
         ) {
             // the closest is itself, so we ignore the first item
+            if closest_agents.len() < 2 {
+                continue;
+            }
             let closest_agent_id = closest_agents[1].1;
 
             if collisioned_agents.contains(&closest_agent_id) {
@@ -811,7 +993,11 @@ pub fn collisions(
 
             let closest_agent_dist = closest_agents[1].0;
 
-            let closest_agent = game.agents.get(&closest_agent_id).unwrap();
+            // the agent may have died since the kdtree was built
+            let closest_agent = match game.agents.get(&closest_agent_id) {
+                Some(closest_agent) => closest_agent,
+                None => continue,
+            };
 
             let mut agent_pair_checked = Vec::new();
 
@@ -839,8 +1025,15 @@ pub fn collisions(
 
                     //     for (k, other_atom) in other_body.iter().filter(|b| b.is_used).enumerate() {
 
-                    let atoms1 = atoms_query.get(agent.entity.unwrap()).unwrap();
-                    let atoms2 = atoms_query.get(closest_agent.entity.unwrap()).unwrap();
+                    let (atoms1, atoms2) = match (
+                        agent.entity.and_then(|entity| atoms_query.get(entity).ok()),
+                        closest_agent
+                            .entity
+                            .and_then(|entity| atoms_query.get(entity).ok()),
+                    ) {
+                        (Some(atoms1), Some(atoms2)) => (atoms1, atoms2),
+                        _ => continue,
+                    };
 
                     for child1 in atoms1.iter() {
                         let mut found_collision = false;
@@ -863,43 +1056,12 @@ pub fn collisions(
                                 collisioned_agents.push(&closest_agent.id);
                                 collisioned_agents.push(&agent.id);
 
-                                let u1 = agent.velocity;
-                                let u2 = Vec2::new(
-                                    closest_agent.look_at_angle.cos() * closest_agent.speed,
-                                    closest_agent.look_at_angle.sin() * closest_agent.speed,
+                                let collision = AgentCollisionInfo::new(
+                                    agent,
+                                    closest_agent,
+                                    *child1,
+                                    child2.clone(),
                                 );
-
-                                let m_ratio1 =
-                                    2.0 * closest_agent.mass / (closest_agent.mass + agent.mass);
-                                let m_ratio2 = 2.0 * agent.mass / (closest_agent.mass + agent.mass);
-
-                                let mut collision_line = closest_agent.position - agent.position;
-                                if collision_line != Vec2::ZERO {
-                                    collision_line = collision_line.normalize();
-                                } else {
-                                    collision_line = Vec2::new(1.0, 0.0);
-                                }
-
-                                let velocity1 = -collision_line * m_ratio1 * 4.0;
-                                let velocity2 = collision_line * m_ratio2 * 4.0;
-
-                                let other_collision_mass1 = closest_agent.mass;
-                                let other_collision_mass2 = agent.mass;
-
-                                let collision = AgentCollisionInfo {
-                                    agent_id1: agent.id,
-                                    atom_entity1: *child1,
-                                    other_collision_mass1,
-                                    is_guardian1: agent.is_guardian,
-                                    // velocity1: v1,
-                                    velocity1,
-                                    agent_id2: closest_agent.id,
-                                    atom_entity2: child2.clone(),
-                                    other_collision_mass2,
-                                    velocity2,
-                                    is_guardian2: closest_agent.is_guardian,
-                                    // velocity2: v2,
-                                };
                                 // println!("collision: {:?}", collision);
 
                                 collisions.push(collision);
@@ -936,69 +1098,127 @@ pub fn collisions(
         }
     }
 
-    let mut id_cache = Vec::new();
-    for collision in collisions {
-        /////////////// agent 1 /////////////////////////////////////////////////////////
-        let mut agent = game.agents.get_mut(&collision.agent_id1).unwrap();
+    let collisioned_agents = collisioned_agents
+        .iter()
+        .map(|id| **id)
+        .collect::<Vec<u32>>();
 
-        if !agent.just_collided {
-            id_cache.push(agent.id);
+    let swept_hits = find_swept_hits(&game, &kdtrees, &collisioned_agents);
 
-            // here no properties are changed, just information about the collision
-            agent.last_position = agent.position - collision.velocity1 * COLLISION_BOUNCE;
-            agent.just_collided = true;
-            agent.other_collider_mass = collision.other_collision_mass1;
+    // bring the fast agents, and the agents they hit, back to the point of impact before
+    // the collision line is taken, so they never end up on the other side of each other
+    let mut rewound_agents = Vec::new();
+    for hit in swept_hits.iter() {
+        for id in [hit.agent_id, hit.other_agent_id] {
+            if rewound_agents.contains(&id) {
+                continue;
+            }
+            if let Some(agent) = game.agents.get_mut(&id) {
+                agent.position =
+                    agent.last_position + (agent.position - agent.last_position) * hit.toi;
+                rewound_agents.push(id);
+            }
+        }
+    }
 
-            collision_event.send(CollisionEvent {
-                agent_id: collision.agent_id1,
-                other_agent_id: collision.agent_id2,
-                other_is_guardian: collision.is_guardian2,
-            });
+    for hit in swept_hits.iter() {
+        let (agent, other_agent) = match (
+            game.agents.get(&hit.agent_id),
+            game.agents.get(&hit.other_agent_id),
+        ) {
+            (Some(agent), Some(other_agent)) => (agent, other_agent),
+            _ => continue,
+        };
+
+        let atom1 = agent
+            .entity
+            .and_then(|entity| atoms_query.get(entity).ok())
+            .and_then(|children| children.first().copied());
+        let atom2 = other_agent
+            .entity
+            .and_then(|entity| atoms_query.get(entity).ok())
+            .and_then(|children| children.first().copied());
+
+        if let (Some(atom1), Some(atom2)) = (atom1, atom2) {
+            collisions.push(AgentCollisionInfo::new(agent, other_agent, atom1, atom2));
+        }
+    }
+
+    let mut id_cache = Vec::new();
+    for collision in collisions {
+        /////////////// agent 1 /////////////////////////////////////////////////////////
+        if let Some(agent) = game.agents.get_mut(&collision.agent_id1) {
+            if !agent.just_collided {
+                id_cache.push(agent.id);
+
+                // here no properties are changed, just information about the collision
+                agent.last_position = agent.position - collision.velocity1 * COLLISION_BOUNCE;
+                agent.just_collided = true;
+                agent.other_collider_mass = collision.other_collision_mass1;
+
+                collision_event.send(CollisionEvent {
+                    agent_id: collision.agent_id1,
+                    other_agent_id: collision.agent_id2,
+                    other_is_guardian: collision.is_guardian2,
+                });
+            }
         }
 
         /////////////// agent 2 /////////////////////////////////////////////////////////
 
-        let mut closest_agent = game.agents.get_mut(&collision.agent_id2).unwrap();
-        if !closest_agent.just_collided {
-            id_cache.push(closest_agent.id);
+        if let Some(closest_agent) = game.agents.get_mut(&collision.agent_id2) {
+            if !closest_agent.just_collided {
+                id_cache.push(closest_agent.id);
 
-            closest_agent.last_position = closest_agent.position - collision.velocity2;
-            closest_agent.just_collided = true;
-            closest_agent.other_collider_mass = collision.other_collision_mass2;
+                closest_agent.last_position = closest_agent.position - collision.velocity2;
+                closest_agent.just_collided = true;
+                closest_agent.other_collider_mass = collision.other_collision_mass2;
 
-            collision_event.send(CollisionEvent {
-                agent_id: collision.agent_id2,
-                other_agent_id: collision.agent_id1,
-                other_is_guardian: collision.is_guardian1,
-            });
+                collision_event.send(CollisionEvent {
+                    agent_id: collision.agent_id2,
+                    other_agent_id: collision.agent_id1,
+                    other_is_guardian: collision.is_guardian1,
+                });
+            }
         }
     }
 }
 
-pub fn agent_decisions(mut game: ResMut<Game>, time: Res<Time>) {
-    let mut rng = rand::thread_rng();
+pub fn agent_decisions(
+    mut game: ResMut<Game>,
+    relations: Res<TribeRelations>,
+    mut world_rng: ResMut<WorldRng>,
+    time: Res<WorldTime>,
+) {
+    let rng = &mut world_rng.rng;
 
     for (id, agent) in game.agents.iter_mut() {
         if *id == 1 {
             // println!("sightings: {:?}", agent.sensors.agent_sight);
         }
+        // guardians follow the orders of their squad, see `guardians`
+        if agent.is_guardian {
+            continue;
+        }
+
         // make a decision once per 10 frames on average
 
         // if the past goal has been going on for too long, change it
         if time.seconds_since_startup() as f32 - agent.goal_time > agent.memory_time {
-            for (seen_agent_id, _agent_sighting) in agent.sensors.agent_sight.iter() {
-                //
-                if agent.is_guardian {
-                    if seen_agent_id == &1 {
-                        agent.goal = Goal::Bully(seen_agent_id.clone());
-                        agent.goal_time = time.seconds_since_startup() as f32;
-                        break;
-                    }
-                }
-
+            for (seen_agent_id, agent_sighting) in agent.sensors.agent_sight.iter() {
                 if rng.gen::<f32>() < 0.1 {
                     if *seen_agent_id != agent.last_agent_hit {
-                        if rng.gen::<f32>() < 0.2 {
+                        // allies are left alone, enemies are picked on
+                        let eagerness = match relations.relation_of(
+                            (agent.id, agent.tribe),
+                            (*seen_agent_id, agent_sighting.tribe),
+                        ) {
+                            Relation::Ally => continue,
+                            Relation::Neutral => 0.2,
+                            Relation::Hostile => 0.5,
+                        };
+                        // wounded agents are less eager to pick a fight
+                        if rng.gen::<f32>() < eagerness * agent.health_ratio() {
                             agent.goal = Goal::Bully(seen_agent_id.clone());
                             agent.goal_time = time.seconds_since_startup() as f32;
                             break;
@@ -1013,21 +1233,27 @@ pub fn agent_decisions(mut game: ResMut<Game>, time: Res<Time>) {
 // increase energy if the last agent hit isn't the same as the previous one
 pub fn update_agent_properties(
     mut game: ResMut<Game>,
-    time: Res<Time>,
+    time: Res<WorldTime>,
     // mut agents_transform_query: Query<&mut Transform, With<AgentId>>,
     // atoms_query: Query<&Children, With<AgentId>>,
     // mut atom_transform_query: Query<&mut Transform, (With<Atom>, Without<AgentId>)>,
     mut collision_event: EventReader<CollisionEvent>,
+    mut notifications: EventWriter<HudNotification>,
+    mut progress: ResMut<Progress>,
 ) {
     for collision_info in collision_event.iter() {
         // let other_agent = game.agents.get(&collision_info.other_agent_id).unwrap();
         // let is_guardian = other_agent.is_guardian;
-        let mut agent = game.agents.get_mut(&collision_info.agent_id).unwrap();
+        let agent = match game.agents.get_mut(&collision_info.agent_id) {
+            Some(agent) => agent,
+            None => continue,
+        };
         agent.just_collided = false;
         // unused
         if collision_info.other_is_guardian && agent.id == 1 {
             agent.energy *= 0.75;
-            println!("energy GUARDIAN SMASH: {}", agent.energy);
+            notifications.send(HudNotification("Guardian smash".to_string()));
+            progress.guardian_hits += 1;
         }
 
         if agent.last_agent_hit != collision_info.other_agent_id {
@@ -1036,8 +1262,8 @@ pub fn update_agent_properties(
             // }
             agent.energy *= 1.0 + ENERGY_INCREASE_RATE;
             agent.last_agent_hit = collision_info.other_agent_id;
-            if agent.id == 1 {
-                println!("energy increase {}", agent.energy);
+            if agent.id == 1 && progress.bumped.insert(collision_info.other_agent_id) {
+                notifications.send(HudNotification("New creature hit".to_string()));
             }
         } else {
             // if agent.id == 1 {
@@ -1045,17 +1271,13 @@ pub fn update_agent_properties(
             // }
             agent.energy *= 1.0 - ENERGY_INCREASE_RATE;
             agent.last_agent_hit = collision_info.other_agent_id;
-            if agent.id == 1 {
-                println!("same collision with {}", collision_info.other_agent_id);
-                println!("energy decrease {}", agent.energy);
-            }
         }
         agent.last_collision_time = time.seconds_since_startup() as f32;
     }
 }
 
-pub fn agent_action(mut game: ResMut<Game>) {
-    let mut rng = rand::thread_rng();
+pub fn agent_action(mut game: ResMut<Game>, mut world_rng: ResMut<WorldRng>) {
+    let rng = &mut world_rng.rng;
 
     let agent_positions = game
         .agents
@@ -1065,7 +1287,110 @@ pub fn agent_action(mut game: ResMut<Game>) {
 
     for (_id, agent) in game.agents.iter_mut() {
         // if rng.gen::<f32>() < 0.5 {
-        agent.act(&agent_positions);
+        agent.act(&agent_positions, rng);
         // }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn swept_circle_toi_hit() {
+        let toi = swept_circle_toi(Vec2::new(-10.0, 0.0), Vec2::new(10.0, 0.0), Vec2::ZERO, 2.0);
+        let toi = toi.expect("the sweep goes through the circle");
+        assert!((toi - 0.4).abs() < 1e-5);
+    }
+
+    #[test]
+    fn swept_circle_toi_miss() {
+        // passes above the circle
+        let toi = swept_circle_toi(Vec2::new(-10.0, 5.0), Vec2::new(10.0, 5.0), Vec2::ZERO, 2.0);
+        assert_eq!(toi, None);
+
+        // stops before reaching it
+        let toi = swept_circle_toi(Vec2::new(-10.0, 0.0), Vec2::new(-5.0, 0.0), Vec2::ZERO, 2.0);
+        assert_eq!(toi, None);
+
+        // moves away from it
+        let toi = swept_circle_toi(Vec2::new(5.0, 0.0), Vec2::new(10.0, 0.0), Vec2::ZERO, 2.0);
+        assert_eq!(toi, None);
+    }
+
+    #[test]
+    fn swept_circle_toi_starts_overlapping() {
+        let toi = swept_circle_toi(Vec2::new(1.0, 0.0), Vec2::new(-10.0, 0.0), Vec2::ZERO, 2.0);
+        assert_eq!(toi, None);
+    }
+
+    #[test]
+    fn swept_circle_toi_zero_length() {
+        let start = Vec2::new(-10.0, 0.0);
+        assert_eq!(swept_circle_toi(start, start, Vec2::ZERO, 2.0), None);
+    }
+
+    #[test]
+    fn sweep_level_bounds_inside() {
+        let pos = sweep_level_bounds(
+            Vec2::new(50.0, 50.0),
+            Vec2::new(60.0, 40.0),
+            5.0,
+            100.0,
+            1.0,
+            2.0,
+        );
+        assert_eq!(pos, Vec2::new(60.0, 40.0));
+    }
+
+    #[test]
+    fn sweep_level_bounds_stops_at_the_wall() {
+        // the wall is touched at 30% of the move, before the agent would have gone down
+        let pos = sweep_level_bounds(
+            Vec2::new(20.0, 50.0),
+            Vec2::new(-30.0, 30.0),
+            5.0,
+            100.0,
+            1.0,
+            2.0,
+        );
+        assert!((pos - Vec2::new(6.0, 44.0)).length() < 1e-4);
+
+        let pos = sweep_level_bounds(
+            Vec2::new(80.0, 50.0),
+            Vec2::new(130.0, 50.0),
+            5.0,
+            100.0,
+            1.0,
+            2.0,
+        );
+        assert!((pos - Vec2::new(94.0, 50.0)).length() < 1e-4);
+    }
+
+    #[test]
+    fn sweep_level_bounds_stops_at_the_floor() {
+        let pos = sweep_level_bounds(
+            Vec2::new(50.0, 20.0),
+            Vec2::new(50.0, -20.0),
+            5.0,
+            100.0,
+            1.0,
+            2.0,
+        );
+        assert!((pos - Vec2::new(50.0, 7.0)).length() < 1e-4);
+    }
+
+    #[test]
+    fn sweep_level_bounds_zero_length() {
+        let inside = Vec2::new(50.0, 50.0);
+        assert_eq!(
+            sweep_level_bounds(inside, inside, 5.0, 100.0, 1.0, 2.0),
+            inside
+        );
+
+        // an agent that is already in the wall is pushed out of it
+        let in_wall = Vec2::new(2.0, 50.0);
+        let pos = sweep_level_bounds(in_wall, in_wall, 5.0, 100.0, 1.0, 2.0);
+        assert_eq!(pos, Vec2::new(6.0, 50.0));
+    }
+}